@@ -0,0 +1,97 @@
+use crate::h264::{SliceHeader, SliceType};
+
+/// 1フレーム分（同じ RTP タイムスタンプ）の NAL ユニット
+#[derive(Debug)]
+pub struct AccessUnit {
+    /// RTP タイムスタンプ（90kHz基準）
+    pub ts: u32,
+    /// IDR スライスを含むか
+    pub is_key: bool,
+    /// スライスなどの VCL NAL ユニットを含むか（SEI / AUD だけの場合は false）
+    pub has_vcl: bool,
+    /// 受信順の NAL ユニット（スタートコードなし）
+    pub nals: Vec<Vec<u8>>,
+    /// スライスヘッダから求めたピクチャの種類（B スライスがあれば B、P があれば P、それ以外は I）
+    pub picture_type: Option<SliceType>,
+    /// スライスヘッダを解析できたスライスの数
+    pub slices: usize,
+    /// 最初のスライスのヘッダ（POC の計算に使う）
+    pub first_slice: Option<SliceHeader>,
+}
+
+/// NAL ユニットをアクセスユニット（MP4 の1サンプル）にまとめる。
+///
+/// RTP タイムスタンプが同じ NAL ユニットを、マーカービットが立つか
+/// タイムスタンプが変わるまで1つのアクセスユニットに集める。
+/// スライスヘッダなどから新しいピクチャの開始が分かる場合は start_picture() で区切る。
+pub struct AccessUnitAssembler {
+    current: Option<AccessUnit>,
+}
+
+impl AccessUnitAssembler {
+    pub fn new() -> Self {
+        Self { current: None }
+    }
+
+    /// NAL ユニットを追加する。
+    /// タイムスタンプが変わった場合は、それまでのアクセスユニットを返す。
+    pub fn push(&mut self, ts: u32, nal: &[u8], is_key: bool, is_vcl: bool) -> Option<AccessUnit> {
+        let finished = match self.current {
+            Some(ref au) if au.ts != ts => self.current.take(),
+            _ => None,
+        };
+
+        let au = self.current.get_or_insert_with(|| AccessUnit {
+            ts,
+            is_key: false,
+            has_vcl: false,
+            nals: Vec::new(),
+            picture_type: None,
+            slices: 0,
+            first_slice: None,
+        });
+        au.is_key |= is_key;
+        au.has_vcl |= is_vcl;
+        au.nals.push(nal.to_vec());
+
+        finished
+    }
+
+    /// 次の NAL ユニットが新しいアクセスユニットの先頭だと分かった（7.4.1.2.3）。
+    /// 組み立て中のアクセスユニットにスライスが含まれていれば、それを返す。
+    pub fn start_picture(&mut self) -> Option<AccessUnit> {
+        match self.current {
+            Some(ref au) if au.has_vcl => self.current.take(),
+            _ => None,
+        }
+    }
+
+    /// 直前に push() したスライスのヘッダを記録する。
+    pub fn note_slice(&mut self, header: &SliceHeader) {
+        if let Some(ref mut au) = self.current {
+            let slice_type = header.slice_type;
+            let rank = |t: SliceType| match t {
+                SliceType::B => 2,
+                SliceType::P | SliceType::SP => 1,
+                SliceType::I | SliceType::SI => 0,
+            };
+            if au.picture_type.is_none_or(|t| rank(slice_type) > rank(t)) {
+                au.picture_type = Some(slice_type);
+            }
+            au.slices += 1;
+            if au.first_slice.is_none() {
+                au.first_slice = Some(header.clone());
+            }
+        }
+    }
+
+    /// マーカービットを受け取った。組み立て中のアクセスユニットを返す。
+    pub fn end(&mut self) -> Option<AccessUnit> {
+        self.current.take()
+    }
+
+    /// 組み立て中のアクセスユニットを捨てる（パケット欠落時など）。
+    pub fn discard(&mut self) {
+        self.current = None;
+    }
+}
@@ -0,0 +1,327 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+/// Digest 認証のハッシュアルゴリズム
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DigestAlgorithm {
+    Md5,
+    Md5Sess,
+    Sha256,
+    Sha256Sess,
+}
+
+impl DigestAlgorithm {
+    fn parse(s: &str) -> Option<DigestAlgorithm> {
+        match s.to_ascii_uppercase().as_str() {
+            "MD5" => Some(DigestAlgorithm::Md5),
+            "MD5-SESS" => Some(DigestAlgorithm::Md5Sess),
+            "SHA-256" => Some(DigestAlgorithm::Sha256),
+            "SHA-256-SESS" => Some(DigestAlgorithm::Sha256Sess),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            DigestAlgorithm::Md5 => "MD5",
+            DigestAlgorithm::Md5Sess => "MD5-sess",
+            DigestAlgorithm::Sha256 => "SHA-256",
+            DigestAlgorithm::Sha256Sess => "SHA-256-sess",
+        }
+    }
+
+    fn is_sess(&self) -> bool {
+        matches!(self, DigestAlgorithm::Md5Sess | DigestAlgorithm::Sha256Sess)
+    }
+
+    /// SHA-256 系を MD5 系より優先する
+    fn strength(&self) -> u8 {
+        match self {
+            DigestAlgorithm::Md5 | DigestAlgorithm::Md5Sess => 0,
+            DigestAlgorithm::Sha256 | DigestAlgorithm::Sha256Sess => 1,
+        }
+    }
+
+    fn hash(&self, data: &str) -> String {
+        let digest: Vec<u8> = match self {
+            DigestAlgorithm::Md5 | DigestAlgorithm::Md5Sess => Md5::digest(data.as_bytes()).to_vec(),
+            DigestAlgorithm::Sha256 | DigestAlgorithm::Sha256Sess => Sha256::digest(data.as_bytes()).to_vec(),
+        };
+        digest.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+/// WWW-Authenticate: Digest ... のチャレンジ
+#[derive(Debug, Clone)]
+pub struct DigestChallenge {
+    pub realm: String,
+    pub nonce: String,
+    pub opaque: Option<String>,
+    pub algorithm: DigestAlgorithm,
+    /// qop=auth を使うかどうか（auth-int は非対応）
+    pub qop_auth: bool,
+    pub stale: bool,
+}
+
+impl DigestChallenge {
+    /// WWW-Authenticate ヘッダの値を解析する。Digest 以外のスキームや
+    /// 未対応のアルゴリズムなら None を返す。
+    pub fn parse(header: &str) -> Option<DigestChallenge> {
+        let header = header.trim();
+        let (scheme, params) = header.split_once(char::is_whitespace)?;
+        if !scheme.eq_ignore_ascii_case("Digest") {
+            return None;
+        }
+
+        let mut realm = None;
+        let mut nonce = None;
+        let mut opaque = None;
+        let mut algorithm = DigestAlgorithm::Md5;
+        let mut qop_auth = false;
+        let mut stale = false;
+        for (key, value) in parse_params(params) {
+            match key.to_ascii_lowercase().as_str() {
+                "realm" => realm = Some(value),
+                "nonce" => nonce = Some(value),
+                "opaque" => opaque = Some(value),
+                "algorithm" => algorithm = DigestAlgorithm::parse(&value)?,
+                "qop" => qop_auth = value.split(',').any(|q| q.trim().eq_ignore_ascii_case("auth")),
+                "stale" => stale = value.eq_ignore_ascii_case("true"),
+                _ => {}
+            }
+        }
+
+        Some(DigestChallenge {
+            realm: realm?,
+            nonce: nonce?,
+            opaque,
+            algorithm,
+            qop_auth,
+            stale,
+        })
+    }
+
+    /// 複数の WWW-Authenticate ヘッダから最も強いアルゴリズムのチャレンジを選ぶ。
+    pub fn select<'a, I: Iterator<Item = &'a str>>(headers: I) -> Option<DigestChallenge> {
+        headers
+            .filter_map(DigestChallenge::parse)
+            .max_by_key(|c| c.algorithm.strength())
+    }
+}
+
+/// key=value, key="quoted, value" 形式のパラメータ列を分解する
+fn parse_params(s: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut chars = s.chars().peekable();
+    loop {
+        // 区切りと空白を読み飛ばす
+        while matches!(chars.peek(), Some(c) if *c == ',' || c.is_whitespace()) {
+            chars.next();
+        }
+        let mut key = String::new();
+        while let Some(&c) = chars.peek() {
+            if c == '=' || c == ',' {
+                break;
+            }
+            key.push(c);
+            chars.next();
+        }
+        if key.is_empty() {
+            break;
+        }
+
+        let mut value = String::new();
+        if chars.peek() == Some(&'=') {
+            chars.next();
+            if chars.peek() == Some(&'"') {
+                chars.next();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => {
+                            if let Some(escaped) = chars.next() {
+                                value.push(escaped);
+                            }
+                        }
+                        '"' => break,
+                        _ => value.push(c),
+                    }
+                }
+            } else {
+                while let Some(&c) = chars.peek() {
+                    if c == ',' {
+                        break;
+                    }
+                    value.push(c);
+                    chars.next();
+                }
+            }
+        }
+        params.push((key.trim().to_string(), value.trim().to_string()));
+    }
+    params
+}
+
+/// チャレンジに対するレスポンスを生成する。nonce-count は同じ nonce を
+/// 使う限りリクエストごとに増加する。
+pub struct DigestAuth {
+    challenge: DigestChallenge,
+    nonce_count: u32,
+    cnonce: String,
+}
+
+impl DigestAuth {
+    pub fn new(challenge: DigestChallenge) -> Self {
+        Self {
+            challenge,
+            nonce_count: 0,
+            cnonce: generate_cnonce(),
+        }
+    }
+
+    pub fn nonce(&self) -> &str {
+        &self.challenge.nonce
+    }
+
+    /// Authorization ヘッダの値を生成する。
+    pub fn authorization(&mut self, username: &str, password: &str, method: &str, uri: &str) -> String {
+        let c = &self.challenge;
+        let alg = c.algorithm;
+
+        self.nonce_count += 1;
+        let nc = format!("{:08x}", self.nonce_count);
+
+        let mut ha1 = alg.hash(&format!("{}:{}:{}", username, c.realm, password));
+        if alg.is_sess() {
+            ha1 = alg.hash(&format!("{}:{}:{}", ha1, c.nonce, self.cnonce));
+        }
+        let ha2 = alg.hash(&format!("{}:{}", method, uri));
+        let response = if c.qop_auth {
+            alg.hash(&format!("{}:{}:{}:{}:auth:{}", ha1, c.nonce, nc, self.cnonce, ha2))
+        } else {
+            alg.hash(&format!("{}:{}:{}", ha1, c.nonce, ha2))
+        };
+
+        let mut header = format!(
+            "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", response=\"{}\", algorithm={}",
+            username, c.realm, c.nonce, uri, response, alg.name()
+        );
+        if c.qop_auth {
+            header += &format!(", qop=auth, nc={}, cnonce=\"{}\"", nc, self.cnonce);
+        }
+        if let Some(ref opaque) = c.opaque {
+            header += &format!(", opaque=\"{}\"", opaque);
+        }
+        header
+    }
+}
+
+fn generate_cnonce() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    let seed = format!("{}:{}", nanos, std::process::id());
+    DigestAlgorithm::Md5.hash(&seed)[..16].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Authorization ヘッダの値から1つのパラメータを取り出す
+    fn param(header: &str, key: &str) -> Option<String> {
+        let params = header.strip_prefix("Digest ")?;
+        parse_params(params).into_iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    #[test]
+    fn rfc2617_md5_example() {
+        // RFC 2617 3.5
+        let challenge = DigestChallenge::parse(
+            "Digest realm=\"testrealm@host.com\", qop=\"auth,auth-int\", \
+             nonce=\"dcd98b7102dd2f0e8b11d0f600bfb0c093\", opaque=\"5ccc069c403ebaf9f0171e9517f40e41\"",
+        ).unwrap();
+        assert_eq!(challenge.algorithm, DigestAlgorithm::Md5);
+        assert!(challenge.qop_auth);
+
+        let mut auth = DigestAuth::new(challenge);
+        auth.cnonce = "0a4f113b".to_string();
+        let header = auth.authorization("Mufasa", "Circle Of Life", "GET", "/dir/index.html");
+        assert_eq!(param(&header, "response").as_deref(), Some("6629fae49393a05397450978507c4ef1"));
+        assert_eq!(param(&header, "nc").as_deref(), Some("00000001"));
+        assert_eq!(param(&header, "qop").as_deref(), Some("auth"));
+        assert_eq!(param(&header, "cnonce").as_deref(), Some("0a4f113b"));
+        assert_eq!(param(&header, "opaque").as_deref(), Some("5ccc069c403ebaf9f0171e9517f40e41"));
+        assert_eq!(param(&header, "algorithm").as_deref(), Some("MD5"));
+
+        // 同じ nonce を使い続けると nonce-count が増え、response も変わる
+        let header = auth.authorization("Mufasa", "Circle Of Life", "GET", "/dir/index.html");
+        assert_eq!(param(&header, "nc").as_deref(), Some("00000002"));
+        assert_ne!(param(&header, "response").as_deref(), Some("6629fae49393a05397450978507c4ef1"));
+    }
+
+    #[test]
+    fn rfc7616_sha256_example() {
+        // RFC 7616 3.9.1
+        let challenge = DigestChallenge::parse(
+            "Digest realm=\"http-auth@example.org\", qop=\"auth, auth-int\", algorithm=SHA-256, \
+             nonce=\"7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v\", \
+             opaque=\"FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS\"",
+        ).unwrap();
+        assert_eq!(challenge.algorithm, DigestAlgorithm::Sha256);
+
+        let mut auth = DigestAuth::new(challenge);
+        auth.cnonce = "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ".to_string();
+        let header = auth.authorization("Mufasa", "Circle of Life", "GET", "/dir/index.html");
+        assert_eq!(
+            param(&header, "response").as_deref(),
+            Some("753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1")
+        );
+        assert_eq!(param(&header, "nc").as_deref(), Some("00000001"));
+        assert_eq!(param(&header, "algorithm").as_deref(), Some("SHA-256"));
+    }
+
+    #[test]
+    fn parse_challenge_params() {
+        // 引用符の中のカンマとエスケープ、スキーム名の大文字小文字
+        let challenge = DigestChallenge::parse(
+            "dIgEsT realm=\"Lobby, \\\"East\\\" gate\",nonce=\"a,b\" , qop=auth, stale=TRUE",
+        ).unwrap();
+        assert_eq!(challenge.realm, "Lobby, \"East\" gate");
+        assert_eq!(challenge.nonce, "a,b");
+        assert!(challenge.qop_auth);
+        assert!(challenge.stale);
+        assert_eq!(challenge.opaque, None);
+
+        // qop がなければ RFC 2069 形式で、nc / cnonce を送らない
+        let challenge = DigestChallenge::parse("Digest realm=\"IP Camera\", nonce=\"0123\", algorithm=md5").unwrap();
+        assert!(!challenge.qop_auth);
+        let header = DigestAuth::new(challenge).authorization("admin", "12345", "DESCRIBE", "rtsp://cam/stream");
+        assert_eq!(param(&header, "nc"), None);
+        assert_eq!(param(&header, "cnonce"), None);
+        let ha1 = DigestAlgorithm::Md5.hash("admin:IP Camera:12345");
+        let ha2 = DigestAlgorithm::Md5.hash("DESCRIBE:rtsp://cam/stream");
+        assert_eq!(param(&header, "response"), Some(DigestAlgorithm::Md5.hash(&format!("{}:0123:{}", ha1, ha2))));
+
+        // Digest 以外、nonce がない、未対応のアルゴリズム、qop=auth-int のみ
+        assert!(DigestChallenge::parse("Basic realm=\"IP Camera\"").is_none());
+        assert!(DigestChallenge::parse("Digest realm=\"IP Camera\"").is_none());
+        assert!(DigestChallenge::parse("Digest realm=\"x\", nonce=\"y\", algorithm=SHA-512-256").is_none());
+        assert!(!DigestChallenge::parse("Digest realm=\"x\", nonce=\"y\", qop=\"auth-int\"").unwrap().qop_auth);
+    }
+
+    #[test]
+    fn select_prefers_sha256() {
+        let headers = [
+            "Basic realm=\"IP Camera\"",
+            "Digest realm=\"IP Camera\", nonce=\"md5\", algorithm=MD5, qop=\"auth\"",
+            "Digest realm=\"IP Camera\", nonce=\"sha\", algorithm=SHA-256, qop=\"auth\"",
+        ];
+        assert_eq!(DigestChallenge::select(headers.iter().copied()).unwrap().nonce, "sha");
+        assert_eq!(DigestChallenge::select(headers.iter().rev().copied()).unwrap().nonce, "sha");
+        // 解析できるのが MD5 だけならそれを使う
+        assert_eq!(DigestChallenge::select(headers[..2].iter().copied()).unwrap().algorithm, DigestAlgorithm::Md5);
+        assert!(DigestChallenge::select(headers[..1].iter().copied()).is_none());
+    }
+}
@@ -1,316 +1,1845 @@
-use colored::*;
+use std::fmt;
 
+/// NAL ユニットからエミュレーション防止バイトを取り除き、RBSP を返す。
+///
+/// NAL の中では 00 00 00〜03 が現れないように 00 00 03 xx と 03 が挿入されているので、
+/// 00 00 に続く 03 を捨てる（NAL ヘッダもそのまま含めて返す）。
+pub fn nal_to_rbsp(nal: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(nal.len());
+    let mut zeros = 0;
+    for &b in nal {
+        if zeros >= 2 && b == 0x03 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if b == 0 { zeros + 1 } else { 0 };
+        rbsp.push(b);
+    }
+    rbsp
+}
+
+/// RBSP をビット単位で読む。データの終わりを超える読み込みは None を返す。
 pub struct BitReader<'a> {
     data: &'a [u8],
     bit_pos: usize, // 読み込んだビット位置
 }
 
 impl<'a> BitReader<'a> {
+    /// エミュレーション防止バイトを取り除いた RBSP を渡すこと（nal_to_rbsp）。
     pub fn new(data: &'a [u8]) -> Self {
         Self { data, bit_pos: 0 }
     }
 
-    // n ビット読む
+    /// 残りのビット数
+    pub fn bits_left(&self) -> usize {
+        (self.data.len() * 8).saturating_sub(self.bit_pos)
+    }
+
+    // n ビット読む（n <= 32）
     pub fn read_bits(&mut self, n: usize) -> Option<u32> {
-        let mut val = 0u32;
+        if n > 32 {
+            return None;
+        }
+        self.read_bits64(n).map(|v| v as u32)
+    }
+
+    // n ビット読む（n <= 64）
+    pub fn read_bits64(&mut self, n: usize) -> Option<u64> {
+        if n > 64 || n > self.bits_left() {
+            return None;
+        }
+        let mut val = 0u64;
         for _ in 0..n {
             let byte_idx = self.bit_pos / 8;
-            if byte_idx >= self.data.len() { return None; }
             let bit_offset = 7 - (self.bit_pos % 8);
             let bit = (self.data[byte_idx] >> bit_offset) & 1;
-            val = (val << 1) | bit as u32;
+            val = (val << 1) | bit as u64;
             self.bit_pos += 1;
         }
         Some(val)
     }
 
+    // 1ビットのフラグを読む
+    pub fn read_flag(&mut self) -> Option<bool> {
+        self.read_bits(1).map(|b| b == 1)
+    }
+
+    // n ビット読み飛ばす
+    pub fn skip_bits(&mut self, n: usize) -> Option<()> {
+        if n > self.bits_left() {
+            return None;
+        }
+        self.bit_pos += n;
+        Some(())
+    }
+
     // ue(v) 無符号 Exp-Golomb
     pub fn read_ue(&mut self) -> Option<u32> {
         let mut zeros = 0;
-        while let Some(bit) = self.read_bits(1) {
-            if bit == 0 { zeros += 1; } else { break; }
+        while self.read_bits(1)? == 0 {
+            zeros += 1;
+            // u32 に収まらない値（壊れたデータ）
+            if zeros > 31 {
+                return None;
+            }
         }
-        let suffix = if zeros > 0 { self.read_bits(zeros)? } else { 0 };
-        Some((1 << zeros) - 1 + suffix)
+        let suffix = self.read_bits(zeros)?;
+        Some((1u32 << zeros) - 1 + suffix)
     }
 
     // se(v) 符号付き Exp-Golomb
     pub fn read_se(&mut self) -> Option<i32> {
-        let code_num = self.read_ue()? as i32;
-        Some(if code_num % 2 == 0 { -(code_num / 2) } else { (code_num + 1)/2 })
-    }
-}
-
-fn scaling_list(br: &mut BitReader, list: &mut [u8], size: usize, use_default_flag: &mut bool) {
-    // size 個分の値を読み込む処理
-    for j in 0..size {
-        // ここで各係数を読み込む
-        // list[j] = br.read_ue().unwrap() as u8; など
-    }
-    // use_default_flag を必要に応じて更新
-}
-
-/// SPS NAL ユニット（NALヘッダバイト込み）から映像解像度を解析して返す。
-/// スタートコードは含まない生 NAL データを渡すこと。
-pub fn parse_sps_resolution(sps: &[u8]) -> Option<(u16, u16)> {
-    if sps.len() < 4 {
-        return None;
-    }
-    let mut br = BitReader::new(&sps[1..]); // sps[0] は NAL ヘッダ
-
-    let profile_idc = br.read_bits(8)?;
-    br.read_bits(8)?; // constraint flags + reserved
-    br.read_bits(8)?; // level_idc
-    br.read_ue()?;    // seq_parameter_set_id
-
-    let high_profiles: [u32; 13] = [100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134, 135];
-    let mut chroma_format_idc = 1u32;
-
-    if high_profiles.contains(&profile_idc) {
-        chroma_format_idc = br.read_ue()?;
-        if chroma_format_idc == 3 {
-            br.read_bits(1)?; // separate_colour_plane_flag
-        }
-        br.read_ue()?; // bit_depth_luma_minus8
-        br.read_ue()?; // bit_depth_chroma_minus8
-        br.read_bits(1)?; // qpprime_y_zero_transform_bypass_flag
-        let scaling_present = br.read_bits(1)?;
-        if scaling_present == 1 {
-            let count = if chroma_format_idc != 3 { 8usize } else { 12 };
-            for i in 0..count {
-                let list_present = br.read_bits(1)?;
-                if list_present == 1 {
-                    let size = if i < 6 { 16usize } else { 64 };
-                    let mut last_scale = 8u32;
-                    let mut next_scale = 8u32;
-                    for _ in 0..size {
-                        if next_scale != 0 {
-                            let delta = br.read_se()?;
-                            next_scale = ((last_scale as i32 + delta + 256) % 256) as u32;
-                        }
-                        last_scale = if next_scale == 0 { last_scale } else { next_scale };
+        let code_num = self.read_ue()? as i64;
+        Some(if code_num % 2 == 0 { -(code_num / 2) } else { (code_num + 1) / 2 } as i32)
+    }
+
+    // バイト境界にいるか
+    pub fn byte_aligned(&self) -> bool {
+        self.bit_pos.is_multiple_of(8)
+    }
+
+    /// rbsp_trailing_bits（最後の 1 とそれに続く 0）より前にまだデータがあるか。
+    pub fn more_rbsp_data(&self) -> bool {
+        let Some(last) = self.data.iter().rposition(|&b| b != 0) else {
+            return false;
+        };
+        let stop_bit = last * 8 + 7 - self.data[last].trailing_zeros() as usize;
+        self.bit_pos < stop_bit
+    }
+}
+
+/// SDP の sprop-parameter-sets（RFC 6184）を NAL ユニットの列に変換する。
+/// カンマ区切りの各要素は base64 でエンコードされた SPS / PPS。
+pub fn parse_sprop_parameter_sets(value: &str) -> Vec<Vec<u8>> {
+    value.split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .filter_map(|s| match base64::decode(s) {
+            Ok(nal) if !nal.is_empty() => Some(nal),
+            _ => {
+                eprintln!("invalid sprop-parameter-sets entry: {}", s);
+                None
+            }
+        })
+        .collect()
+}
+
+/// SPS / PPS / スライスヘッダの解析エラー
+#[derive(Debug, Clone, PartialEq)]
+pub enum H264Error {
+    /// 必要なビット数が残っていない
+    Truncated,
+    /// 期待した種類の NAL ユニットではない
+    UnexpectedNalType(u8),
+    /// 規格の範囲外の値（要素名, 値）
+    InvalidValue(&'static str, i64),
+    /// 参照先のパラメータセットをまだ受け取っていない（"SPS" / "PPS", id）
+    MissingParameterSet(&'static str, u32),
+}
+
+impl fmt::Display for H264Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            H264Error::Truncated => write!(f, "truncated H.264 syntax"),
+            H264Error::UnexpectedNalType(t) => write!(f, "unexpected NAL unit type {}", t),
+            H264Error::InvalidValue(name, v) => write!(f, "invalid {} = {}", name, v),
+            H264Error::MissingParameterSet(kind, id) => write!(f, "{} id {} not received", kind, id),
+        }
+    }
+}
+
+impl std::error::Error for H264Error {}
+
+// 規格の記述子 u(n) / ue(v) / se(v) に対応する読み込み
+fn u(br: &mut BitReader, n: usize) -> Result<u32, H264Error> {
+    br.read_bits(n).ok_or(H264Error::Truncated)
+}
+
+fn ue(br: &mut BitReader) -> Result<u32, H264Error> {
+    br.read_ue().ok_or(H264Error::Truncated)
+}
+
+fn se(br: &mut BitReader) -> Result<i32, H264Error> {
+    br.read_se().ok_or(H264Error::Truncated)
+}
+
+fn flag(br: &mut BitReader) -> Result<bool, H264Error> {
+    br.read_flag().ok_or(H264Error::Truncated)
+}
+
+/// 値が範囲内か確認する
+fn check(name: &'static str, value: u32, max: u32) -> Result<u32, H264Error> {
+    if value > max {
+        return Err(H264Error::InvalidValue(name, value as i64));
+    }
+    Ok(value)
+}
+
+/// スケーリングリスト（7.3.2.1.1.1）
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScalingList {
+    /// リストが送られていない（フォールバック規則で前のリストか既定値を使う）
+    NotPresent,
+    /// UseDefaultScalingMatrixFlag（既定のリストを使う）
+    Default,
+    /// 送られた係数（ジグザグスキャン順）
+    Explicit(Vec<u8>),
+}
+
+impl ScalingList {
+    fn parse(br: &mut BitReader, size: usize) -> Result<ScalingList, H264Error> {
+        let mut list = Vec::with_capacity(size);
+        let mut last_scale = 8i32;
+        let mut next_scale = 8i32;
+        for j in 0..size {
+            if next_scale != 0 {
+                let delta_scale = se(br)?;
+                if !(-128..=127).contains(&delta_scale) {
+                    return Err(H264Error::InvalidValue("delta_scale", delta_scale as i64));
+                }
+                next_scale = (last_scale + delta_scale + 256) % 256;
+                if j == 0 && next_scale == 0 {
+                    return Ok(ScalingList::Default);
+                }
+            }
+            let scale = if next_scale == 0 { last_scale } else { next_scale };
+            list.push(scale as u8);
+            last_scale = scale;
+        }
+        Ok(ScalingList::Explicit(list))
+    }
+}
+
+/// スケーリング行列（4x4 が6個、8x8 が2個または6個）
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScalingMatrix {
+    pub lists_4x4: Vec<ScalingList>,
+    pub lists_8x8: Vec<ScalingList>,
+}
+
+impl ScalingMatrix {
+    fn parse(br: &mut BitReader, count: usize) -> Result<ScalingMatrix, H264Error> {
+        let mut matrix = ScalingMatrix { lists_4x4: Vec::new(), lists_8x8: Vec::new() };
+        for i in 0..count {
+            let present = flag(br)?;
+            let size = if i < 6 { 16 } else { 64 };
+            let list = if present { ScalingList::parse(br, size)? } else { ScalingList::NotPresent };
+            if i < 6 {
+                matrix.lists_4x4.push(list);
+            } else {
+                matrix.lists_8x8.push(list);
+            }
+        }
+        Ok(matrix)
+    }
+}
+
+/// pic_order_cnt_type ごとのパラメータ
+#[derive(Debug, Clone, PartialEq)]
+pub enum PicOrderCnt {
+    Type0 {
+        log2_max_pic_order_cnt_lsb_minus4: u32,
+    },
+    Type1 {
+        delta_pic_order_always_zero_flag: bool,
+        offset_for_non_ref_pic: i32,
+        offset_for_top_to_bottom_field: i32,
+        offset_for_ref_frame: Vec<i32>,
+    },
+    Type2,
+}
+
+/// frame_cropping の各オフセット（クロップ単位）
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameCropping {
+    pub left: u32,
+    pub right: u32,
+    pub top: u32,
+    pub bottom: u32,
+}
+
+/// aspect_ratio_info
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AspectRatioInfo {
+    pub aspect_ratio_idc: u8,
+    /// aspect_ratio_idc が 255 (Extended_SAR) のときだけ送られる
+    pub sar_width: u16,
+    pub sar_height: u16,
+}
+
+/// colour_description
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ColourDescription {
+    pub colour_primaries: u8,
+    pub transfer_characteristics: u8,
+    pub matrix_coefficients: u8,
+}
+
+/// video_signal_type
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VideoSignalType {
+    pub video_format: u8,
+    pub video_full_range_flag: bool,
+    pub colour_description: Option<ColourDescription>,
+}
+
+/// timing_info
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimingInfo {
+    pub num_units_in_tick: u32,
+    pub time_scale: u32,
+    pub fixed_frame_rate_flag: bool,
+}
+
+/// hrd_parameters の CPB ごとの値
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CpbSpec {
+    pub bit_rate_value_minus1: u32,
+    pub cpb_size_value_minus1: u32,
+    pub cbr_flag: bool,
+}
+
+/// hrd_parameters（E.1.2）
+#[derive(Debug, Clone, PartialEq)]
+pub struct HrdParameters {
+    pub bit_rate_scale: u8,
+    pub cpb_size_scale: u8,
+    pub cpb_specs: Vec<CpbSpec>,
+    pub initial_cpb_removal_delay_length_minus1: u8,
+    pub cpb_removal_delay_length_minus1: u8,
+    pub dpb_output_delay_length_minus1: u8,
+    pub time_offset_length: u8,
+}
+
+impl HrdParameters {
+    fn parse(br: &mut BitReader) -> Result<HrdParameters, H264Error> {
+        let cpb_cnt_minus1 = check("cpb_cnt_minus1", ue(br)?, 31)?;
+        let bit_rate_scale = u(br, 4)? as u8;
+        let cpb_size_scale = u(br, 4)? as u8;
+        let mut cpb_specs = Vec::new();
+        for _ in 0..=cpb_cnt_minus1 {
+            cpb_specs.push(CpbSpec {
+                bit_rate_value_minus1: ue(br)?,
+                cpb_size_value_minus1: ue(br)?,
+                cbr_flag: flag(br)?,
+            });
+        }
+        Ok(HrdParameters {
+            bit_rate_scale,
+            cpb_size_scale,
+            cpb_specs,
+            initial_cpb_removal_delay_length_minus1: u(br, 5)? as u8,
+            cpb_removal_delay_length_minus1: u(br, 5)? as u8,
+            dpb_output_delay_length_minus1: u(br, 5)? as u8,
+            time_offset_length: u(br, 5)? as u8,
+        })
+    }
+}
+
+/// bitstream_restriction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BitstreamRestriction {
+    pub motion_vectors_over_pic_boundaries_flag: bool,
+    pub max_bytes_per_pic_denom: u32,
+    pub max_bits_per_mb_denom: u32,
+    pub log2_max_mv_length_horizontal: u32,
+    pub log2_max_mv_length_vertical: u32,
+    pub max_num_reorder_frames: u32,
+    pub max_dec_frame_buffering: u32,
+}
+
+/// vui_parameters（E.1.1）
+#[derive(Debug, Clone, PartialEq)]
+pub struct VuiParameters {
+    pub aspect_ratio_info: Option<AspectRatioInfo>,
+    /// overscan_info_present_flag が立っている場合の overscan_appropriate_flag
+    pub overscan_appropriate: Option<bool>,
+    pub video_signal_type: Option<VideoSignalType>,
+    /// (chroma_sample_loc_type_top_field, chroma_sample_loc_type_bottom_field)
+    pub chroma_sample_loc_type: Option<(u32, u32)>,
+    pub timing_info: Option<TimingInfo>,
+    pub nal_hrd_parameters: Option<HrdParameters>,
+    pub vcl_hrd_parameters: Option<HrdParameters>,
+    /// HRD パラメータがある場合だけ送られる
+    pub low_delay_hrd_flag: bool,
+    pub pic_struct_present_flag: bool,
+    pub bitstream_restriction: Option<BitstreamRestriction>,
+}
+
+impl VuiParameters {
+    fn parse(br: &mut BitReader) -> Result<VuiParameters, H264Error> {
+        let aspect_ratio_info = if flag(br)? {
+            let aspect_ratio_idc = u(br, 8)? as u8;
+            let (sar_width, sar_height) = if aspect_ratio_idc == 255 {
+                (u(br, 16)? as u16, u(br, 16)? as u16)
+            } else {
+                (0, 0)
+            };
+            Some(AspectRatioInfo { aspect_ratio_idc, sar_width, sar_height })
+        } else {
+            None
+        };
+        let overscan_appropriate = if flag(br)? { Some(flag(br)?) } else { None };
+        let video_signal_type = if flag(br)? {
+            let video_format = u(br, 3)? as u8;
+            let video_full_range_flag = flag(br)?;
+            let colour_description = if flag(br)? {
+                Some(ColourDescription {
+                    colour_primaries: u(br, 8)? as u8,
+                    transfer_characteristics: u(br, 8)? as u8,
+                    matrix_coefficients: u(br, 8)? as u8,
+                })
+            } else {
+                None
+            };
+            Some(VideoSignalType { video_format, video_full_range_flag, colour_description })
+        } else {
+            None
+        };
+        let chroma_sample_loc_type = if flag(br)? {
+            Some((check("chroma_sample_loc_type_top_field", ue(br)?, 5)?,
+                  check("chroma_sample_loc_type_bottom_field", ue(br)?, 5)?))
+        } else {
+            None
+        };
+        let timing_info = if flag(br)? {
+            Some(TimingInfo {
+                num_units_in_tick: u(br, 32)?,
+                time_scale: u(br, 32)?,
+                fixed_frame_rate_flag: flag(br)?,
+            })
+        } else {
+            None
+        };
+        let nal_hrd_parameters = if flag(br)? { Some(HrdParameters::parse(br)?) } else { None };
+        let vcl_hrd_parameters = if flag(br)? { Some(HrdParameters::parse(br)?) } else { None };
+        let low_delay_hrd_flag = if nal_hrd_parameters.is_some() || vcl_hrd_parameters.is_some() {
+            flag(br)?
+        } else {
+            false
+        };
+        let pic_struct_present_flag = flag(br)?;
+        let bitstream_restriction = if flag(br)? {
+            Some(BitstreamRestriction {
+                motion_vectors_over_pic_boundaries_flag: flag(br)?,
+                max_bytes_per_pic_denom: check("max_bytes_per_pic_denom", ue(br)?, 16)?,
+                max_bits_per_mb_denom: check("max_bits_per_mb_denom", ue(br)?, 16)?,
+                log2_max_mv_length_horizontal: check("log2_max_mv_length_horizontal", ue(br)?, 16)?,
+                log2_max_mv_length_vertical: check("log2_max_mv_length_vertical", ue(br)?, 16)?,
+                max_num_reorder_frames: ue(br)?,
+                max_dec_frame_buffering: ue(br)?,
+            })
+        } else {
+            None
+        };
+        Ok(VuiParameters {
+            aspect_ratio_info,
+            overscan_appropriate,
+            video_signal_type,
+            chroma_sample_loc_type,
+            timing_info,
+            nal_hrd_parameters,
+            vcl_hrd_parameters,
+            low_delay_hrd_flag,
+            pic_struct_present_flag,
+            bitstream_restriction,
+        })
+    }
+}
+
+/// aspect_ratio_idc 1〜16 のサンプルアスペクト比（表 E-1）
+const SAMPLE_ASPECT_RATIOS: [(u16, u16); 16] = [
+    (1, 1), (12, 11), (10, 11), (16, 11), (40, 33), (24, 11), (20, 11), (32, 11),
+    (80, 33), (18, 11), (15, 11), (64, 33), (160, 99), (4, 3), (3, 2), (2, 1),
+];
+
+/// aspect_ratio_idc が Extended_SAR（sar_width / sar_height を直接送る）
+const EXTENDED_SAR: u8 = 255;
+
+impl VuiParameters {
+    /// サンプル（画素）のアスペクト比 (横, 縦)。未指定や不正な値なら None。
+    pub fn sample_aspect_ratio(&self) -> Option<(u16, u16)> {
+        let info = self.aspect_ratio_info?;
+        let sar = match info.aspect_ratio_idc {
+            EXTENDED_SAR => (info.sar_width, info.sar_height),
+            idc @ 1..=16 => SAMPLE_ASPECT_RATIOS[idc as usize - 1],
+            _ => return None,
+        };
+        if sar.0 == 0 || sar.1 == 0 {
+            return None;
+        }
+        Some(sar)
+    }
+
+    /// フレームレート。1フレームは2クロックティック（E.2.1 の time_scale / (2 * num_units_in_tick)）。
+    pub fn frame_rate(&self) -> Option<f64> {
+        let timing = self.timing_info?;
+        if timing.num_units_in_tick == 0 || timing.time_scale == 0 {
+            return None;
+        }
+        Some(timing.time_scale as f64 / (2.0 * timing.num_units_in_tick as f64))
+    }
+
+    /// `timescale` 単位の1フレームの長さ。
+    pub fn frame_duration(&self, timescale: u32) -> Option<u32> {
+        let timing = self.timing_info?;
+        if timing.time_scale == 0 {
+            return None;
+        }
+        let ticks = timescale as u64 * 2 * timing.num_units_in_tick as u64 / timing.time_scale as u64;
+        u32::try_from(ticks).ok().filter(|&t| t > 0)
+    }
+}
+
+/// chroma_format_idc などが送られるプロファイル（7.3.2.1.1）
+const HIGH_PROFILES: [u8; 13] = [100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134, 135];
+
+/// シーケンスパラメータセット（7.3.2.1.1）
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sps {
+    pub profile_idc: u8,
+    /// constraint_set0_flag〜constraint_set5_flag と reserved_zero_2bits（avcC の profile_compatibility）
+    pub constraint_flags: u8,
+    pub level_idc: u8,
+    pub seq_parameter_set_id: u32,
+    /// High 系以外のプロファイルでは 1 (4:2:0)
+    pub chroma_format_idc: u32,
+    pub separate_colour_plane_flag: bool,
+    pub bit_depth_luma_minus8: u32,
+    pub bit_depth_chroma_minus8: u32,
+    pub qpprime_y_zero_transform_bypass_flag: bool,
+    /// seq_scaling_matrix_present_flag が立っている場合のスケーリング行列
+    pub scaling_matrix: Option<ScalingMatrix>,
+    pub log2_max_frame_num_minus4: u32,
+    pub pic_order_cnt: PicOrderCnt,
+    pub max_num_ref_frames: u32,
+    pub gaps_in_frame_num_value_allowed_flag: bool,
+    pub pic_width_in_mbs_minus1: u32,
+    pub pic_height_in_map_units_minus1: u32,
+    pub frame_mbs_only_flag: bool,
+    pub mb_adaptive_frame_field_flag: bool,
+    pub direct_8x8_inference_flag: bool,
+    pub frame_cropping: Option<FrameCropping>,
+    pub vui: Option<VuiParameters>,
+}
+
+impl Sps {
+    /// SPS NAL ユニット（NAL ヘッダバイト込み、スタートコードなし）を解析する。
+    pub fn parse(nal: &[u8]) -> Result<Sps, H264Error> {
+        let rbsp = nal_to_rbsp(nal);
+        let mut br = BitReader::new(&rbsp);
+        br.skip_bits(3).ok_or(H264Error::Truncated)?; // forbidden_zero_bit, nal_ref_idc
+        let nal_unit_type = u(&mut br, 5)? as u8;
+        if nal_unit_type != 7 {
+            return Err(H264Error::UnexpectedNalType(nal_unit_type));
+        }
+
+        let profile_idc = u(&mut br, 8)? as u8;
+        let constraint_flags = u(&mut br, 8)? as u8;
+        let level_idc = u(&mut br, 8)? as u8;
+        let seq_parameter_set_id = check("seq_parameter_set_id", ue(&mut br)?, 31)?;
+
+        let mut chroma_format_idc = 1;
+        let mut separate_colour_plane_flag = false;
+        let mut bit_depth_luma_minus8 = 0;
+        let mut bit_depth_chroma_minus8 = 0;
+        let mut qpprime_y_zero_transform_bypass_flag = false;
+        let mut scaling_matrix = None;
+        if HIGH_PROFILES.contains(&profile_idc) {
+            chroma_format_idc = check("chroma_format_idc", ue(&mut br)?, 3)?;
+            if chroma_format_idc == 3 {
+                separate_colour_plane_flag = flag(&mut br)?;
+            }
+            bit_depth_luma_minus8 = check("bit_depth_luma_minus8", ue(&mut br)?, 6)?;
+            bit_depth_chroma_minus8 = check("bit_depth_chroma_minus8", ue(&mut br)?, 6)?;
+            qpprime_y_zero_transform_bypass_flag = flag(&mut br)?;
+            if flag(&mut br)? {
+                let count = if chroma_format_idc != 3 { 8 } else { 12 };
+                scaling_matrix = Some(ScalingMatrix::parse(&mut br, count)?);
+            }
+        }
+
+        let log2_max_frame_num_minus4 = check("log2_max_frame_num_minus4", ue(&mut br)?, 12)?;
+        let pic_order_cnt = match ue(&mut br)? {
+            0 => PicOrderCnt::Type0 {
+                log2_max_pic_order_cnt_lsb_minus4: check("log2_max_pic_order_cnt_lsb_minus4", ue(&mut br)?, 12)?,
+            },
+            1 => {
+                let delta_pic_order_always_zero_flag = flag(&mut br)?;
+                let offset_for_non_ref_pic = se(&mut br)?;
+                let offset_for_top_to_bottom_field = se(&mut br)?;
+                let num = check("num_ref_frames_in_pic_order_cnt_cycle", ue(&mut br)?, 255)?;
+                let offset_for_ref_frame = (0..num).map(|_| se(&mut br)).collect::<Result<_, _>>()?;
+                PicOrderCnt::Type1 {
+                    delta_pic_order_always_zero_flag,
+                    offset_for_non_ref_pic,
+                    offset_for_top_to_bottom_field,
+                    offset_for_ref_frame,
+                }
+            }
+            2 => PicOrderCnt::Type2,
+            t => return Err(H264Error::InvalidValue("pic_order_cnt_type", t as i64)),
+        };
+
+        let max_num_ref_frames = ue(&mut br)?;
+        let gaps_in_frame_num_value_allowed_flag = flag(&mut br)?;
+        let pic_width_in_mbs_minus1 = ue(&mut br)?;
+        let pic_height_in_map_units_minus1 = ue(&mut br)?;
+        let frame_mbs_only_flag = flag(&mut br)?;
+        let mb_adaptive_frame_field_flag = if !frame_mbs_only_flag { flag(&mut br)? } else { false };
+        let direct_8x8_inference_flag = flag(&mut br)?;
+        let frame_cropping = if flag(&mut br)? {
+            Some(FrameCropping {
+                left: ue(&mut br)?,
+                right: ue(&mut br)?,
+                top: ue(&mut br)?,
+                bottom: ue(&mut br)?,
+            })
+        } else {
+            None
+        };
+        let vui = if flag(&mut br)? { Some(VuiParameters::parse(&mut br)?) } else { None };
+
+        let sps = Sps {
+            profile_idc,
+            constraint_flags,
+            level_idc,
+            seq_parameter_set_id,
+            chroma_format_idc,
+            separate_colour_plane_flag,
+            bit_depth_luma_minus8,
+            bit_depth_chroma_minus8,
+            qpprime_y_zero_transform_bypass_flag,
+            scaling_matrix,
+            log2_max_frame_num_minus4,
+            pic_order_cnt,
+            max_num_ref_frames,
+            gaps_in_frame_num_value_allowed_flag,
+            pic_width_in_mbs_minus1,
+            pic_height_in_map_units_minus1,
+            frame_mbs_only_flag,
+            mb_adaptive_frame_field_flag,
+            direct_8x8_inference_flag,
+            frame_cropping,
+            vui,
+        };
+        // クロップが画像より大きい SPS は解像度を計算できない
+        if sps.width().is_none() || sps.height().is_none() {
+            return Err(H264Error::InvalidValue("frame_crop_offset", 0));
+        }
+        Ok(sps)
+    }
+
+    /// constraint_setN_flag（N = 0〜5）
+    pub fn constraint_set_flag(&self, n: u8) -> bool {
+        n < 6 && (self.constraint_flags >> (7 - n)) & 1 == 1
+    }
+
+    /// プロファイル名（ログ用）
+    pub fn profile_name(&self) -> &'static str {
+        match self.profile_idc {
+            66 if self.constraint_set_flag(1) => "Constrained Baseline",
+            66 => "Baseline",
+            77 => "Main",
+            88 => "Extended",
+            100 => "High",
+            110 => "High 10",
+            122 => "High 4:2:2",
+            244 => "High 4:4:4 Predictive",
+            44 => "CAVLC 4:4:4 Intra",
+            _ => "Unknown",
+        }
+    }
+
+    /// ChromaArrayType（separate_colour_plane_flag が立っていれば 0）
+    pub fn chroma_array_type(&self) -> u32 {
+        if self.separate_colour_plane_flag { 0 } else { self.chroma_format_idc }
+    }
+
+    pub fn bit_depth_luma(&self) -> u32 {
+        self.bit_depth_luma_minus8 + 8
+    }
+
+    /// MaxFrameNum
+    pub fn max_frame_num(&self) -> u32 {
+        1 << (self.log2_max_frame_num_minus4 + 4)
+    }
+
+    /// クロップ単位 (CropUnitX, CropUnitY)（7.4.2.1.1）
+    fn crop_unit(&self) -> (u32, u32) {
+        let field_factor = 2 - self.frame_mbs_only_flag as u32;
+        match self.chroma_array_type() {
+            1 => (2, 2 * field_factor), // 4:2:0
+            2 => (2, field_factor),     // 4:2:2
+            _ => (1, field_factor),     // モノクロ / 4:4:4
+        }
+    }
+
+    /// クロップ後の幅（ピクセル）
+    pub fn width(&self) -> Option<u32> {
+        let width = (self.pic_width_in_mbs_minus1 as u64 + 1) * 16;
+        let crop = self.frame_cropping.map_or(0, |c| (c.left as u64 + c.right as u64) * self.crop_unit().0 as u64);
+        u32::try_from(width.checked_sub(crop)?).ok()
+    }
+
+    /// クロップ後の高さ（ピクセル）
+    pub fn height(&self) -> Option<u32> {
+        let field_factor = 2 - self.frame_mbs_only_flag as u64;
+        let height = (self.pic_height_in_map_units_minus1 as u64 + 1) * 16 * field_factor;
+        let crop = self.frame_cropping.map_or(0, |c| (c.top as u64 + c.bottom as u64) * self.crop_unit().1 as u64);
+        u32::try_from(height.checked_sub(crop)?).ok()
+    }
+}
+
+/// rbsp_trailing_bits（rbsp_stop_one_bit とバイト境界までの 0）
+fn rbsp_trailing_bits(br: &mut BitReader) -> Result<(), H264Error> {
+    if !flag(br)? {
+        return Err(H264Error::InvalidValue("rbsp_stop_one_bit", 0));
+    }
+    while !br.byte_aligned() {
+        if flag(br)? {
+            return Err(H264Error::InvalidValue("rbsp_alignment_zero_bit", 1));
+        }
+    }
+    Ok(())
+}
+
+/// NAL ヘッダ（1バイト目）を読んで (nal_ref_idc, nal_unit_type) を返す。
+fn nal_header(br: &mut BitReader) -> Result<(u8, u8), H264Error> {
+    br.skip_bits(1).ok_or(H264Error::Truncated)?; // forbidden_zero_bit
+    let nal_ref_idc = u(br, 2)? as u8;
+    let nal_unit_type = u(br, 5)? as u8;
+    Ok((nal_ref_idc, nal_unit_type))
+}
+
+/// スライスグループの割り当て（num_slice_groups_minus1 > 0 の場合、FMO）
+#[derive(Debug, Clone, PartialEq)]
+pub enum SliceGroupMap {
+    /// slice_group_map_type 0
+    Interleaved { run_length_minus1: Vec<u32> },
+    /// slice_group_map_type 1
+    Dispersed,
+    /// slice_group_map_type 2: (top_left, bottom_right) の組
+    Foreground { rectangles: Vec<(u32, u32)> },
+    /// slice_group_map_type 3〜5
+    Changing {
+        slice_group_map_type: u32,
+        slice_group_change_direction_flag: bool,
+        slice_group_change_rate_minus1: u32,
+    },
+    /// slice_group_map_type 6
+    Explicit { slice_group_id: Vec<u32> },
+}
+
+/// ピクチャパラメータセット（7.3.2.2）
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pps {
+    pub pic_parameter_set_id: u32,
+    pub seq_parameter_set_id: u32,
+    pub entropy_coding_mode_flag: bool,
+    pub bottom_field_pic_order_in_frame_present_flag: bool,
+    pub num_slice_groups_minus1: u32,
+    pub slice_group_map: Option<SliceGroupMap>,
+    pub num_ref_idx_l0_default_active_minus1: u32,
+    pub num_ref_idx_l1_default_active_minus1: u32,
+    pub weighted_pred_flag: bool,
+    pub weighted_bipred_idc: u32,
+    pub pic_init_qp_minus26: i32,
+    pub pic_init_qs_minus26: i32,
+    pub chroma_qp_index_offset: i32,
+    pub deblocking_filter_control_present_flag: bool,
+    pub constrained_intra_pred_flag: bool,
+    pub redundant_pic_cnt_present_flag: bool,
+    /// 以下は High 系の拡張（なければ既定値）
+    pub transform_8x8_mode_flag: bool,
+    pub pic_scaling_matrix: Option<ScalingMatrix>,
+    /// 省略時は chroma_qp_index_offset と同じ
+    pub second_chroma_qp_index_offset: i32,
+}
+
+impl Pps {
+    /// PPS の (pic_parameter_set_id, seq_parameter_set_id) だけを読む。
+    /// 参照先の SPS をまだ受け取っていなくても、どの SPS を待てばよいか分かる。
+    pub fn ids(nal: &[u8]) -> Result<(u32, u32), H264Error> {
+        let rbsp = nal_to_rbsp(nal);
+        let mut br = BitReader::new(&rbsp);
+        let (_, nal_unit_type) = nal_header(&mut br)?;
+        if nal_unit_type != 8 {
+            return Err(H264Error::UnexpectedNalType(nal_unit_type));
+        }
+        let pic_parameter_set_id = check("pic_parameter_set_id", ue(&mut br)?, 255)?;
+        let seq_parameter_set_id = check("seq_parameter_set_id", ue(&mut br)?, 31)?;
+        Ok((pic_parameter_set_id, seq_parameter_set_id))
+    }
+
+    /// PPS NAL ユニット（NAL ヘッダバイト込み、スタートコードなし）を解析する。
+    /// スケーリング行列の数が chroma_format_idc で変わるので、参照先の SPS が必要。
+    pub fn parse(nal: &[u8], sps: &Sps) -> Result<Pps, H264Error> {
+        let rbsp = nal_to_rbsp(nal);
+        let mut br = BitReader::new(&rbsp);
+        let (_, nal_unit_type) = nal_header(&mut br)?;
+        if nal_unit_type != 8 {
+            return Err(H264Error::UnexpectedNalType(nal_unit_type));
+        }
+
+        let pic_parameter_set_id = check("pic_parameter_set_id", ue(&mut br)?, 255)?;
+        let seq_parameter_set_id = check("seq_parameter_set_id", ue(&mut br)?, 31)?;
+        if seq_parameter_set_id != sps.seq_parameter_set_id {
+            return Err(H264Error::InvalidValue("seq_parameter_set_id", seq_parameter_set_id as i64));
+        }
+        let entropy_coding_mode_flag = flag(&mut br)?;
+        let bottom_field_pic_order_in_frame_present_flag = flag(&mut br)?;
+        let num_slice_groups_minus1 = check("num_slice_groups_minus1", ue(&mut br)?, 7)?;
+        let slice_group_map = if num_slice_groups_minus1 > 0 {
+            Some(match ue(&mut br)? {
+                0 => SliceGroupMap::Interleaved {
+                    run_length_minus1: (0..=num_slice_groups_minus1).map(|_| ue(&mut br)).collect::<Result<_, _>>()?,
+                },
+                1 => SliceGroupMap::Dispersed,
+                2 => SliceGroupMap::Foreground {
+                    rectangles: (0..num_slice_groups_minus1)
+                        .map(|_| Ok((ue(&mut br)?, ue(&mut br)?)))
+                        .collect::<Result<_, H264Error>>()?,
+                },
+                t @ 3..=5 => SliceGroupMap::Changing {
+                    slice_group_map_type: t,
+                    slice_group_change_direction_flag: flag(&mut br)?,
+                    slice_group_change_rate_minus1: ue(&mut br)?,
+                },
+                6 => {
+                    let pic_size_in_map_units_minus1 = ue(&mut br)?;
+                    // 画像のマクロブロック数を超える値は読まない（壊れたデータで巨大な確保をしない）
+                    let map_units = (sps.pic_width_in_mbs_minus1 as u64 + 1) * (sps.pic_height_in_map_units_minus1 as u64 + 1);
+                    if pic_size_in_map_units_minus1 as u64 >= map_units {
+                        return Err(H264Error::InvalidValue("pic_size_in_map_units_minus1", pic_size_in_map_units_minus1 as i64));
+                    }
+                    // Ceil(Log2(num_slice_groups_minus1 + 1)) ビット
+                    let bits = (u32::BITS - num_slice_groups_minus1.leading_zeros()) as usize;
+                    SliceGroupMap::Explicit {
+                        slice_group_id: (0..=pic_size_in_map_units_minus1).map(|_| u(&mut br, bits)).collect::<Result<_, _>>()?,
                     }
                 }
+                t => return Err(H264Error::InvalidValue("slice_group_map_type", t as i64)),
+            })
+        } else {
+            None
+        };
+        let num_ref_idx_l0_default_active_minus1 = check("num_ref_idx_l0_default_active_minus1", ue(&mut br)?, 31)?;
+        let num_ref_idx_l1_default_active_minus1 = check("num_ref_idx_l1_default_active_minus1", ue(&mut br)?, 31)?;
+        let weighted_pred_flag = flag(&mut br)?;
+        let weighted_bipred_idc = check("weighted_bipred_idc", u(&mut br, 2)?, 2)?;
+        let pic_init_qp_minus26 = se(&mut br)?;
+        let pic_init_qs_minus26 = se(&mut br)?;
+        let chroma_qp_index_offset = se(&mut br)?;
+        let deblocking_filter_control_present_flag = flag(&mut br)?;
+        let constrained_intra_pred_flag = flag(&mut br)?;
+        let redundant_pic_cnt_present_flag = flag(&mut br)?;
+
+        let mut transform_8x8_mode_flag = false;
+        let mut pic_scaling_matrix = None;
+        let mut second_chroma_qp_index_offset = chroma_qp_index_offset;
+        if br.more_rbsp_data() {
+            transform_8x8_mode_flag = flag(&mut br)?;
+            if flag(&mut br)? {
+                let lists_8x8 = if transform_8x8_mode_flag {
+                    if sps.chroma_format_idc != 3 { 2 } else { 6 }
+                } else {
+                    0
+                };
+                pic_scaling_matrix = Some(ScalingMatrix::parse(&mut br, 6 + lists_8x8)?);
             }
+            second_chroma_qp_index_offset = se(&mut br)?;
+        }
+        rbsp_trailing_bits(&mut br)?;
+
+        Ok(Pps {
+            pic_parameter_set_id,
+            seq_parameter_set_id,
+            entropy_coding_mode_flag,
+            bottom_field_pic_order_in_frame_present_flag,
+            num_slice_groups_minus1,
+            slice_group_map,
+            num_ref_idx_l0_default_active_minus1,
+            num_ref_idx_l1_default_active_minus1,
+            weighted_pred_flag,
+            weighted_bipred_idc,
+            pic_init_qp_minus26,
+            pic_init_qs_minus26,
+            chroma_qp_index_offset,
+            deblocking_filter_control_present_flag,
+            constrained_intra_pred_flag,
+            redundant_pic_cnt_present_flag,
+            transform_8x8_mode_flag,
+            pic_scaling_matrix,
+            second_chroma_qp_index_offset,
+        })
+    }
+}
+
+/// slice_type（表 7-6。5〜9 は 0〜4 と同じ種類）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SliceType {
+    P,
+    B,
+    I,
+    SP,
+    SI,
+}
+
+impl SliceType {
+    fn from_raw(slice_type: u32) -> Result<SliceType, H264Error> {
+        match slice_type % 5 {
+            _ if slice_type > 9 => Err(H264Error::InvalidValue("slice_type", slice_type as i64)),
+            0 => Ok(SliceType::P),
+            1 => Ok(SliceType::B),
+            2 => Ok(SliceType::I),
+            3 => Ok(SliceType::SP),
+            _ => Ok(SliceType::SI),
         }
     }
 
-    br.read_ue()?; // log2_max_frame_num_minus4
-    let pic_order_cnt_type = br.read_ue()?;
-    if pic_order_cnt_type == 0 {
-        br.read_ue()?; // log2_max_pic_order_cnt_lsb_minus4
-    } else if pic_order_cnt_type == 1 {
-        br.read_bits(1)?; // delta_pic_order_always_zero_flag
-        br.read_se()?;    // offset_for_non_ref_pic
-        br.read_se()?;    // offset_for_top_to_bottom_field
-        let num = br.read_ue()?;
-        for _ in 0..num {
-            br.read_se()?;
-        }
-    }
-
-    br.read_ue()?;    // max_num_ref_frames
-    br.read_bits(1)?; // gaps_in_frame_num_value_allowed_flag
-
-    let width_in_mbs  = br.read_ue()? + 1;
-    let height_in_mbs = br.read_ue()? + 1;
-    let frame_mbs_only = br.read_bits(1)?;
-
-    if frame_mbs_only == 0 {
-        br.read_bits(1)?; // mb_adaptive_frame_field_flag
-    }
-
-    br.read_bits(1)?; // direct_8x8_inference_flag
-
-    let crop_flag = br.read_bits(1)?;
-    let (crop_l, crop_r, crop_t, crop_b) = if crop_flag == 1 {
-        (br.read_ue()?, br.read_ue()?, br.read_ue()?, br.read_ue()?)
-    } else {
-        (0, 0, 0, 0)
-    };
-
-    let (crop_unit_x, crop_unit_y) = match chroma_format_idc {
-        0 => (1u32, 2 - frame_mbs_only),
-        1 => (2,    2 * (2 - frame_mbs_only)),
-        2 => (2,    2 - frame_mbs_only),
-        3 => (1,    2 - frame_mbs_only),
-        _ => (1, 1),
-    };
-
-    let mut width  = width_in_mbs  * 16;
-    let mut height = height_in_mbs * 16;
-    if frame_mbs_only == 0 { height *= 2; }
-    width  -= (crop_l + crop_r) * crop_unit_x;
-    height -= (crop_t + crop_b) * crop_unit_y;
-
-    Some((width as u16, height as u16))
-}
-
-pub fn decode_sps(payload: &[u8]) {
-    let mut br = BitReader::new(&payload[1..]);
-     let profile_idc = br.read_bits(8).unwrap();
-    match profile_idc {
-        66 => {
-            println!("SPS Profile({}): Baseline", profile_idc);
-        },
-        77 => {
-            println!("SPS Profile({}): Main", profile_idc);
-        },
-        88 => {
-            println!("SPS Profile({}): Extended", profile_idc);
-        },
-        100 => {
-            println!("SPS Profile({}): High", profile_idc);
-        }
-        _ => {
-            println!("SPS Profile({}): Unknown", profile_idc);
-        }
-    }
-    let constraint_set0_flag = br.read_bits(1).unwrap() == 1;
-    let constraint_set1_flag = br.read_bits(1).unwrap() == 1;
-    let constraint_set2_flag = br.read_bits(1).unwrap() == 1;
-    let constraint_set3_flag = br.read_bits(1).unwrap() == 1;
-    let constraint_set4_flag = br.read_bits(1).unwrap() == 1;
-    let constraint_set5_flag = br.read_bits(1).unwrap() == 1;
-    let reserved_zero_2bits  = br.read_bits(2).unwrap() == 1;
-
-    println!("{}", format!("SPS Constraint Set0 Flag: {}", constraint_set0_flag).red());
-    println!("{}", format!("SPS Constraint Set1 Flag: {}", constraint_set1_flag).red());
-    println!("{}", format!("SPS Constraint Set2 Flag: {}", constraint_set2_flag).red());
-    println!("{}", format!("SPS Constraint Set3 Flag: {}", constraint_set3_flag).red());
-    println!("{}", format!("SPS Constraint Set4 Flag: {}", constraint_set4_flag).red());
-    println!("{}", format!("SPS Constraint Set5 Flag: {}", constraint_set5_flag).red());
-    println!("{}", format!("SPS Reserved Zero 2 Bits: {}", reserved_zero_2bits).red());
-
-    let level_idc = br.read_bits(8).unwrap();
-    println!("{}", format!("level IDC : {}", level_idc).red());
-    let sps_id = br.read_ue().unwrap();
-    println!("{}", format!("SPS ID = {}", sps_id).red());
-
-    let profile_idc_list = [100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134, 135];
-    if profile_idc_list.contains(&profile_idc) {
-        let chroma_format_idc = br.read_ue().unwrap();
-        println!("{}", format!("SPS chroma_format_idc = {}", chroma_format_idc).red());
-        if chroma_format_idc == 3 {
-            let separate_colour_plane_flag  = br.read_bits(1).unwrap() == 1;
-             println!("{}", format!("SPS separate_colour_plane_flag: {}", separate_colour_plane_flag).red());
-        }
-
-        let bit_depth_luma_minus8 = br.read_ue().unwrap();
-        println!("{}", format!("SPS bit_depth_luma_minus8 = {}", bit_depth_luma_minus8).red());
-        let bit_depth_chroma_minus8 = br.read_ue().unwrap();
-        println!("{}", format!("SPS bit_depth_chroma_minus8 = {}", bit_depth_chroma_minus8).red());
-        let qpprime_y_zero_transform_bypass_flag = br.read_bits(1).unwrap() == 1;
-        println!("{}", format!("SPS qpprime_y_zero_transform_bypass_flag = {}", qpprime_y_zero_transform_bypass_flag).red());
-        let seq_scaling_matrix_present_flag = br.read_bits(1).unwrap() == 1;
-        println!("{}", format!("SPS seq_scaling_matrix_present_flag = {}", seq_scaling_matrix_present_flag).red());
-        if( seq_scaling_matrix_present_flag ) {
-            let mut count = 12;
-            if chroma_format_idc != 3 {
-                count = 8;
+    /// イントラ予測だけのスライス（参照リストを持たない）
+    pub fn is_intra(self) -> bool {
+        matches!(self, SliceType::I | SliceType::SI)
+    }
+}
+
+/// スライスヘッダ（7.3.3）。POC とアクセスユニットの境界判定に必要な dec_ref_pic_marking まで読む。
+#[derive(Debug, Clone, PartialEq)]
+pub struct SliceHeader {
+    pub nal_ref_idc: u8,
+    pub nal_unit_type: u8,
+    pub first_mb_in_slice: u32,
+    pub slice_type: SliceType,
+    pub pic_parameter_set_id: u32,
+    pub colour_plane_id: u8,
+    pub frame_num: u32,
+    pub field_pic_flag: bool,
+    pub bottom_field_flag: bool,
+    /// IDR ピクチャの場合だけ
+    pub idr_pic_id: Option<u32>,
+    pub pic_order_cnt_lsb: u32,
+    pub delta_pic_order_cnt_bottom: i32,
+    pub delta_pic_order_cnt: [i32; 2],
+    pub redundant_pic_cnt: u32,
+    /// dec_ref_pic_marking に memory_management_control_operation = 5 が含まれる
+    pub memory_management_control_operation_5: bool,
+}
+
+impl SliceHeader {
+    /// スライス NAL ユニット（タイプ 1, 5。NAL ヘッダバイト込み）のスライスヘッダを解析する。
+    ///
+    /// `lookup` はスライスの pic_parameter_set_id から、その PPS と PPS が参照する SPS を返す。
+    /// 見つからなければ `H264Error::MissingParameterSet` を返す。
+    pub fn parse<'a>(
+        nal: &[u8],
+        lookup: impl FnOnce(u32) -> Result<(&'a Sps, &'a Pps), H264Error>,
+    ) -> Result<SliceHeader, H264Error> {
+        let rbsp = nal_to_rbsp(nal);
+        let mut br = BitReader::new(&rbsp);
+        let (nal_ref_idc, nal_unit_type) = nal_header(&mut br)?;
+        if nal_unit_type != 1 && nal_unit_type != 5 {
+            return Err(H264Error::UnexpectedNalType(nal_unit_type));
+        }
+        let idr = nal_unit_type == 5;
+
+        let first_mb_in_slice = ue(&mut br)?;
+        let slice_type = SliceType::from_raw(ue(&mut br)?)?;
+        let pic_parameter_set_id = check("pic_parameter_set_id", ue(&mut br)?, 255)?;
+        let (sps, pps) = lookup(pic_parameter_set_id)?;
+        let colour_plane_id = if sps.separate_colour_plane_flag { u(&mut br, 2)? as u8 } else { 0 };
+        let frame_num = u(&mut br, sps.log2_max_frame_num_minus4 as usize + 4)?;
+        let mut field_pic_flag = false;
+        let mut bottom_field_flag = false;
+        if !sps.frame_mbs_only_flag {
+            field_pic_flag = flag(&mut br)?;
+            if field_pic_flag {
+                bottom_field_flag = flag(&mut br)?;
+            }
+        }
+        let idr_pic_id = if idr { Some(ue(&mut br)?) } else { None };
+
+        let mut pic_order_cnt_lsb = 0;
+        let mut delta_pic_order_cnt_bottom = 0;
+        let mut delta_pic_order_cnt = [0; 2];
+        match sps.pic_order_cnt {
+            PicOrderCnt::Type0 { log2_max_pic_order_cnt_lsb_minus4 } => {
+                pic_order_cnt_lsb = u(&mut br, log2_max_pic_order_cnt_lsb_minus4 as usize + 4)?;
+                if pps.bottom_field_pic_order_in_frame_present_flag && !field_pic_flag {
+                    delta_pic_order_cnt_bottom = se(&mut br)?;
+                }
+            }
+            PicOrderCnt::Type1 { delta_pic_order_always_zero_flag: false, .. } => {
+                delta_pic_order_cnt[0] = se(&mut br)?;
+                if pps.bottom_field_pic_order_in_frame_present_flag && !field_pic_flag {
+                    delta_pic_order_cnt[1] = se(&mut br)?;
+                }
+            }
+            _ => {}
+        }
+        let redundant_pic_cnt = if pps.redundant_pic_cnt_present_flag {
+            check("redundant_pic_cnt", ue(&mut br)?, 127)?
+        } else {
+            0
+        };
+
+        if slice_type == SliceType::B {
+            br.skip_bits(1).ok_or(H264Error::Truncated)?; // direct_spatial_mv_pred_flag
+        }
+        let mut num_ref_idx_l0_active_minus1 = pps.num_ref_idx_l0_default_active_minus1;
+        let mut num_ref_idx_l1_active_minus1 = pps.num_ref_idx_l1_default_active_minus1;
+        if !slice_type.is_intra() && flag(&mut br)? { // num_ref_idx_active_override_flag
+            num_ref_idx_l0_active_minus1 = check("num_ref_idx_l0_active_minus1", ue(&mut br)?, 31)?;
+            if slice_type == SliceType::B {
+                num_ref_idx_l1_active_minus1 = check("num_ref_idx_l1_active_minus1", ue(&mut br)?, 31)?;
+            }
+        }
+
+        // ref_pic_list_modification
+        let lists = match slice_type {
+            SliceType::B => 2,
+            t if t.is_intra() => 0,
+            _ => 1,
+        };
+        for _ in 0..lists {
+            if flag(&mut br)? { // ref_pic_list_modification_flag_lX
+                loop {
+                    match ue(&mut br)? { // modification_of_pic_nums_idc
+                        0..=2 => { ue(&mut br)?; } // abs_diff_pic_num_minus1 / long_term_pic_num
+                        3 => break,
+                        v => return Err(H264Error::InvalidValue("modification_of_pic_nums_idc", v as i64)),
+                    }
+                }
+            }
+        }
+
+        // pred_weight_table
+        let weighted = match slice_type {
+            SliceType::P | SliceType::SP => pps.weighted_pred_flag,
+            SliceType::B => pps.weighted_bipred_idc == 1,
+            _ => false,
+        };
+        if weighted {
+            ue(&mut br)?; // luma_log2_weight_denom
+            let chroma = sps.chroma_array_type() != 0;
+            if chroma {
+                ue(&mut br)?; // chroma_log2_weight_denom
+            }
+            let mut refs = vec![num_ref_idx_l0_active_minus1];
+            if slice_type == SliceType::B {
+                refs.push(num_ref_idx_l1_active_minus1);
+            }
+            for num_minus1 in refs {
+                for _ in 0..=num_minus1 {
+                    if flag(&mut br)? { // luma_weight_lX_flag
+                        se(&mut br)?;
+                        se(&mut br)?;
+                    }
+                    if chroma && flag(&mut br)? { // chroma_weight_lX_flag
+                        for _ in 0..4 {
+                            se(&mut br)?;
+                        }
+                    }
+                }
             }
-            for i in 0..count  {
-                /*
-                let seq_scaling_list_present_flag = br.read_bits(1).unwrap() == 1;
-                if (seq_scaling_list_present_flag) {
-                    if (i < 6) {
-                        scaling_list( ScalingList4x4[ i ], 16, UseDefaultScalingMatrix4x4Flag[ i ] );
-                    } else {
-                       scaling_list( ScalingList8x8[ i − 6 ], 64, UseDefaultScalingMatrix8x8Flag[ i − 6 ] )
+        }
+
+        // dec_ref_pic_marking
+        let mut memory_management_control_operation_5 = false;
+        if nal_ref_idc != 0 {
+            if idr {
+                br.skip_bits(2).ok_or(H264Error::Truncated)?; // no_output_of_prior_pics_flag, long_term_reference_flag
+            } else if flag(&mut br)? { // adaptive_ref_pic_marking_mode_flag
+                loop {
+                    match ue(&mut br)? {
+                        0 => break,
+                        op @ 1..=6 => {
+                            if op == 1 || op == 3 {
+                                ue(&mut br)?; // difference_of_pic_nums_minus1
+                            }
+                            if op == 2 {
+                                ue(&mut br)?; // long_term_pic_num
+                            }
+                            if op == 3 || op == 6 {
+                                ue(&mut br)?; // long_term_frame_idx
+                            }
+                            if op == 4 {
+                                ue(&mut br)?; // max_long_term_frame_idx_plus1
+                            }
+                            memory_management_control_operation_5 |= op == 5;
+                        }
+                        op => return Err(H264Error::InvalidValue("memory_management_control_operation", op as i64)),
                     }
                 }
-                */
             }
         }
 
-        // --- ⑤ フレーム情報 ---
-        let log2_max_frame_num_minus4 = br.read_ue().unwrap();
-        let pic_order_cnt_type = br.read_ue().unwrap();
+        Ok(SliceHeader {
+            nal_ref_idc,
+            nal_unit_type,
+            first_mb_in_slice,
+            slice_type,
+            pic_parameter_set_id,
+            colour_plane_id,
+            frame_num,
+            field_pic_flag,
+            bottom_field_flag,
+            idr_pic_id,
+            pic_order_cnt_lsb,
+            delta_pic_order_cnt_bottom,
+            delta_pic_order_cnt,
+            redundant_pic_cnt,
+            memory_management_control_operation_5,
+        })
+    }
+
+    pub fn is_idr(&self) -> bool {
+        self.nal_unit_type == 5
+    }
+
+    /// このスライスが直前のスライスとは別のプライマリピクチャの最初のスライスか（7.4.1.2.4）。
+    pub fn starts_new_picture(&self, prev: &SliceHeader) -> bool {
+        // 冗長ピクチャのスライスはプライマリピクチャに含まれない
+        if self.redundant_pic_cnt > 0 {
+            return false;
+        }
+        self.frame_num != prev.frame_num
+            || self.pic_parameter_set_id != prev.pic_parameter_set_id
+            || self.field_pic_flag != prev.field_pic_flag
+            || self.bottom_field_flag != prev.bottom_field_flag
+            || (self.nal_ref_idc == 0) != (prev.nal_ref_idc == 0)
+            || self.pic_order_cnt_lsb != prev.pic_order_cnt_lsb
+            || self.delta_pic_order_cnt_bottom != prev.delta_pic_order_cnt_bottom
+            || self.delta_pic_order_cnt != prev.delta_pic_order_cnt
+            || self.is_idr() != prev.is_idr()
+            || (self.is_idr() && prev.is_idr() && self.idr_pic_id != prev.idr_pic_id)
+    }
+}
+
+/// ピクチャ順序カウント（POC、8.2.1）を復号順に計算する。
+///
+/// POC は表示順を表す値で、IDR（または memory_management_control_operation = 5）ごとに基準が戻る。
+/// 前のピクチャの値を使うので、すべてのピクチャを復号順に渡すこと。
+#[derive(Debug, Default)]
+pub struct PicOrderCounter {
+    /// 直前の参照ピクチャの PicOrderCntMsb / pic_order_cnt_lsb（タイプ 0）
+    prev_pic_order_cnt_msb: i64,
+    prev_pic_order_cnt_lsb: i64,
+    /// 直前のピクチャの FrameNumOffset / frame_num（タイプ 1, 2）
+    prev_frame_num_offset: i64,
+    prev_frame_num: u32,
+}
 
-        println!("log2_max_frame_num_minus4: {}", log2_max_frame_num_minus4);
-        println!("pic_order_cnt_type: {}", pic_order_cnt_type);
+impl PicOrderCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-        if pic_order_cnt_type == 0 {
-            let v = br.read_ue().unwrap();
-            println!("log2_max_pic_order_cnt_lsb_minus4: {}", v);
-        } else if pic_order_cnt_type == 1 {
-            let flag = br.read_bits(1).unwrap();
-            let offset1 = br.read_se().unwrap();
-            let offset2 = br.read_se().unwrap();
-            let num = br.read_ue().unwrap();
+    /// スライスヘッダ（ピクチャの最初のスライス）から PicOrderCnt を求める。
+    pub fn compute(&mut self, sps: &Sps, header: &SliceHeader) -> i32 {
+        let (top, bottom) = match sps.pic_order_cnt {
+            PicOrderCnt::Type0 { log2_max_pic_order_cnt_lsb_minus4 } => {
+                self.compute_type0(header, 1i64 << (log2_max_pic_order_cnt_lsb_minus4 + 4))
+            }
+            PicOrderCnt::Type1 { offset_for_non_ref_pic, offset_for_top_to_bottom_field, ref offset_for_ref_frame, .. } => {
+                let frame_num_offset = self.frame_num_offset(sps, header);
+                let n = offset_for_ref_frame.len() as i64;
+                let mut abs_frame_num = if n != 0 { frame_num_offset + header.frame_num as i64 } else { 0 };
+                if header.nal_ref_idc == 0 && abs_frame_num > 0 {
+                    abs_frame_num -= 1;
+                }
+                let mut expected = 0i64;
+                if abs_frame_num > 0 {
+                    let cycle_cnt = (abs_frame_num - 1) / n;
+                    let in_cycle = ((abs_frame_num - 1) % n) as usize;
+                    let delta_per_cycle: i64 = offset_for_ref_frame.iter().map(|&o| o as i64).sum();
+                    expected = cycle_cnt.wrapping_mul(delta_per_cycle)
+                        + offset_for_ref_frame[..=in_cycle].iter().map(|&o| o as i64).sum::<i64>();
+                }
+                if header.nal_ref_idc == 0 {
+                    expected += offset_for_non_ref_pic as i64;
+                }
+                let delta = header.delta_pic_order_cnt.map(|d| d as i64);
+                let t2b = offset_for_top_to_bottom_field as i64;
+                if !header.field_pic_flag {
+                    let top = expected + delta[0];
+                    (top, top + t2b + delta[1])
+                } else if !header.bottom_field_flag {
+                    (expected + delta[0], expected + delta[0])
+                } else {
+                    (expected + t2b + delta[0], expected + t2b + delta[0])
+                }
+            }
+            PicOrderCnt::Type2 => {
+                let frame_num_offset = self.frame_num_offset(sps, header);
+                let poc = if header.is_idr() {
+                    0
+                } else if header.nal_ref_idc == 0 {
+                    2 * (frame_num_offset + header.frame_num as i64) - 1
+                } else {
+                    2 * (frame_num_offset + header.frame_num as i64)
+                };
+                (poc, poc)
+            }
+        };
 
-            println!("delta_pic_order_always_zero_flag: {}", flag);
-            println!("offset_for_non_ref_pic: {}", offset1);
-            println!("offset_for_top_to_bottom_field: {}", offset2);
-            println!("num_ref_frames_in_pic_order_cnt_cycle: {}", num);
+        let poc = if !header.field_pic_flag {
+            top.min(bottom)
+        } else if !header.bottom_field_flag {
+            top
+        } else {
+            bottom
+        };
 
-            for i in 0..num {
-                let v = br.read_se().unwrap();
-                println!("offset_for_ref_frame[{}]: {}", i, v);
+        // memory_management_control_operation = 5 の後は POC と frame_num が 0 から数え直しになる
+        if header.memory_management_control_operation_5 {
+            self.prev_frame_num_offset = 0;
+            self.prev_frame_num = 0;
+            if header.nal_ref_idc != 0 {
+                self.prev_pic_order_cnt_msb = 0;
+                // tempPicOrderCnt を引いた後の TopFieldOrderCnt（ボトムフィールドなら 0）
+                self.prev_pic_order_cnt_lsb = if header.field_pic_flag && header.bottom_field_flag { 0 } else { top - poc };
             }
         }
+        poc as i32
+    }
 
-        let max_num_ref_frames = br.read_ue().unwrap();
-        let gaps_flag = br.read_bits(1).unwrap();
+    /// タイプ 0: pic_order_cnt_lsb の折り返しから PicOrderCntMsb を求める（8.2.1.1）。
+    fn compute_type0(&mut self, header: &SliceHeader, max_lsb: i64) -> (i64, i64) {
+        if header.is_idr() {
+            self.prev_pic_order_cnt_msb = 0;
+            self.prev_pic_order_cnt_lsb = 0;
+        }
+        let lsb = header.pic_order_cnt_lsb as i64;
+        let prev_lsb = self.prev_pic_order_cnt_lsb;
+        let msb = if lsb < prev_lsb && prev_lsb - lsb >= max_lsb / 2 {
+            self.prev_pic_order_cnt_msb + max_lsb
+        } else if lsb > prev_lsb && lsb - prev_lsb > max_lsb / 2 {
+            self.prev_pic_order_cnt_msb - max_lsb
+        } else {
+            self.prev_pic_order_cnt_msb
+        };
+        // 参照ピクチャだけが次のピクチャの基準になる
+        if header.nal_ref_idc != 0 {
+            self.prev_pic_order_cnt_msb = msb;
+            self.prev_pic_order_cnt_lsb = lsb;
+        }
+        if !header.field_pic_flag {
+            (msb + lsb, msb + lsb + header.delta_pic_order_cnt_bottom as i64)
+        } else {
+            (msb + lsb, msb + lsb)
+        }
+    }
 
-        println!("max_num_ref_frames: {}", max_num_ref_frames);
-        println!("gaps_in_frame_num_value_allowed_flag: {}", gaps_flag);
+    /// タイプ 1, 2: frame_num の折り返しを数えた FrameNumOffset（8.2.1.2）。
+    fn frame_num_offset(&mut self, sps: &Sps, header: &SliceHeader) -> i64 {
+        let offset = if header.is_idr() {
+            0
+        } else if self.prev_frame_num > header.frame_num {
+            self.prev_frame_num_offset + sps.max_frame_num() as i64
+        } else {
+            self.prev_frame_num_offset
+        };
+        self.prev_frame_num_offset = offset;
+        self.prev_frame_num = header.frame_num;
+        offset
+    }
+}
 
-        let pic_width_in_mbs_minus1 = br.read_ue().unwrap();
-        let pic_height_in_map_units_minus1 = br.read_ue().unwrap();
 
-        println!("pic_width_in_mbs_minus1: {}", pic_width_in_mbs_minus1);
-        println!("pic_height_in_map_units_minus1: {}", pic_height_in_map_units_minus1);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let frame_mbs_only_flag = br.read_bits(1).unwrap();
-        println!("frame_mbs_only_flag: {}", frame_mbs_only_flag);
+    /// Dahua の sprop-parameter-sets の SPS（High、POC タイプ 0）
+    const DAHUA_SPS: &str = "Z2QAH6wsaoFAFumoCAgIEA==";
 
-        if frame_mbs_only_flag == 0 {
-            let flag = br.read_bits(1).unwrap();
-            println!("mb_adaptive_frame_field_flag: {}", flag);
+    /// Dahua の PPS（transform_8x8_mode_flag あり）
+    const DAHUA_PPS: &str = "aO48sA==";
+    /// Hikvision の sprop-parameter-sets（Main、POC タイプ 0）
+    const HIKVISION_SPS: &str = "Z00AKpY1QPAET8s3AQEBAgAAAwACAAADAHkBAQ==";
+    const HIKVISION_PPS: &str = "aO48gA==";
+    /// Axis の sprop-parameter-sets の SPS（Main、VUI に bitstream_restriction あり）
+    const AXIS_SPS: &str = "Z00AKeKQDwBE/LgLcBAQGkHiRFQ=";
+
+    /// openh264 のテストデータ multi_512x512.h264 の SPS / PPS と、続く2枚の IDR スライスの先頭
+    const OPENH264_SPS: &str = "6764001facb810020d2905060606d0a13c";
+    const OPENH264_PPS: &str = "68ee06f2c0";
+    const OPENH264_IDR: [&str; 2] = [
+        "65b840c7c224cd03c5fccce7b709cdc59344db948de93684",
+        "65b82031ffc224cd03c5fccce7b709cdc5a1a9de53fb4886",
+    ];
+
+    fn decode(sprop: &str) -> Vec<u8> {
+        parse_sprop_parameter_sets(sprop).remove(0)
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    /// NAL ヘッダバイトと "0"/"1" のビット列（空白は無視）から NAL ユニットを組み立てる。
+    /// 最後に rbsp_stop_one_bit とバイト境界までの 0 を付ける。
+    fn nal(header: u8, bits: &str) -> Vec<u8> {
+        let mut bits: Vec<u8> = bits.bytes().filter(|b| !b.is_ascii_whitespace()).map(|b| b - b'0').collect();
+        bits.push(1);
+        while !bits.len().is_multiple_of(8) {
+            bits.push(0);
         }
+        let mut out = vec![header];
+        out.extend(bits.chunks(8).map(|c| c.iter().fold(0, |acc, &b| (acc << 1) | b)));
+        out
+    }
 
-        let direct_8x8_inference_flag = br.read_bits(1).unwrap();
-        println!("direct_8x8_inference_flag: {}", direct_8x8_inference_flag);
+    fn dahua() -> (Sps, Pps) {
+        let sps = Sps::parse(&decode(DAHUA_SPS)).unwrap();
+        let pps = Pps::parse(&decode(DAHUA_PPS), &sps).unwrap();
+        (sps, pps)
+    }
 
-        // --- ⑥ cropping ---
-        let frame_cropping_flag = br.read_bits(1).unwrap();
-        println!("frame_cropping_flag: {}", frame_cropping_flag);
+    fn hikvision() -> (Sps, Pps) {
+        let sps = Sps::parse(&decode(HIKVISION_SPS)).unwrap();
+        let pps = Pps::parse(&decode(HIKVISION_PPS), &sps).unwrap();
+        (sps, pps)
+    }
 
-        let (mut crop_left, mut crop_right, mut crop_top, mut crop_bottom) = (0,0,0,0);
+    fn parse_slice(nal: &[u8], sps: &Sps, pps: &Pps) -> Result<SliceHeader, H264Error> {
+        SliceHeader::parse(nal, |_| Ok((sps, pps)))
+    }
 
-        if frame_cropping_flag == 1 {
-            crop_left = br.read_ue().unwrap();
-            crop_right = br.read_ue().unwrap();
-            crop_top = br.read_ue().unwrap();
-            crop_bottom = br.read_ue().unwrap();
+    /// max_frame_num = 16 にし、POC の種類を差し替えた SPS
+    fn sps_with(pic_order_cnt: PicOrderCnt) -> Sps {
+        let mut sps = Sps::parse(&decode(DAHUA_SPS)).unwrap();
+        sps.log2_max_frame_num_minus4 = 0;
+        sps.pic_order_cnt = pic_order_cnt;
+        sps
+    }
 
-            println!("crop_left: {}", crop_left);
-            println!("crop_right: {}", crop_right);
-            println!("crop_top: {}", crop_top);
-            println!("crop_bottom: {}", crop_bottom);
+    /// フレームのスライスヘッダ。`nal_ref_idc` が 0 なら非参照ピクチャ
+    fn frame(idr: bool, nal_ref_idc: u8, frame_num: u32, pic_order_cnt_lsb: u32) -> SliceHeader {
+        SliceHeader {
+            nal_ref_idc,
+            nal_unit_type: if idr { 5 } else { 1 },
+            first_mb_in_slice: 0,
+            slice_type: if idr { SliceType::I } else if nal_ref_idc == 0 { SliceType::B } else { SliceType::P },
+            pic_parameter_set_id: 0,
+            colour_plane_id: 0,
+            frame_num,
+            field_pic_flag: false,
+            bottom_field_flag: false,
+            idr_pic_id: if idr { Some(0) } else { None },
+            pic_order_cnt_lsb,
+            delta_pic_order_cnt_bottom: 0,
+            delta_pic_order_cnt: [0, 0],
+            redundant_pic_cnt: 0,
+            memory_management_control_operation_5: false,
         }
+    }
+
+    fn pocs(sps: &Sps, headers: &[SliceHeader]) -> Vec<i32> {
+        let mut counter = PicOrderCounter::new();
+        headers.iter().map(|h| counter.compute(sps, h)).collect()
+    }
+
+    #[test]
+    fn nal_to_rbsp_strips_emulation_prevention() {
+        // 続けて現れる 00 00 03 はどちらも取り除く
+        assert_eq!(nal_to_rbsp(&[0x00, 0x00, 0x03, 0x00, 0x00, 0x03]), vec![0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(nal_to_rbsp(&[0x65, 0x00, 0x00, 0x03, 0x01, 0x00, 0x00, 0x03, 0x03]), vec![0x65, 0x00, 0x00, 0x01, 0x00, 0x00, 0x03]);
+        // NAL の末尾の 00 00 03（cabac_zero_word）
+        assert_eq!(nal_to_rbsp(&[0x65, 0x80, 0x00, 0x00, 0x03]), vec![0x65, 0x80, 0x00, 0x00]);
+        // 00 に続かない 03 や 00 が1つだけの 03 はそのまま
+        assert_eq!(nal_to_rbsp(&[0x03, 0x00, 0x03, 0x00]), vec![0x03, 0x00, 0x03, 0x00]);
+        assert_eq!(nal_to_rbsp(&[]), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn bit_reader_read_bits() {
+        let data = [0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF, 0xFF];
+        let mut br = BitReader::new(&data);
+        assert_eq!(br.read_bits(33), None);
+        assert_eq!(br.read_bits64(65), None);
+        assert_eq!(br.read_bits(4), Some(0x0));
+        assert_eq!(br.read_bits64(33), Some(0x1_2345_6789 >> 3));
+        assert_eq!(br.bits_left(), 72 - 37);
+
+        let mut br = BitReader::new(&data);
+        assert_eq!(br.read_bits64(64), Some(0x0123_4567_89AB_CDEF));
+        // バイト境界にない位置からの 64 ビット
+        let mut br = BitReader::new(&data);
+        assert_eq!(br.read_bits(4), Some(0));
+        assert!(!br.byte_aligned());
+        assert_eq!(br.read_bits64(64), Some(0x1234_5678_9ABC_DEFF));
+        assert_eq!(br.bits_left(), 4);
+
+        // 終わりを超える読み込みは None で、位置は進まない
+        assert_eq!(br.read_bits(5), None);
+        assert_eq!(br.read_bits64(5), None);
+        assert_eq!(br.skip_bits(5), None);
+        assert_eq!(br.bits_left(), 4);
+        assert_eq!(br.read_bits(4), Some(0xF));
+        assert_eq!(br.read_flag(), None);
+        assert!(br.byte_aligned());
+    }
+
+    #[test]
+    fn bit_reader_exp_golomb() {
+        // 1 | 010 | 011 | 00100 | 00101 → ue 0, 1, 2, 3 と se -2
+        let data = nal(0, "1 010 011 00100 00101");
+        let mut br = BitReader::new(&data[1..]);
+        assert_eq!(br.read_ue(), Some(0));
+        assert_eq!(br.read_ue(), Some(1));
+        assert_eq!(br.read_se(), Some(-1));
+        assert_eq!(br.read_ue(), Some(3));
+        assert_eq!(br.read_se(), Some(-2));
+
+        // 31 個の 0、1、31 ビットの suffix が u32 で表せる最大の符号
+        let bits = format!("{} 1 {}", "0".repeat(31), "1".repeat(31));
+        let data = nal(0, &bits);
+        assert_eq!(BitReader::new(&data[1..]).read_ue(), Some(u32::MAX - 1));
+        assert_eq!(BitReader::new(&data[1..]).read_se(), Some(i32::MIN + 1));
+        let bits = format!("{} 1 {}", "0".repeat(31), "0".repeat(31));
+        let data = nal(0, &bits);
+        assert_eq!(BitReader::new(&data[1..]).read_ue(), Some(i32::MAX as u32));
+        assert_eq!(BitReader::new(&data[1..]).read_se(), Some(1 << 30));
+        // 32 個以上の 0 は u32 に収まらない
+        let data = [0x00, 0x00, 0x00, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00];
+        assert_eq!(BitReader::new(&data).read_ue(), None);
+        // suffix の途中や、0 しかないまま終わる
+        assert_eq!(BitReader::new(&[0x00, 0x01]).read_ue(), None);
+        assert_eq!(BitReader::new(&[0x00]).read_ue(), None);
+        assert_eq!(BitReader::new(&[]).read_se(), None);
+    }
+
+    #[test]
+    fn bit_reader_more_rbsp_data() {
+        // 1ビットのデータ + rbsp_stop_one_bit、後ろに cabac_zero_word が2つ
+        let rbsp = nal_to_rbsp(&[0xC0, 0x00, 0x00, 0x03, 0x00, 0x00, 0x03]);
+        assert_eq!(rbsp, vec![0xC0, 0x00, 0x00, 0x00, 0x00]);
+        let mut br = BitReader::new(&rbsp);
+        assert!(br.more_rbsp_data());
+        assert_eq!(br.read_flag(), Some(true));
+        assert!(!br.more_rbsp_data());
+        assert!(rbsp_trailing_bits(&mut br).is_ok());
+
+        // stop bit がバイトの最下位ビットにある
+        let mut br = BitReader::new(&[0x55, 0x01]);
+        br.skip_bits(14).unwrap();
+        assert!(br.more_rbsp_data());
+        br.skip_bits(1).unwrap();
+        assert!(!br.more_rbsp_data());
+
+        assert!(!BitReader::new(&[0x00, 0x00]).more_rbsp_data());
+        assert!(!BitReader::new(&[]).more_rbsp_data());
+    }
+
+    #[test]
+    fn poc_type0_lsb_wrap_and_msb_carry() {
+        // MaxPicOrderCntLsb = 16
+        let sps = sps_with(PicOrderCnt::Type0 { log2_max_pic_order_cnt_lsb_minus4: 0 });
+        let headers = [
+            frame(true, 3, 0, 0),
+            frame(false, 2, 1, 4),
+            frame(false, 2, 2, 8),
+            frame(false, 2, 3, 12),
+            // 12 → 0 で折り返して PicOrderCntMsb が 16 に繰り上がる
+            frame(false, 2, 4, 0),
+            // 非参照の B は直前の参照ピクチャ（lsb 0）を基準に 16 の前へ戻る
+            frame(false, 0, 5, 14),
+            frame(false, 2, 5, 6),
+            // 非参照ピクチャは基準を変えない
+            frame(false, 0, 6, 4),
+            frame(false, 2, 6, 10),
+            // IDR で基準が戻る
+            frame(true, 3, 0, 2),
+        ];
+        assert_eq!(pocs(&sps, &headers), vec![0, 4, 8, 12, 16, 14, 22, 20, 26, 2]);
+
+        // フレームの POC は TopFieldOrderCnt と BottomFieldOrderCnt の小さい方
+        let mut bottom_first = frame(false, 2, 1, 8);
+        bottom_first.delta_pic_order_cnt_bottom = -1;
+        assert_eq!(pocs(&sps, &[frame(true, 3, 0, 0), bottom_first]), vec![0, 7]);
+    }
+
+    #[test]
+    fn poc_type0_mmco5_reset() {
+        let sps = sps_with(PicOrderCnt::Type0 { log2_max_pic_order_cnt_lsb_minus4: 0 });
+        let mut mmco5 = frame(false, 2, 3, 6);
+        mmco5.memory_management_control_operation_5 = true;
+        let headers = [
+            frame(true, 3, 0, 0),
+            frame(false, 2, 1, 8),
+            frame(false, 2, 2, 0),  // 16
+            mmco5,                  // 22。この後は 0 から数え直す
+            frame(false, 2, 1, 2),
+            frame(false, 0, 2, 12), // 基準 lsb 2 から見ると負の側: -4
+        ];
+        assert_eq!(pocs(&sps, &headers), vec![0, 8, 16, 22, 2, -4]);
+    }
+
+    #[test]
+    fn poc_type1_offset_for_ref_frame_cycles() {
+        // 参照フレームごとに 2, 4 を交互に足す（1周期 6）。非参照ピクチャは -3
+        let sps = sps_with(PicOrderCnt::Type1 {
+            delta_pic_order_always_zero_flag: false,
+            offset_for_non_ref_pic: -3,
+            offset_for_top_to_bottom_field: 1,
+            offset_for_ref_frame: vec![2, 4],
+        });
+        let mut with_delta = frame(false, 2, 5, 0);
+        with_delta.delta_pic_order_cnt = [1, 0];
+        let headers = [
+            frame(true, 3, 0, 0),
+            frame(false, 2, 1, 0),  // 2
+            frame(false, 2, 2, 0),  // 2 + 4
+            frame(false, 2, 3, 0),  // 6 + 2（2周期目）
+            frame(false, 0, 4, 0),  // 直前の参照フレームの期待値 8 に -3
+            frame(false, 2, 4, 0),  // 12
+            with_delta,             // 14 + delta_pic_order_cnt[0]
+        ];
+        assert_eq!(pocs(&sps, &headers), vec![0, 2, 6, 8, 5, 12, 15]);
+
+        // frame_num の折り返し（max_frame_num = 16）で FrameNumOffset が進む
+        let mut headers = vec![frame(true, 3, 0, 0)];
+        headers.extend((1..16).map(|n| frame(false, 2, n, 0)));
+        headers.push(frame(false, 2, 0, 0));
+        let result = pocs(&sps, &headers);
+        // absFrameNum 15 → 7周期 + 2、absFrameNum 16 → 7周期 + 2 + 4
+        assert_eq!(&result[15..], &[44, 48]);
+    }
+
+    #[test]
+    fn poc_type2_frame_num_wrap_and_mmco5() {
+        let sps = sps_with(PicOrderCnt::Type2);
+        let mut headers = vec![frame(true, 3, 0, 0), frame(false, 2, 1, 0), frame(false, 0, 2, 0), frame(false, 2, 2, 0)];
+        headers.extend((3..16).map(|n| frame(false, 2, n, 0)));
+        headers.push(frame(false, 2, 0, 0));
+        let mut mmco5 = frame(false, 2, 1, 0);
+        mmco5.memory_management_control_operation_5 = true;
+        headers.push(mmco5);
+        headers.push(frame(false, 2, 1, 0));
+        let result = pocs(&sps, &headers);
+        assert_eq!(&result[..4], &[0, 2, 3, 4]);
+        assert_eq!(&result[16..], &[30, 32, 34, 2]);
+    }
+
+    #[test]
+    fn pps_parse_camera_sprop() {
+        let (sps, pps) = hikvision();
+        assert_eq!(Pps::ids(&decode(HIKVISION_PPS)), Ok((0, 0)));
+        assert!(pps.entropy_coding_mode_flag);
+        assert_eq!(pps.slice_group_map, None);
+        assert!(pps.deblocking_filter_control_present_flag);
+        // more_rbsp_data() が偽なので High の拡張は既定値
+        assert!(!pps.transform_8x8_mode_flag);
+        assert_eq!(pps.pic_scaling_matrix, None);
+        assert_eq!(pps.second_chroma_qp_index_offset, pps.chroma_qp_index_offset);
+        // SPS は PPS ではない
+        assert_eq!(Pps::parse(&decode(HIKVISION_SPS), &sps), Err(H264Error::UnexpectedNalType(7)));
+
+        let (_, pps) = dahua();
+        assert!(pps.transform_8x8_mode_flag);
+        assert_eq!(pps.pic_scaling_matrix, None);
+        assert_eq!(pps.second_chroma_qp_index_offset, 0);
+    }
 
-        // --- ⑦ 解像度 ---
-        let mut width = (pic_width_in_mbs_minus1 + 1) * 16;
-        let mut height = (pic_height_in_map_units_minus1 + 1) * 16;
+    #[test]
+    fn pps_parse_more_rbsp_data() {
+        let (sps, _) = dahua();
+        // chroma_qp_index_offset = -2、拡張なし
+        let base = "1 1 1 0 1 1 1 0 00 1 1 00101 1 0 0";
+        let pps = Pps::parse(&nal(0x68, base), &sps).unwrap();
+        assert!(!pps.transform_8x8_mode_flag);
+        assert_eq!(pps.second_chroma_qp_index_offset, -2);
 
-        if frame_mbs_only_flag == 0 {
-            height *= 2;
+        // transform_8x8_mode_flag = 0, pic_scaling_matrix_present_flag = 0, second_chroma_qp_index_offset = 3
+        let pps = Pps::parse(&nal(0x68, &format!("{} 0 0 00110", base)), &sps).unwrap();
+        assert_eq!(pps.chroma_qp_index_offset, -2);
+        assert_eq!(pps.second_chroma_qp_index_offset, 3);
+
+        // 参照先の SPS と seq_parameter_set_id が違う
+        let other_sps = "1 010 1 0 1 1 1 0 00 1 1 1 1 0 0";
+        assert_eq!(Pps::parse(&nal(0x68, other_sps), &sps), Err(H264Error::InvalidValue("seq_parameter_set_id", 1)));
+    }
+
+    #[test]
+    fn pps_parse_slice_groups() {
+        let (sps, _) = dahua();
+        // num_slice_groups_minus1 とマップの部分だけを差し替えた PPS
+        let pps = |slice_groups: &str| {
+            let bits = format!("1 1 0 0 {} 1 1 0 00 1 1 1 1 0 0", slice_groups);
+            Pps::parse(&nal(0x68, &bits), &sps)
+        };
+
+        // slice_group_map_type 6 の slice_group_id は Ceil(Log2(num_slice_groups_minus1 + 1)) ビット
+        let explicit = |ids: Vec<u32>| Ok(Some(SliceGroupMap::Explicit { slice_group_id: ids }));
+        assert_eq!(pps("010 00111 00100 0 1 1 0").map(|p| p.slice_group_map), explicit(vec![0, 1, 1, 0]));
+        assert_eq!(pps("011 00111 00100 00 01 10 10").map(|p| p.slice_group_map), explicit(vec![0, 1, 2, 2]));
+        assert_eq!(pps("00100 00111 00100 00 11 10 01").map(|p| p.slice_group_map), explicit(vec![0, 3, 2, 1]));
+        assert_eq!(pps("00101 00111 00100 000 011 100 001").map(|p| p.slice_group_map), explicit(vec![0, 3, 4, 1]));
+
+        assert_eq!(
+            pps("010 1 1 010").map(|p| p.slice_group_map),
+            Ok(Some(SliceGroupMap::Interleaved { run_length_minus1: vec![0, 1] }))
+        );
+        assert_eq!(
+            pps("010 011 1 00100").map(|p| p.slice_group_map),
+            Ok(Some(SliceGroupMap::Foreground { rectangles: vec![(0, 3)] }))
+        );
+        assert_eq!(
+            pps("011 00101 1 011").map(|p| p.slice_group_map),
+            Ok(Some(SliceGroupMap::Changing {
+                slice_group_map_type: 4,
+                slice_group_change_direction_flag: true,
+                slice_group_change_rate_minus1: 2,
+            }))
+        );
+        assert_eq!(pps("010 0001000").map(|p| p.slice_group_map), Err(H264Error::InvalidValue("slice_group_map_type", 7)));
+        // マップユニット数（80x45 マクロブロック）を超える pic_size_in_map_units_minus1
+        assert_eq!(
+            pps("010 00111 00000000000111000010001 0").map(|p| p.slice_group_map),
+            Err(H264Error::InvalidValue("pic_size_in_map_units_minus1", 3600))
+        );
+    }
+
+    #[test]
+    fn slice_header_parse_idr() {
+        let sps = Sps::parse(&hex(OPENH264_SPS)).unwrap();
+        let pps = Pps::parse(&hex(OPENH264_PPS), &sps).unwrap();
+        let first = parse_slice(&hex(OPENH264_IDR[0]), &sps, &pps).unwrap();
+        assert!(first.is_idr());
+        assert_eq!(first.nal_ref_idc, 3);
+        assert_eq!(first.slice_type, SliceType::I);
+        assert_eq!(first.first_mb_in_slice, 0);
+        assert_eq!(first.frame_num, 0);
+        assert_eq!(first.idr_pic_id, Some(0));
+        assert!(!first.memory_management_control_operation_5);
+
+        // 続く IDR は idr_pic_id だけが違い、別のピクチャになる
+        let second = parse_slice(&hex(OPENH264_IDR[1]), &sps, &pps).unwrap();
+        assert_eq!(second.idr_pic_id, Some(1));
+        assert!(second.starts_new_picture(&first));
+
+        // PPS が見つからなければ呼び出し側のエラーをそのまま返す
+        let missing = SliceHeader::parse(&hex(OPENH264_IDR[0]), |id| Err(H264Error::MissingParameterSet("PPS", id)));
+        assert_eq!(missing, Err(H264Error::MissingParameterSet("PPS", 0)));
+        assert_eq!(parse_slice(&hex(OPENH264_SPS), &sps, &pps), Err(H264Error::UnexpectedNalType(7)));
+    }
+
+    /// 参照 B スライス（nal_ref_idc = 2）。`weights` は pred_weight_table の部分
+    fn b_slice(weights: &str) -> Vec<u8> {
+        let bits = format!(
+            "1 00111 1 00000010 0000000000000100 \
+             1 1 010 1 \
+             1 1 1 00100 \
+             1 010 011 00100 \
+             {} \
+             1 010 1 00110 1",
+            weights
+        );
+        nal(0x41, &bits)
+    }
+
+    #[test]
+    fn slice_header_parse_b_slice() {
+        // first_mb_in_slice, slice_type = 6 (B), pic_parameter_set_id, frame_num = 2 (8 ビット), pic_order_cnt_lsb = 4 (16 ビット)
+        // direct_spatial_mv_pred_flag, num_ref_idx_active_override_flag（L0 は 2 枚、L1 は 1 枚）
+        // ref_pic_list_modification: L0 は idc 0、L1 は idc 1、どちらも 3 で終わる
+        // dec_ref_pic_marking: mmco 1、mmco 5、0 で終わる
+        let (sps, mut pps) = dahua();
+        let header = parse_slice(&b_slice(""), &sps, &pps).unwrap();
+        assert_eq!(header.nal_ref_idc, 2);
+        assert_eq!(header.slice_type, SliceType::B);
+        assert_eq!(header.frame_num, 2);
+        assert_eq!(header.pic_order_cnt_lsb, 4);
+        assert_eq!(header.idr_pic_id, None);
+        assert!(header.memory_management_control_operation_5);
+
+        // weighted_bipred_idc = 1 なら pred_weight_table を読み飛ばしてから dec_ref_pic_marking を読む
+        // luma_log2_weight_denom, chroma_log2_weight_denom
+        // L0[0]: luma 1, -1 と chroma 4 つ、L0[1]: なし、L1[0]: chroma 1, -1, 0, 0
+        pps.weighted_bipred_idc = 1;
+        let weights = "00111 00110  1 010 011 1 1 1 1 1  0 0  0 1 010 011 1 1";
+        assert_eq!(parse_slice(&b_slice(weights), &sps, &pps), Ok(header.clone()));
+        // weighted_bipred_idc = 2（暗黙の重み）なら pred_weight_table はない
+        pps.weighted_bipred_idc = 2;
+        assert_eq!(parse_slice(&b_slice(""), &sps, &pps), Ok(header));
+
+        // 途中で切れていればエラーにする（パニックしない）
+        pps.weighted_bipred_idc = 1;
+        let full = b_slice(weights);
+        for len in 1..full.len() - 1 {
+            assert!(parse_slice(&full[..len], &sps, &pps).is_err(), "len {}", len);
         }
+    }
+
+    #[test]
+    fn slice_header_parse_p_slice() {
+        // 2 つ目のスライス（first_mb_in_slice = 120）、slice_type = 5 (P)、frame_num = 3、pic_order_cnt_lsb = 6
+        // num_ref_idx_active_override_flag = 0、ref_pic_list_modification_flag_l0 = 0
+        let head = "0000001111001 00110 1 00000011 0000000000000110 0 0";
+        // dec_ref_pic_marking: mmco 3, 6, 2, 4 のあと 0
+        let marking = "1 00100 1 010 00111 1 011 1 00101 1 1";
+        let (sps, mut pps) = hikvision();
+        let header = parse_slice(&nal(0x41, &format!("{} {}", head, marking)), &sps, &pps).unwrap();
+        assert_eq!(header.first_mb_in_slice, 120);
+        assert_eq!(header.slice_type, SliceType::P);
+        assert_eq!(header.frame_num, 3);
+        assert_eq!(header.pic_order_cnt_lsb, 6);
+        assert!(!header.memory_management_control_operation_5);
 
-        let (crop_unit_x, crop_unit_y) = match chroma_format_idc {
-            0 => (1, 2 - frame_mbs_only_flag),
-            1 => (2, 2 * (2 - frame_mbs_only_flag)),
-            2 => (2, 2 - frame_mbs_only_flag),
-            3 => (1, 2 - frame_mbs_only_flag),
-            _ => (1, 1),
+        // weighted_pred_flag: luma / chroma の denom、L0[0] は luma 0, 0 のみ
+        pps.weighted_pred_flag = true;
+        let weighted = nal(0x41, &format!("{} 1 1 1 1 1 0 {}", head, marking));
+        assert_eq!(parse_slice(&weighted, &sps, &pps), Ok(header));
+
+        // 範囲外の memory_management_control_operation
+        let invalid = nal(0x41, &format!("{} 1 1 1 1 1 0 1 0001000", head));
+        assert_eq!(
+            parse_slice(&invalid, &sps, &pps),
+            Err(H264Error::InvalidValue("memory_management_control_operation", 7))
+        );
+    }
+
+    #[test]
+    fn starts_new_picture_rules() {
+        let prev = frame(false, 2, 3, 6);
+
+        // 同じピクチャの後続スライス
+        let mut next = prev.clone();
+        next.first_mb_in_slice = 120;
+        next.slice_type = SliceType::I;
+        assert!(!next.starts_new_picture(&prev));
+        // nal_ref_idc は 0 かどうかだけを比べる
+        next.nal_ref_idc = 3;
+        assert!(!next.starts_new_picture(&prev));
+
+        let changed = |f: &dyn Fn(&mut SliceHeader)| {
+            let mut next = prev.clone();
+            f(&mut next);
+            next.starts_new_picture(&prev)
         };
+        assert!(changed(&|h| h.frame_num = 4));
+        assert!(changed(&|h| h.pic_parameter_set_id = 1));
+        assert!(changed(&|h| h.field_pic_flag = true));
+        assert!(changed(&|h| h.bottom_field_flag = true));
+        assert!(changed(&|h| h.nal_ref_idc = 0));
+        assert!(changed(&|h| h.pic_order_cnt_lsb = 8));
+        assert!(changed(&|h| h.delta_pic_order_cnt_bottom = -1));
+        assert!(changed(&|h| h.delta_pic_order_cnt = [0, 1]));
+        assert!(changed(&|h| {
+            h.nal_unit_type = 5;
+            h.idr_pic_id = Some(0);
+        }));
+        // 冗長ピクチャのスライスは新しいプライマリピクチャを始めない
+        assert!(!changed(&|h| {
+            h.frame_num = 4;
+            h.redundant_pic_cnt = 1;
+        }));
+
+        // 連続する IDR は idr_pic_id で区別する
+        let idr = frame(true, 3, 0, 0);
+        let mut same = idr.clone();
+        same.first_mb_in_slice = 1;
+        assert!(!same.starts_new_picture(&idr));
+        same.idr_pic_id = Some(1);
+        assert!(same.starts_new_picture(&idr));
+    }
 
-        width -= (crop_left + crop_right) * crop_unit_x;
-        height -= (crop_top + crop_bottom) * crop_unit_y;
+    #[test]
+    fn sps_parse_camera_sprop() {
+        // (SPS, profile_idc, level_idc, 幅, 高さ, log2_max_pic_order_cnt_lsb_minus4, MaxFrameNum)
+        let cameras = [
+            (HIKVISION_SPS, 77, 42, 1920, 1080, 12, 256),
+            (DAHUA_SPS, 100, 31, 1280, 720, 12, 256),
+            (AXIS_SPS, 77, 41, 1920, 1080, 9, 16),
+        ];
+        for (sprop, profile_idc, level_idc, width, height, lsb_minus4, max_frame_num) in cameras {
+            let sps = Sps::parse(&decode(sprop)).unwrap();
+            assert_eq!(sps.profile_idc, profile_idc, "{}", sprop);
+            assert_eq!(sps.level_idc, level_idc, "{}", sprop);
+            assert_eq!(sps.seq_parameter_set_id, 0, "{}", sprop);
+            assert_eq!(sps.chroma_format_idc, 1, "{}", sprop);
+            assert_eq!(sps.bit_depth_luma(), 8, "{}", sprop);
+            assert_eq!(sps.width(), Some(width), "{}", sprop);
+            assert_eq!(sps.height(), Some(height), "{}", sprop);
+            assert_eq!(sps.pic_order_cnt, PicOrderCnt::Type0 { log2_max_pic_order_cnt_lsb_minus4: lsb_minus4 }, "{}", sprop);
+            assert_eq!(sps.max_frame_num(), max_frame_num, "{}", sprop);
+            assert!(sps.frame_mbs_only_flag, "{}", sprop);
+        }
 
-        println!("===> RESOLUTION: {} x {}", width, height);
-        println!("===== SPS Decode End =====");
+        // 1080p は 1088 ラインを符号化して下端 8 ライン（4:2:0 で frame_crop_bottom_offset = 4）を切り取る
+        let sps = Sps::parse(&decode(AXIS_SPS)).unwrap();
+        assert_eq!(sps.pic_height_in_map_units_minus1, 67);
+        assert_eq!(sps.frame_cropping, Some(FrameCropping { left: 0, right: 0, top: 0, bottom: 4 }));
+        assert_eq!(sps.profile_name(), "Main");
+        assert_eq!(Sps::parse(&decode(DAHUA_SPS)).unwrap().profile_name(), "High");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn sps_parse_truncated() {
+        // 途中で切れた SPS は Truncated を返す（パニックしない）。
+        // rbsp_trailing_bits は見ないので、VUI の最後まで残っていれば元の SPS と同じ結果になる
+        // （Hikvision の SPS は stop bit の後ろに余分なバイトが付いている）
+        for sprop in [HIKVISION_SPS, DAHUA_SPS, AXIS_SPS] {
+            let nal = decode(sprop);
+            let full = Sps::parse(&nal).unwrap();
+            for len in 0..nal.len() {
+                match Sps::parse(&nal[..len]) {
+                    Ok(sps) => assert_eq!(sps, full, "{} len {}", sprop, len),
+                    Err(e) => assert_eq!(e, H264Error::Truncated, "{} len {}", sprop, len),
+                }
+            }
+            // VUI の途中で切れている
+            assert_eq!(Sps::parse(&nal[..12]), Err(H264Error::Truncated), "{}", sprop);
+        }
+        assert_eq!(Sps::parse(&decode(HIKVISION_PPS)), Err(H264Error::UnexpectedNalType(8)));
+    }
+
+    /// aspect_ratio_idc = 255 (4:3)、colour_description あり、timing_info 1/50 の VUI
+    const VUI: &str = "1 11111111 0000000000000100 0000000000000011 \
+                       0 \
+                       1 101 0 1 00001001 00010000 00001001 \
+                       0 \
+                       1 00000000000000000000000000000001 00000000000000000000000000110010 1 \
+                       0 0 0 0";
+
+    fn vui_parameters() -> VuiParameters {
+        let rbsp = nal(0, VUI);
+        VuiParameters::parse(&mut BitReader::new(&rbsp[1..])).unwrap()
+    }
+
+    #[test]
+    fn vui_parse_timing_sar_colour() {
+        let vui = vui_parameters();
+        assert_eq!(vui.aspect_ratio_info, Some(AspectRatioInfo { aspect_ratio_idc: 255, sar_width: 4, sar_height: 3 }));
+        assert_eq!(vui.sample_aspect_ratio(), Some((4, 3)));
+        assert_eq!(vui.overscan_appropriate, None);
+        assert_eq!(
+            vui.video_signal_type,
+            Some(VideoSignalType {
+                video_format: 5,
+                video_full_range_flag: false,
+                colour_description: Some(ColourDescription { colour_primaries: 9, transfer_characteristics: 16, matrix_coefficients: 9 }),
+            })
+        );
+        assert_eq!(vui.timing_info, Some(TimingInfo { num_units_in_tick: 1, time_scale: 50, fixed_frame_rate_flag: true }));
+        // 1フレームは2クロックティックなので 25fps、90kHz で 3600
+        assert_eq!(vui.frame_rate(), Some(25.0));
+        assert_eq!(vui.frame_duration(90000), Some(3600));
+        assert_eq!(vui.bitstream_restriction, None);
+
+        // 表 E-1 の値と、Extended_SAR の 0
+        let mut table = vui.clone();
+        table.aspect_ratio_info = Some(AspectRatioInfo { aspect_ratio_idc: 14, sar_width: 0, sar_height: 0 });
+        assert_eq!(table.sample_aspect_ratio(), Some((4, 3)));
+        table.aspect_ratio_info = Some(AspectRatioInfo { aspect_ratio_idc: 255, sar_width: 0, sar_height: 1 });
+        assert_eq!(table.sample_aspect_ratio(), None);
+        table.timing_info = None;
+        assert_eq!(table.frame_duration(90000), None);
+
+        // timing_info の途中で切れている
+        let rbsp = nal(0, VUI);
+        assert_eq!(VuiParameters::parse(&mut BitReader::new(&rbsp[1..12])), Err(H264Error::Truncated));
+    }
+}
@@ -0,0 +1,625 @@
+use crate::nal::NalEvent;
+use crate::rtp::{self, RTPHeader};
+
+/// NAL ユニット1つを NalEvent に変換する（payload は NAL ヘッダから始まる）
+fn parse_single_nalu(payload: &[u8], rtp_ts: u32) -> Option<NalEvent<'_>> {
+    if payload.is_empty() {
+        return None;
+    }
+
+    match payload[0] & 0x1F {
+        rtp::NAL_UNIT_TYPE_NON_IDR => Some(NalEvent::Video {
+            data: payload,
+            ts: rtp_ts,
+            is_key: false,
+        }),
+
+        rtp::NAL_UNIT_TYPE_IDR => Some(NalEvent::Video {
+            data: payload,
+            ts: rtp_ts,
+            is_key: true,
+        }),
+
+        rtp::NAL_UNIT_TYPE_SEI => Some(NalEvent::Sei {
+            data: payload,
+            ts: rtp_ts,
+        }),
+
+        rtp::NAL_UNIT_TYPE_AUD => Some(NalEvent::Aud {
+            data: payload,
+            ts: rtp_ts,
+        }),
+
+        rtp::NAL_UNIT_TYPE_SPS => Some(NalEvent::Sps(payload)),
+
+        rtp::NAL_UNIT_TYPE_PPS => Some(NalEvent::Pps(payload)),
+
+        rtp::NAL_UNIT_TYPE_END_OF_STREAM => Some(NalEvent::End),
+
+        rtp::NAL_UNIT_TYPE_END_OF_SEQUENCE
+        | rtp::NAL_UNIT_TYPE_FILLER_DATA
+        | rtp::NAL_UNIT_TYPE_SPS_EXT => None,
+
+        // データ分割は未対応
+        rtp::NAL_UNIT_TYPE_PARTITION_A
+        | rtp::NAL_UNIT_TYPE_PARTITION_B
+        | rtp::NAL_UNIT_TYPE_PARTITION_C => None,
+
+        _ => None,
+    }
+}
+
+/// 2バイトのサイズ＋NAL ユニットの並び（STAP-A / STAP-B の本体）を分解する
+fn parse_aggregation_units(mut data: &[u8]) -> Vec<&[u8]> {
+    let mut nalus = Vec::new();
+    while data.len() >= 2 {
+        let size = u16::from_be_bytes([data[0], data[1]]) as usize;
+        data = &data[2..];
+        if size > data.len() {
+            eprintln!("Invalid aggregation packet: NAL size exceeds payload");
+            break;
+        }
+        if size > 0 {
+            nalus.push(&data[..size]);
+        }
+        data = &data[size..];
+    }
+    nalus
+}
+
+/// MTAP16 / MTAP24 の本体を分解する。
+/// 各ユニットは サイズ(2) + DOND(1) + TS オフセット(2 or 3) + NAL ユニット。
+/// 戻り値は (NAL ユニット, DOND, RTP タイムスタンプからのオフセット)。
+fn parse_mtap_units(mut data: &[u8], ts_offset_len: usize) -> Vec<(&[u8], u8, u32)> {
+    let mut nalus = Vec::new();
+    while data.len() >= 2 {
+        let size = u16::from_be_bytes([data[0], data[1]]) as usize;
+        data = &data[2..];
+        // サイズには DOND と TS オフセットも含まれる
+        let header_len = 1 + ts_offset_len;
+        if size > data.len() || size < header_len {
+            eprintln!("Invalid MTAP: NAL size exceeds payload");
+            break;
+        }
+        let ts_offset = data[1..header_len]
+            .iter()
+            .fold(0u32, |acc, &b| (acc << 8) | b as u32);
+        if size > header_len {
+            nalus.push((&data[header_len..size], data[0], ts_offset));
+        }
+        data = &data[size..];
+    }
+    nalus
+}
+
+/// RTP ペイロードから取り出した NAL ユニット
+struct NalUnit<'a> {
+    data: &'a [u8],
+    ts: u32,
+    /// 復号順序番号（DON）。インターリーブモードのパケット以外は None
+    don: Option<u16>,
+}
+
+/// FU-A / FU-B の組み立てバッファ
+struct FragmentAssembler {
+    /// 組み立て中の NAL ユニット（NAL ヘッダから、スタートコードなし）
+    buf: Vec<u8>,
+    /// 開始フラグメントのタイムスタンプ
+    ts: u32,
+    /// 開始フラグメント（FU-B）の DON
+    don: Option<u16>,
+    /// 開始フラグメントを受け取り、欠落なく組み立て中か
+    active: bool,
+}
+
+impl FragmentAssembler {
+    /// フラグメントを追加し、最後のフラグメントなら NAL ユニットを返す。
+    fn push<'a>(&'a mut self, payload: &[u8], rtp_ts: u32) -> Option<NalUnit<'a>> {
+        if payload.len() < 2 {
+            return None;
+        }
+        let is_fu_b = payload[0] & 0x1F == rtp::NAL_UNIT_TYPE_FU_B;
+        let fu_header = payload[1];
+        let start_bit = (fu_header >> 7) & 0x01;
+        let end_bit = (fu_header >> 6) & 0x01;
+        let nal_header = (payload[0] & 0xE0) | (fu_header & 0x1F);
+
+        let data = if start_bit == 1 {
+            // FU-B は開始フラグメントにだけ DON(2) を持つ
+            let offset = if is_fu_b { 4 } else { 2 };
+            if payload.len() < offset {
+                return None;
+            }
+            self.buf.clear();
+            self.buf.push(nal_header);
+            self.ts = rtp_ts;
+            self.don = if is_fu_b { Some(u16::from_be_bytes([payload[2], payload[3]])) } else { None };
+            self.active = true;
+            &payload[offset..]
+        } else if self.active {
+            &payload[2..]
+        } else {
+            // 開始フラグメントを受け取っていない（欠落済み）
+            return None;
+        };
+        self.buf.extend_from_slice(data);
+
+        if end_bit == 1 {
+            self.active = false;
+            return Some(NalUnit { data: &self.buf, ts: self.ts, don: self.don });
+        }
+        None
+    }
+}
+
+/// packetization-mode=2 で送られてくる NAL ユニットを DON 順に並べ直す。
+///
+/// sprop-interleaving-depth 個の NAL ユニットを溜めておき、それを超えたら
+/// DON が最も小さいものから送り出す。
+struct Deinterleaver {
+    depth: usize,
+    /// 並べ替え待ちの (DON, タイムスタンプ, NAL ユニット)
+    units: Vec<(u16, u32, Vec<u8>)>,
+    /// 最後に受け取った DON（DON を持たないパケットに続き番号を振るため）
+    last_don: Option<u16>,
+    /// 直前の push で送り出した (タイムスタンプ, NAL ユニット)
+    ready: Vec<(u32, Vec<u8>)>,
+}
+
+impl Deinterleaver {
+    fn new(depth: usize) -> Self {
+        Self {
+            depth,
+            units: Vec::new(),
+            last_don: None,
+            ready: Vec::new(),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.units.clear();
+        self.last_don = None;
+        self.ready.clear();
+    }
+
+    fn push(&mut self, unit: NalUnit) {
+        let don = unit.don
+            .or_else(|| self.last_don.map(|d| d.wrapping_add(1)))
+            .unwrap_or(0);
+        self.last_don = Some(don);
+        self.units.push((don, unit.ts, unit.data.to_vec()));
+    }
+
+    /// 溜まりすぎた分を DON の小さい順に ready に移す。
+    fn release(&mut self) {
+        self.release_until(self.depth);
+    }
+
+    /// 溜まっている NAL ユニットをすべて DON の小さい順に ready に移す。
+    fn flush(&mut self) {
+        self.ready.clear();
+        self.release_until(0);
+        self.last_don = None;
+    }
+
+    /// 溜まっている数が `keep` 個になるまで DON の小さい順に ready に移す。
+    fn release_until(&mut self, keep: usize) {
+        while self.units.len() > keep {
+            // DON は 16bit で折り返すので、先頭からの差分（RFC 6184 の don_diff）で比べる
+            let base = self.units[0].0;
+            let (index, _) = self.units.iter()
+                .enumerate()
+                .min_by_key(|(_, u)| u.0.wrapping_sub(base) as i16)
+                .unwrap();
+            let (_, ts, data) = self.units.remove(index);
+            self.ready.push((ts, data));
+        }
+    }
+}
+
+/// RFC 6184 の RTP ペイロードから NAL ユニットを取り出す。
+///
+/// Single NAL unit / STAP-A / STAP-B / MTAP16 / MTAP24 / FU-A / FU-B に対応する。
+/// sequence_number の欠落を検出すると組み立て中のフラグメントを捨て、
+/// `NalEvent::Loss` を通知する。
+/// マーカービットの立ったパケットの後には `NalEvent::AccessUnitEnd` を通知する。
+/// packetization-mode=2 の場合は `set_interleaving_depth` で DON による並べ替えを有効にする。
+pub struct H264Depacketizer {
+    fragment: FragmentAssembler,
+    /// インターリーブモードでなければ None
+    deinterleaver: Option<Deinterleaver>,
+    /// 直前に受け取った RTP の sequence_number
+    last_seq: Option<u16>,
+}
+
+impl H264Depacketizer {
+    pub fn new() -> Self {
+        Self {
+            fragment: FragmentAssembler {
+                buf: Vec::new(),
+                ts: 0,
+                don: None,
+                active: false,
+            },
+            deinterleaver: None,
+            last_seq: None,
+        }
+    }
+
+    /// インターリーブモード（packetization-mode=2）の並べ替えを設定する。
+    /// `depth` は SDP の sprop-interleaving-depth。None なら受信順のまま送り出す。
+    pub fn set_interleaving_depth(&mut self, depth: Option<usize>) {
+        self.deinterleaver = depth.map(Deinterleaver::new);
+    }
+
+    /// 状態を初期化する。再接続などで sequence_number が連続しなくなる場合に呼ぶ。
+    pub fn reset(&mut self) {
+        self.fragment.buf.clear();
+        self.fragment.active = false;
+        self.last_seq = None;
+        if let Some(ref mut d) = self.deinterleaver {
+            d.reset();
+        }
+    }
+
+    /// 並べ替え待ちの NAL ユニットをすべて DON 順に送り出す。
+    /// 録画の終了時や、再接続で reset() する前に呼んで取りこぼしを防ぐ。
+    pub fn flush(&mut self) -> Vec<NalEvent<'_>> {
+        let mut events = Vec::new();
+        if let Some(ref mut d) = self.deinterleaver {
+            d.flush();
+            for (ts, data) in &d.ready {
+                events.extend(parse_single_nalu(data, *ts));
+            }
+        }
+        events
+    }
+
+    /// RTP パケットを1つ処理し、取り出せた NAL ユニットのイベントを返す。
+    pub fn push<'a>(&'a mut self, header: &RTPHeader, payload: &'a [u8]) -> Vec<NalEvent<'a>> {
+        let mut events = Vec::new();
+
+        // sequence_number が飛んでいれば組み立て中の NAL は壊れているので捨てる
+        let seq = header.sequence_number;
+        if matches!(self.last_seq, Some(prev) if seq != prev.wrapping_add(1)) {
+            if self.fragment.active {
+                eprintln!("FU fragment lost (seq {}), dropping incomplete NAL", seq);
+                self.fragment.active = false;
+            }
+            events.push(NalEvent::Loss);
+        }
+        self.last_seq = Some(seq);
+
+        // インターリーブモードではパケット順と復号順が異なるので、
+        // マーカービットではなくタイムスタンプの変化でフレームを区切る
+        let marker = header.marker == 1 && self.deinterleaver.is_none();
+
+        let units = split_payload(&mut self.fragment, payload, header.timestamp);
+        match self.deinterleaver {
+            None => {
+                for unit in units {
+                    events.extend(parse_single_nalu(unit.data, unit.ts));
+                }
+            }
+            Some(ref mut d) => {
+                d.ready.clear();
+                for unit in units {
+                    d.push(unit);
+                }
+                d.release();
+                for (ts, data) in &d.ready {
+                    events.extend(parse_single_nalu(data, *ts));
+                }
+            }
+        }
+
+        if marker {
+            events.push(NalEvent::AccessUnitEnd);
+        }
+        events
+    }
+}
+
+/// RTP ペイロードを NAL ユニットに分解する。
+fn split_payload<'a>(fragment: &'a mut FragmentAssembler, payload: &'a [u8], rtp_ts: u32) -> Vec<NalUnit<'a>> {
+    let mut units = Vec::new();
+    if payload.is_empty() {
+        return units;
+    }
+
+    match payload[0] & 0x1F {
+        rtp::NAL_UNIT_TYPE_UNSPECIFIED => {
+            println!("Received NAL unit with unspecified type, skipping");
+        }
+        rtp::NAL_UNIT_TYPE_STAP_A => {
+            for data in parse_aggregation_units(&payload[1..]) {
+                units.push(NalUnit { data, ts: rtp_ts, don: None });
+            }
+        }
+        rtp::NAL_UNIT_TYPE_STAP_B => {
+            // STAP-B ヘッダの後に DON(2) が続き、以降の NAL ユニットは DON+1, DON+2, ...
+            if payload.len() < 3 {
+                return units;
+            }
+            let don = u16::from_be_bytes([payload[1], payload[2]]);
+            for (i, data) in parse_aggregation_units(&payload[3..]).into_iter().enumerate() {
+                units.push(NalUnit { data, ts: rtp_ts, don: Some(don.wrapping_add(i as u16)) });
+            }
+        }
+        rtp::NAL_UNIT_TYPE_MTAP16 | rtp::NAL_UNIT_TYPE_MTAP24 => {
+            // MTAP ヘッダの後に DONB(2) が続き、各 NAL ユニットの DON は DONB + DOND
+            if payload.len() < 3 {
+                return units;
+            }
+            let donb = u16::from_be_bytes([payload[1], payload[2]]);
+            let ts_offset_len = if payload[0] & 0x1F == rtp::NAL_UNIT_TYPE_MTAP16 { 2 } else { 3 };
+            for (data, dond, ts_offset) in parse_mtap_units(&payload[3..], ts_offset_len) {
+                units.push(NalUnit {
+                    data,
+                    ts: rtp_ts.wrapping_add(ts_offset),
+                    don: Some(donb.wrapping_add(dond as u16)),
+                });
+            }
+        }
+        rtp::NAL_UNIT_TYPE_FU_A | rtp::NAL_UNIT_TYPE_FU_B => {
+            units.extend(fragment.push(payload, rtp_ts));
+        }
+        _ => {
+            units.push(NalUnit { data: payload, ts: rtp_ts, don: None });
+        }
+    }
+    units
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 比較しやすいように NalEvent を所有データに写したもの
+    #[derive(Debug, PartialEq)]
+    enum Ev {
+        Video(Vec<u8>, u32, bool),
+        Sps(Vec<u8>),
+        Pps(Vec<u8>),
+        Sei,
+        Aud,
+        End,
+        AccessUnitEnd,
+        Loss,
+    }
+
+    fn owned(events: Vec<NalEvent>) -> Vec<Ev> {
+        events.into_iter()
+            .map(|ev| match ev {
+                NalEvent::Video { data, ts, is_key } => Ev::Video(data.to_vec(), ts, is_key),
+                NalEvent::Sps(data) => Ev::Sps(data.to_vec()),
+                NalEvent::Pps(data) => Ev::Pps(data.to_vec()),
+                NalEvent::Sei { .. } => Ev::Sei,
+                NalEvent::Aud { .. } => Ev::Aud,
+                NalEvent::End => Ev::End,
+                NalEvent::AccessUnitEnd => Ev::AccessUnitEnd,
+                NalEvent::Loss => Ev::Loss,
+            })
+            .collect()
+    }
+
+    fn header(seq: u16, ts: u32, marker: bool) -> RTPHeader {
+        RTPHeader {
+            marker: marker as u8,
+            payload_type: 96,
+            sequence_number: seq,
+            timestamp: ts,
+            ssrc: 0x1234_5678,
+            csrcs: Vec::new(),
+            extension_header: None,
+        }
+    }
+
+    fn push(d: &mut H264Depacketizer, seq: u16, ts: u32, marker: bool, payload: &[u8]) -> Vec<Ev> {
+        owned(d.push(&header(seq, ts, marker), payload))
+    }
+
+    // NRI=3 の各パケットの先頭バイト
+    const STAP_A: u8 = 0x60 | rtp::NAL_UNIT_TYPE_STAP_A;
+    const STAP_B: u8 = 0x60 | rtp::NAL_UNIT_TYPE_STAP_B;
+    const MTAP16: u8 = 0x60 | rtp::NAL_UNIT_TYPE_MTAP16;
+    const MTAP24: u8 = 0x60 | rtp::NAL_UNIT_TYPE_MTAP24;
+    const FU_A: u8 = 0x60 | rtp::NAL_UNIT_TYPE_FU_A;
+    const FU_B: u8 = 0x60 | rtp::NAL_UNIT_TYPE_FU_B;
+
+    #[test]
+    fn single_nal_units() {
+        let mut d = H264Depacketizer::new();
+        assert_eq!(push(&mut d, 1, 3000, false, &[0x09, 0xF0]), vec![Ev::Aud]);
+        assert_eq!(push(&mut d, 2, 3000, false, &[0x06, 0x05, 0x01]), vec![Ev::Sei]);
+        assert_eq!(
+            push(&mut d, 3, 3000, true, &[0x65, 0x88, 0x84]),
+            vec![Ev::Video(vec![0x65, 0x88, 0x84], 3000, true), Ev::AccessUnitEnd],
+        );
+        assert_eq!(push(&mut d, 4, 6000, true, &[0x41, 0x9A]), vec![Ev::Video(vec![0x41, 0x9A], 6000, false), Ev::AccessUnitEnd]);
+        assert_eq!(push(&mut d, 5, 6000, false, &[0x0B]), vec![Ev::End]);
+        // フィラーデータなどは捨てる
+        assert_eq!(push(&mut d, 6, 6000, false, &[0x0C, 0xFF]), vec![]);
+        assert_eq!(push(&mut d, 7, 6000, false, &[]), vec![]);
+    }
+
+    #[test]
+    fn stap_a() {
+        let mut d = H264Depacketizer::new();
+        let payload = [STAP_A, 0x00, 0x03, 0x67, 0x42, 0x00, 0x00, 0x02, 0x68, 0xCE, 0x00, 0x02, 0x65, 0x88];
+        assert_eq!(
+            push(&mut d, 100, 9000, true, &payload),
+            vec![
+                Ev::Sps(vec![0x67, 0x42, 0x00]),
+                Ev::Pps(vec![0x68, 0xCE]),
+                Ev::Video(vec![0x65, 0x88], 9000, true),
+                Ev::AccessUnitEnd,
+            ],
+        );
+    }
+
+    #[test]
+    fn stap_a_truncated_length() {
+        let mut d = H264Depacketizer::new();
+        // 2つ目のサイズがペイロードを超えているので、そこで打ち切る
+        let payload = [STAP_A, 0x00, 0x02, 0x67, 0x42, 0x00, 0x09, 0x68, 0xCE];
+        assert_eq!(push(&mut d, 1, 0, false, &payload), vec![Ev::Sps(vec![0x67, 0x42])]);
+        // サイズの途中で終わっている
+        let payload = [STAP_A, 0x00, 0x02, 0x67, 0x42, 0x00];
+        assert_eq!(push(&mut d, 2, 0, false, &payload), vec![Ev::Sps(vec![0x67, 0x42])]);
+    }
+
+    #[test]
+    fn stap_b_orders_by_don() {
+        let mut d = H264Depacketizer::new();
+        d.set_interleaving_depth(Some(1));
+        // DON=7, 8 の2つの NAL ユニット。深さ 1 なので1つ目だけが送り出される
+        let payload = [STAP_B, 0x00, 0x07, 0x00, 0x02, 0x65, 0x01, 0x00, 0x02, 0x41, 0x02];
+        assert_eq!(push(&mut d, 1, 3000, true, &payload), vec![Ev::Video(vec![0x65, 0x01], 3000, true)]);
+        // DON=6 の STAP-B が遅れて届く。DON=6 は DON=8 より先に出る
+        let payload = [STAP_B, 0x00, 0x06, 0x00, 0x02, 0x41, 0x03];
+        assert_eq!(push(&mut d, 2, 0, true, &payload), vec![Ev::Video(vec![0x41, 0x03], 0, false)]);
+        // 切り詰められた STAP-B（DON の途中で終わる）は何も出さない
+        assert_eq!(push(&mut d, 3, 0, false, &[STAP_B, 0x00]), vec![]);
+    }
+
+    #[test]
+    fn mtap16_don_and_timestamp_offset() {
+        let mut d = H264Depacketizer::new();
+        d.set_interleaving_depth(Some(0));
+        // DONB=10。DOND=1（オフセット 0）と DOND=0（オフセット 3000）
+        let payload = [
+            MTAP16, 0x00, 0x0A,
+            0x00, 0x05, 0x01, 0x00, 0x00, 0x41, 0xAA,
+            0x00, 0x05, 0x00, 0x0B, 0xB8, 0x65, 0xBB,
+        ];
+        // 深さ 0 ならパケット内で DON 順に並べ替えて全部送り出す
+        assert_eq!(
+            push(&mut d, 1, 90000, true, &payload),
+            vec![Ev::Video(vec![0x65, 0xBB], 93000, true), Ev::Video(vec![0x41, 0xAA], 90000, false)],
+        );
+    }
+
+    #[test]
+    fn mtap24_don_and_timestamp_offset() {
+        let mut d = H264Depacketizer::new();
+        d.set_interleaving_depth(Some(0));
+        // DONB=0xFFFF。DOND=1 は DON=0 に折り返す。オフセットは 24bit
+        let payload = [
+            MTAP24, 0xFF, 0xFF,
+            0x00, 0x06, 0x01, 0x01, 0x00, 0x00, 0x41, 0xAA,
+            0x00, 0x06, 0x00, 0x00, 0x00, 0x10, 0x65, 0xBB,
+        ];
+        assert_eq!(
+            push(&mut d, 1, 0xFFFF_FFF0, false, &payload),
+            vec![Ev::Video(vec![0x65, 0xBB], 0, true), Ev::Video(vec![0x41, 0xAA], 0xFFF0, false)],
+        );
+    }
+
+    #[test]
+    fn flush_releases_in_don_order() {
+        let mut d = H264Depacketizer::new();
+        d.set_interleaving_depth(Some(4));
+        // DON=3, 1, 2 の順に届き、深さ 4 なのでまだ何も出ない
+        let payload = [STAP_B, 0x00, 0x03, 0x00, 0x02, 0x41, 0x03];
+        assert_eq!(push(&mut d, 1, 9000, false, &payload), vec![]);
+        let payload = [STAP_B, 0x00, 0x01, 0x00, 0x02, 0x65, 0x01, 0x00, 0x02, 0x41, 0x02];
+        assert_eq!(push(&mut d, 2, 3000, false, &payload), vec![]);
+
+        assert_eq!(
+            owned(d.flush()),
+            vec![
+                Ev::Video(vec![0x65, 0x01], 3000, true),
+                Ev::Video(vec![0x41, 0x02], 3000, false),
+                Ev::Video(vec![0x41, 0x03], 9000, false),
+            ],
+        );
+        assert_eq!(owned(d.flush()), vec![]);
+
+        // インターリーブモードでなければ何も溜めていない
+        let mut d = H264Depacketizer::new();
+        assert_eq!(push(&mut d, 1, 0, false, &[0x65, 0x01]), vec![Ev::Video(vec![0x65, 0x01], 0, true)]);
+        assert_eq!(owned(d.flush()), vec![]);
+    }
+
+    #[test]
+    fn mtap_truncated_length() {
+        let mut d = H264Depacketizer::new();
+        d.set_interleaving_depth(Some(0));
+        // サイズが DOND + TS オフセットより小さい
+        let payload = [MTAP16, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00];
+        assert_eq!(push(&mut d, 1, 0, false, &payload), vec![]);
+        // 1つ目は正常、2つ目はサイズがペイロードを超える
+        let payload = [
+            MTAP24, 0x00, 0x00,
+            0x00, 0x06, 0x00, 0x00, 0x00, 0x00, 0x41, 0xAA,
+            0x00, 0x20, 0x01, 0x00,
+        ];
+        assert_eq!(push(&mut d, 2, 0, false, &payload), vec![Ev::Video(vec![0x41, 0xAA], 0, false)]);
+    }
+
+    #[test]
+    fn fu_a() {
+        let mut d = H264Depacketizer::new();
+        assert_eq!(push(&mut d, 10, 3000, false, &[FU_A, 0x80 | 0x05, 0x88, 0x84]), vec![]);
+        assert_eq!(push(&mut d, 11, 3000, false, &[FU_A, 0x05, 0x01, 0x02]), vec![]);
+        // NAL ヘッダは FU indicator の F/NRI と FU header の type から復元する
+        assert_eq!(
+            push(&mut d, 12, 3000, true, &[FU_A, 0x40 | 0x05, 0x03]),
+            vec![Ev::Video(vec![0x65, 0x88, 0x84, 0x01, 0x02, 0x03], 3000, true), Ev::AccessUnitEnd],
+        );
+    }
+
+    #[test]
+    fn fu_b() {
+        let mut d = H264Depacketizer::new();
+        d.set_interleaving_depth(Some(0));
+        // 開始フラグメントだけが FU-B（DON 付き）、続きは FU-A
+        assert_eq!(push(&mut d, 1, 3000, false, &[FU_B, 0x80 | 0x01, 0x00, 0x2A, 0x9A, 0x01]), vec![]);
+        assert_eq!(
+            push(&mut d, 2, 3000, false, &[FU_A, 0x40 | 0x01, 0x02]),
+            vec![Ev::Video(vec![0x61, 0x9A, 0x01, 0x02], 3000, false)],
+        );
+        // DON の途中で終わる開始フラグメントは捨てる
+        assert_eq!(push(&mut d, 3, 6000, false, &[FU_B, 0x80 | 0x01, 0x00]), vec![]);
+        assert_eq!(push(&mut d, 4, 6000, false, &[FU_A, 0x40 | 0x01, 0x02]), vec![]);
+    }
+
+    #[test]
+    fn fu_a_without_start_fragment() {
+        let mut d = H264Depacketizer::new();
+        assert_eq!(push(&mut d, 1, 3000, false, &[FU_A, 0x05, 0x01]), vec![]);
+        assert_eq!(push(&mut d, 2, 3000, true, &[FU_A, 0x40 | 0x05, 0x02]), vec![Ev::AccessUnitEnd]);
+        // 次の開始フラグメントからは普通に組み立てる
+        assert_eq!(push(&mut d, 3, 6000, false, &[FU_A, 0x80 | 0x01, 0x9A]), vec![]);
+        assert_eq!(
+            push(&mut d, 4, 6000, true, &[FU_A, 0x40 | 0x01, 0x01]),
+            vec![Ev::Video(vec![0x61, 0x9A, 0x01], 6000, false), Ev::AccessUnitEnd],
+        );
+    }
+
+    #[test]
+    fn sequence_gap_inside_fragment() {
+        let mut d = H264Depacketizer::new();
+        assert_eq!(push(&mut d, 1, 3000, false, &[FU_A, 0x80 | 0x05, 0x88]), vec![]);
+        // seq 2 が欠落。組み立て中の NAL は捨てて Loss を通知し、残りのフラグメントも捨てる
+        assert_eq!(push(&mut d, 3, 3000, false, &[FU_A, 0x05, 0x01]), vec![Ev::Loss]);
+        assert_eq!(push(&mut d, 4, 3000, true, &[FU_A, 0x40 | 0x05, 0x02]), vec![Ev::AccessUnitEnd]);
+    }
+
+    #[test]
+    fn loss_between_packets() {
+        let mut d = H264Depacketizer::new();
+        assert_eq!(push(&mut d, 65534, 0, true, &[0x65, 0x01]), vec![Ev::Video(vec![0x65, 0x01], 0, true), Ev::AccessUnitEnd]);
+        // 65535 → 0 の折り返しは欠落ではない
+        assert_eq!(push(&mut d, 65535, 3000, true, &[0x41, 0x02]), vec![Ev::Video(vec![0x41, 0x02], 3000, false), Ev::AccessUnitEnd]);
+        assert_eq!(push(&mut d, 0, 6000, true, &[0x41, 0x03]), vec![Ev::Video(vec![0x41, 0x03], 6000, false), Ev::AccessUnitEnd]);
+        assert_eq!(
+            push(&mut d, 5, 9000, true, &[0x41, 0x04]),
+            vec![Ev::Loss, Ev::Video(vec![0x41, 0x04], 9000, false), Ev::AccessUnitEnd],
+        );
+        // reset() の後は sequence_number が飛んでも欠落とはみなさない
+        d.reset();
+        assert_eq!(push(&mut d, 1000, 12000, false, &[0x41, 0x05]), vec![Ev::Video(vec![0x41, 0x05], 12000, false)]);
+    }
+}
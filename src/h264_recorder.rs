@@ -1,81 +1,357 @@
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
+use std::time::SystemTime;
 use crate::nal::NalEvent;
 use crate::mp4_writer::Mp4Writer;
 use crate::h264;
+use crate::access_unit::{AccessUnit, AccessUnitAssembler};
+use crate::rtcp::ClockReference;
+
+/// MP4 に書き込んだサンプル
+#[derive(Debug)]
+pub struct RecordedSample {
+    /// RTP タイムスタンプ（90kHz基準）
+    pub ts: u32,
+    pub is_key: bool,
+    /// RTCP Sender Report から求めた撮影時刻（SR をまだ受け取っていなければ None）
+    pub capture_time: Option<SystemTime>,
+}
 
 pub struct H264Recorder {
     mp4: Option<Mp4Writer>,
     sps: Option<Vec<u8>>,
     pps: Option<Vec<u8>>,
+    /// 出力ファイルの連番（0 なら output.mp4、以降 output_1.mp4 ...）
+    segment: u32,
+    /// パケット欠落後、次の IDR まで映像を書かずに待っている
+    waiting_for_idr: bool,
+    /// 同じタイムスタンプの NAL ユニットを1サンプルにまとめる
+    assembler: AccessUnitAssembler,
+    /// RTP タイムスタンプと壁時計の対応（RTCP SR から）
+    clock: Option<ClockReference>,
+    /// RTP タイムスタンプのクロックレート（SDP の rtpmap）。MP4 の timescale にする
+    clock_rate: u32,
+    /// 解析済みの SPS / PPS を id ごとに保持する（スライスヘッダの解析に使う）
+    sps_map: HashMap<u32, h264::Sps>,
+    pps_map: HashMap<u32, h264::Pps>,
+    /// 受け取った PPS の (参照する SPS id, NAL)。参照先の SPS が届いた・変わった時に解析し直す
+    pps_nals: HashMap<u32, (u32, Vec<u8>)>,
+    /// スライスから参照されたのに見つからず、警告済みのパラメータセット（"SPS" / "PPS", id）。
+    /// スライスごとに同じ警告を出さないために使う
+    missing_parameter_sets: HashSet<(&'static str, u32)>,
+    /// 直前のスライスのヘッダ（ピクチャの区切りの判定に使う）
+    last_slice: Option<h264::SliceHeader>,
+    /// 書き込むピクチャの POC（表示順。B フレームの並べ替えに使う）
+    poc: h264::PicOrderCounter,
 }
 
 impl H264Recorder {
-    fn try_init(sps: &[u8], pps: &[u8]) -> Option<Mp4Writer> {
+    fn try_init(path: &str, sps: &[u8], pps: &[u8], clock_rate: u32) -> Option<Mp4Writer> {
         println!("try_init In...");
-        let (width, height) = h264::parse_sps_resolution(sps)?;
-        println!("*********** Video resolution: {}x{}", width, height);
-        let file = File::create("output.mp4").ok()?;
+        let parsed = match h264::Sps::parse(sps) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                eprintln!("Failed to parse SPS: {}", e);
+                return None;
+            }
+        };
+        let width = u16::try_from(parsed.width()?).ok()?;
+        let height = u16::try_from(parsed.height()?).ok()?;
+        println!("*********** Video: {} profile, level {}, {}x{}",
+            parsed.profile_name(), parsed.level_idc, width, height);
+        if let Some(vui) = parsed.vui.as_ref() {
+            if let Some(fps) = vui.frame_rate() {
+                println!("*********** Frame rate (VUI): {:.2} fps", fps);
+            }
+            if let Some((h, v)) = vui.sample_aspect_ratio() {
+                println!("*********** Sample aspect ratio (VUI): {}:{}", h, v);
+            }
+        }
+        let file = File::create(path).ok()?;
         let mut writer = Mp4Writer::new(file, width, height);
+        writer.set_timescale(clock_rate);
         writer.write_header().ok()?;
         writer.set_sps_pps(sps.to_vec(), pps.to_vec());
-        println!("*********** MP4 recording started -> output.mp4");
+        println!("*********** MP4 recording started -> {}", path);
         Some(writer)
     }
 
+    fn output_path(&self) -> String {
+        if self.segment == 0 {
+            "output.mp4".to_string()
+        } else {
+            format!("output_{}.mp4", self.segment)
+        }
+    }
+
     pub fn new() -> Self {
         Self {
             mp4: None,
             sps: None,
             pps: None,
+            segment: 0,
+            waiting_for_idr: false,
+            assembler: AccessUnitAssembler::new(),
+            clock: None,
+            clock_rate: 90000,
+            sps_map: HashMap::new(),
+            pps_map: HashMap::new(),
+            pps_nals: HashMap::new(),
+            missing_parameter_sets: HashSet::new(),
+            last_slice: None,
+            poc: h264::PicOrderCounter::new(),
+        }
+    }
+
+    /// SPS を解析して id ごとに保持し、その SPS を参照する PPS を解析し直す。
+    fn add_sps(&mut self, nal: &[u8]) {
+        self.sps = Some(nal.to_vec());
+        let sps = match h264::Sps::parse(nal) {
+            Ok(sps) => sps,
+            Err(e) => {
+                eprintln!("Failed to parse SPS: {}", e);
+                return;
+            }
+        };
+        let sps_id = sps.seq_parameter_set_id;
+        self.sps_map.insert(sps_id, sps);
+        self.missing_parameter_sets.remove(&("SPS", sps_id));
+        let pps_ids: Vec<u32> = self.pps_nals.iter()
+            .filter(|(_, (id, _))| *id == sps_id)
+            .map(|(&pps_id, _)| pps_id)
+            .collect();
+        for pps_id in pps_ids {
+            self.parse_pps(pps_id);
         }
     }
 
-    pub fn handle_event(&mut self, ev: NalEvent) {
+    /// PPS を id ごとに保持し、参照先の SPS があれば解析する。
+    fn add_pps(&mut self, nal: &[u8]) {
+        self.pps = Some(nal.to_vec());
+        let (pps_id, sps_id) = match h264::Pps::ids(nal) {
+            Ok(ids) => ids,
+            Err(e) => {
+                eprintln!("Failed to parse PPS: {}", e);
+                return;
+            }
+        };
+        self.pps_nals.insert(pps_id, (sps_id, nal.to_vec()));
+        self.missing_parameter_sets.remove(&("PPS", pps_id));
+        self.parse_pps(pps_id);
+    }
+
+    /// 保持している PPS を、参照先の SPS を使って解析し直す。
+    fn parse_pps(&mut self, pps_id: u32) {
+        let Some((sps_id, nal)) = self.pps_nals.get(&pps_id) else {
+            return;
+        };
+        let Some(sps) = self.sps_map.get(sps_id) else {
+            // SPS が届いた時に解析する
+            self.pps_map.remove(&pps_id);
+            println!("PPS {} refers to SPS {} which has not been received yet", pps_id, sps_id);
+            return;
+        };
+        match h264::Pps::parse(nal, sps) {
+            Ok(pps) => {
+                self.pps_map.insert(pps_id, pps);
+            }
+            Err(e) => {
+                self.pps_map.remove(&pps_id);
+                eprintln!("Failed to parse PPS {}: {}", pps_id, e);
+            }
+        }
+    }
+
+    /// 保持している SPS / PPS をすべて忘れる。
+    fn clear_parameter_sets(&mut self) {
+        self.sps = None;
+        self.pps = None;
+        self.sps_map.clear();
+        self.pps_map.clear();
+        self.pps_nals.clear();
+        self.missing_parameter_sets.clear();
+    }
+
+    /// スライスが参照する PPS と、その PPS が参照する SPS を返す。
+    fn lookup_parameter_sets(&self, pps_id: u32) -> Result<(&h264::Sps, &h264::Pps), h264::H264Error> {
+        let pps = self.pps_map.get(&pps_id)
+            .ok_or(h264::H264Error::MissingParameterSet("PPS", pps_id))?;
+        let sps = self.sps_map.get(&pps.seq_parameter_set_id)
+            .ok_or(h264::H264Error::MissingParameterSet("SPS", pps.seq_parameter_set_id))?;
+        Ok((sps, pps))
+    }
+
+    /// スライスのヘッダを解析する（参照先の SPS / PPS が無ければ None）。
+    fn parse_slice_header(&mut self, nal: &[u8]) -> Option<h264::SliceHeader> {
+        match h264::SliceHeader::parse(nal, |id| self.lookup_parameter_sets(id)) {
+            Ok(header) => Some(header),
+            // 見つからない警告は、そのパラメータセットが届くまで1度だけ出す
+            Err(e @ h264::H264Error::MissingParameterSet(kind, id)) => {
+                if self.missing_parameter_sets.insert((kind, id)) {
+                    eprintln!("Failed to parse slice header: {}", e);
+                }
+                None
+            }
+            Err(e) => {
+                eprintln!("Failed to parse slice header: {}", e);
+                None
+            }
+        }
+    }
+
+    /// SDP の sprop-parameter-sets などで得た SPS / PPS を設定する。
+    /// インバンドの SPS / PPS が届けばそちらで上書きされる。
+    pub fn set_parameter_sets(&mut self, sps: Vec<u8>, pps: Vec<u8>) {
+        println!("@@@@@@@@@@@@ SPS/PPS from SDP");
+        self.add_sps(&sps);
+        self.add_pps(&pps);
+    }
+
+    /// SDP の rtpmap のクロックレートを設定する（デフォルト: 90000）。
+    /// 次に作成する MP4 から、RTP タイムスタンプをそのまま timescale の単位として書く。
+    pub fn set_clock_rate(&mut self, clock_rate: u32) {
+        self.clock_rate = clock_rate;
+    }
+
+    /// RTCP Sender Report から得た RTP タイムスタンプと壁時計の対応を設定する。
+    /// 以降に書き込むサンプルに撮影時刻が付く。
+    pub fn set_clock_reference(&mut self, clock: ClockReference) {
+        self.clock = Some(clock);
+    }
+
+    /// NAL ユニットのイベントを処理する。
+    /// フレームが揃って MP4 にサンプルを書き込んだ場合はその情報を返す。
+    pub fn handle_event(&mut self, ev: NalEvent) -> Option<RecordedSample> {
         match ev {
             NalEvent::Sps(sps) => {
                 println!("@@@@@@@@@@@@ Received SPS");
-                self.sps = Some(sps.to_vec());
-                if let (Some(ref sps), Some(ref pps)) = (&self.sps, &self.pps) {
-                    if self.mp4.is_none() {
-                        self.mp4 = Self::try_init(sps, pps);
-                    }
-                }
+                self.add_sps(sps);
+                None
             }
 
             NalEvent::Pps(pps) => {
                 println!("@@@@@@@@@@@@ Received PPS");
-                self.pps = Some(pps.to_vec());
-                if let (Some(ref sps), Some(ref pps)) = (&self.sps, &self.pps) {
-                    if self.mp4.is_none() {
-                        self.mp4 = Self::try_init(sps, pps);
-                    }
+                self.add_pps(pps);
+                None
+            }
+
+            NalEvent::Loss => {
+                // 組み立て中のフレームは欠けているかもしれないので捨てる
+                self.assembler.discard();
+                self.last_slice = None;
+                // 参照先が壊れたフレームを書かないよう、次の IDR まで待つ
+                if self.mp4.is_some() && !self.waiting_for_idr {
+                    println!("Packet loss detected, waiting for next IDR");
+                    self.waiting_for_idr = true;
                 }
+                None
             }
 
             NalEvent::Video { data, ts, is_key } => {
-                if let Some(ref mut writer) = self.mp4 {
-                    println!("*********** Writing video sample: ts={}, is_key={}", ts, is_key);
-                    let _ = writer.write_sample(data, ts, is_key);
+                let slice = self.parse_slice_header(data);
+                // 別のピクチャの最初のスライスなら、タイムスタンプが同じでもここで区切る
+                let finished = match (&slice, &self.last_slice) {
+                    (Some(s), Some(prev)) if s.starts_new_picture(prev) => self.assembler.start_picture(),
+                    _ => None,
+                };
+                let pushed = self.assembler.push(ts, data, is_key, true);
+                if let Some(ref s) = slice {
+                    self.assembler.note_slice(s);
                 }
+                self.last_slice = slice;
+                // 区切った場合は push() で新しいアクセスユニットが始まるので、両方が返ることはない
+                let au = finished.or(pushed)?;
+                self.write_access_unit(au)
             }
 
-            _ => {}
+            NalEvent::Sei { data, ts } | NalEvent::Aud { data, ts } => {
+                // スライスの後の SEI / AUD は次のアクセスユニットの先頭
+                let finished = self.assembler.start_picture();
+                let pushed = self.assembler.push(ts, data, false, false);
+                let au = finished.or(pushed)?;
+                self.write_access_unit(au)
+            }
+
+            NalEvent::AccessUnitEnd | NalEvent::End => {
+                let au = self.assembler.end()?;
+                self.write_access_unit(au)
+            }
         }
     }
 
+    /// アクセスユニットを1サンプルとして MP4 に書き込む。
+    fn write_access_unit(&mut self, au: AccessUnit) -> Option<RecordedSample> {
+        if !au.has_vcl {
+            return None;
+        }
+        if self.waiting_for_idr {
+            if !au.is_key {
+                return None;
+            }
+            self.waiting_for_idr = false;
+        }
+        // 最初のキーフレームで MP4 を作成する（それ以前のフレームは参照先がないので捨てる）
+        if self.mp4.is_none() && au.is_key {
+            if let (Some(ref sps), Some(ref pps)) = (&self.sps, &self.pps) {
+                self.mp4 = Self::try_init(&self.output_path(), sps, pps, self.clock_rate);
+            }
+        }
+        // 復号順に POC を数える（IDR で基準が戻るので、書き込むピクチャだけで足りる）
+        let poc = au.first_slice.as_ref().and_then(|slice| {
+            let pps = self.pps_map.get(&slice.pic_parameter_set_id)?;
+            let sps = self.sps_map.get(&pps.seq_parameter_set_id)?;
+            Some(self.poc.compute(sps, slice))
+        });
+        let writer = self.mp4.as_mut()?;
+        let capture_time = self.clock.map(|c| c.wall_clock_at(au.ts));
+        let picture_type = au.picture_type.map_or("?".to_string(), |t| format!("{:?}", t));
+        println!("*********** Writing video sample: ts={}, is_key={}, type={}, slices={}, poc={:?}, nals={}",
+            au.ts, au.is_key, picture_type, au.slices, poc, au.nals.len());
+        writer.write_sample(&au.nals, au.ts, poc, au.is_key, capture_time).ok()?;
+        Some(RecordedSample {
+            ts: au.ts,
+            is_key: au.is_key,
+            capture_time,
+        })
+    }
+
     pub fn finalize(&mut self) {
+        // 組み立て中のフレームを書き出す
+        if let Some(au) = self.assembler.end() {
+            let _ = self.write_access_unit(au);
+        }
+        let path = self.output_path();
         if let Some(ref mut writer) = self.mp4 {
             let count = writer.sample_count();
             if count > 0 {
                 match writer.finalize() {
-                    Ok(_) => println!("output.mp4 saved ({} samples)", count),
+                    Ok(_) => println!("{} saved ({} samples)", path, count),
                     Err(e) => eprintln!("Failed to finalize MP4: {}", e),
                 }
             } else {
-                println!("No samples recorded, output.mp4 not finalized.");
+                println!("No samples recorded, {} not finalized.", path);
             }
         } else {
-            println!("No samples recorded, output.mp4 not finalized.");
+            println!("No samples recorded, {} not finalized.", path);
+        }
+    }
+
+    /// 現在のファイルを確定し、次の SPS/PPS から新しいファイルに録画する。
+    /// 再接続でタイムスタンプや SPS が変わった場合に使う。
+    pub fn roll(&mut self) {
+        self.assembler.discard();
+        self.last_slice = None;
+        // 再接続後は RTP タイムスタンプの基準が変わる
+        self.clock = None;
+        if self.mp4.is_none() {
+            // まだ何も書いていなければ同じファイル名を使い続ける
+            self.clear_parameter_sets();
+            return;
         }
+        self.finalize();
+        self.mp4 = None;
+        self.waiting_for_idr = false;
+        self.clear_parameter_sets();
+        self.segment += 1;
     }
 }
\ No newline at end of file
@@ -0,0 +1,298 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use crate::rtp::RTPHeader;
+
+/// これ以上 sequence_number が飛んだらストリームが作り直されたとみなす（RFC 3550 A.1 の MAX_DROPOUT）
+const MAX_DROPOUT: i64 = 3000;
+/// バッファに溜める最大パケット数（超えたら待たずに送り出す）
+const MAX_PACKETS: usize = 1024;
+
+/// ジッタバッファから取り出される要素
+#[derive(Debug)]
+pub enum JitterOutput {
+    /// sequence_number 順に並べ直したパケット
+    Packet(RTPHeader, Vec<u8>),
+    /// 待ち時間内に届かなかったパケット（先頭の sequence_number と個数）
+    Lost { first_seq: u16, count: u64 },
+}
+
+struct BufferedPacket {
+    arrival: Instant,
+    header: RTPHeader,
+    payload: Vec<u8>,
+}
+
+/// RTP パケットを sequence_number 順に並べ直すジッタバッファ。
+///
+/// 欠けているパケットは最大 `latency` だけ待ち、それでも届かなければ
+/// 欠落として報告して後続のパケットを送り出す。
+/// sequence_number は 16bit で折り返すので、内部では折り返し回数を含めた
+/// 拡張シーケンス番号で管理する。
+pub struct JitterBuffer {
+    latency: Duration,
+    packets: BTreeMap<u64, BufferedPacket>,
+    /// 次に送り出す拡張シーケンス番号
+    next_seq: Option<u64>,
+    /// これまでに受け取った最大の拡張シーケンス番号
+    highest_seq: u64,
+    /// 遅れて届いた（送り出し済みの番号の）パケット数
+    late: u64,
+    /// 欠落として報告したパケット数
+    lost: u64,
+}
+
+impl JitterBuffer {
+    pub fn new(latency: Duration) -> Self {
+        Self {
+            latency,
+            packets: BTreeMap::new(),
+            next_seq: None,
+            highest_seq: 0,
+            late: 0,
+            lost: 0,
+        }
+    }
+
+    /// 状態を初期化する。再接続などで sequence_number が連続しなくなる場合に呼ぶ。
+    pub fn reset(&mut self) {
+        self.packets.clear();
+        self.next_seq = None;
+        self.highest_seq = 0;
+    }
+
+    /// 16bit の sequence_number を拡張シーケンス番号に変換する。
+    fn extend(&self, seq: u16) -> i64 {
+        let delta = seq.wrapping_sub(self.highest_seq as u16) as i16 as i64;
+        self.highest_seq as i64 + delta
+    }
+
+    /// 受信したパケットを追加する。
+    pub fn push(&mut self, header: RTPHeader, payload: Vec<u8>) {
+        let next = match self.next_seq {
+            Some(n) => n,
+            None => {
+                // 最初のパケット。2^16 分の余裕を持たせて負にならないようにする
+                let ext = 0x10000 + header.sequence_number as u64;
+                self.highest_seq = ext;
+                self.next_seq = Some(ext);
+                ext
+            }
+        };
+
+        let ext = self.extend(header.sequence_number);
+        if (ext - self.highest_seq as i64).abs() > MAX_DROPOUT {
+            // 大きく飛んだ場合は送信側がリセットされたとみなして作り直す
+            println!("RTP sequence jumped to {}, resetting jitter buffer", header.sequence_number);
+            self.reset();
+            self.push(header, payload);
+            return;
+        }
+        if ext < next as i64 {
+            // 送り出し済みの番号（遅延到着・重複）
+            self.late += 1;
+            return;
+        }
+
+        let ext = ext as u64;
+        if ext > self.highest_seq {
+            self.highest_seq = ext;
+        }
+        self.packets.entry(ext).or_insert(BufferedPacket {
+            arrival: Instant::now(),
+            header,
+            payload,
+        });
+    }
+
+    /// 送り出せる要素があれば1つ返す。
+    pub fn pop(&mut self, now: Instant) -> Option<JitterOutput> {
+        let next = self.next_seq?;
+        let (&first, head) = self.packets.iter().next()?;
+
+        if first == next {
+            let p = self.packets.remove(&first)?;
+            self.next_seq = Some(next + 1);
+            return Some(JitterOutput::Packet(p.header, p.payload));
+        }
+
+        // 欠けている番号を待つ。待ち時間切れかバッファ溢れなら諦める
+        if now.duration_since(head.arrival) >= self.latency || self.packets.len() >= MAX_PACKETS {
+            let count = first - next;
+            self.lost += count;
+            self.next_seq = Some(first);
+            return Some(JitterOutput::Lost { first_seq: next as u16, count });
+        }
+        None
+    }
+
+    /// 待っているパケットを送り出すまでの残り時間。バッファが空なら None。
+    /// pop() と同じく、シーケンス番号が最も小さいパケットの到着時刻を基準にする。
+    pub fn time_until_release(&self, now: Instant) -> Option<Duration> {
+        let (&first, head) = self.packets.iter().next()?;
+        if self.next_seq == Some(first) || self.packets.len() >= MAX_PACKETS {
+            return Some(Duration::ZERO);
+        }
+        Some((head.arrival + self.latency).saturating_duration_since(now))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    /// 欠落として報告したパケット数の累計
+    pub fn lost(&self) -> u64 {
+        self.lost
+    }
+
+    /// 遅れて届いて捨てたパケット数の累計
+    pub fn late(&self) -> u64 {
+        self.late
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LATENCY: Duration = Duration::from_millis(200);
+
+    fn push(buffer: &mut JitterBuffer, seq: u16) {
+        let header = RTPHeader {
+            marker: 0,
+            payload_type: 96,
+            sequence_number: seq,
+            timestamp: seq as u32 * 3000,
+            ssrc: 0x1234_5678,
+            csrcs: Vec::new(),
+            extension_header: None,
+        };
+        buffer.push(header, seq.to_be_bytes().to_vec());
+    }
+
+    /// 送り出された要素を (sequence_number, 欠落数) の列にする
+    fn drain(buffer: &mut JitterBuffer, now: Instant) -> Vec<(u16, u64)> {
+        let mut out = Vec::new();
+        while let Some(item) = buffer.pop(now) {
+            out.push(match item {
+                JitterOutput::Packet(header, payload) => {
+                    assert_eq!(payload, header.sequence_number.to_be_bytes());
+                    (header.sequence_number, 0)
+                }
+                JitterOutput::Lost { first_seq, count } => (first_seq, count),
+            });
+        }
+        out
+    }
+
+    #[test]
+    fn in_order_across_wrap() {
+        let mut buffer = JitterBuffer::new(LATENCY);
+        for seq in [65534, 65535, 0, 1] {
+            push(&mut buffer, seq);
+        }
+        assert_eq!(buffer.time_until_release(Instant::now()), Some(Duration::ZERO));
+        assert_eq!(drain(&mut buffer, Instant::now()), vec![(65534, 0), (65535, 0), (0, 0), (1, 0)]);
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.time_until_release(Instant::now()), None);
+        assert_eq!(buffer.lost(), 0);
+    }
+
+    #[test]
+    fn reorders_within_latency() {
+        let mut buffer = JitterBuffer::new(LATENCY);
+        push(&mut buffer, 65535);
+        push(&mut buffer, 1);
+        // 0 を待っている間は 1 を送り出さない
+        assert_eq!(drain(&mut buffer, Instant::now()), vec![(65535, 0)]);
+        let wait = buffer.time_until_release(Instant::now()).unwrap();
+        assert!(wait > Duration::ZERO && wait <= LATENCY, "{:?}", wait);
+
+        push(&mut buffer, 2);
+        push(&mut buffer, 0);
+        assert_eq!(drain(&mut buffer, Instant::now()), vec![(0, 0), (1, 0), (2, 0)]);
+        assert_eq!(buffer.lost(), 0);
+        assert_eq!(buffer.late(), 0);
+    }
+
+    #[test]
+    fn gap_is_reported_after_latency() {
+        let mut buffer = JitterBuffer::new(LATENCY);
+        push(&mut buffer, 65534);
+        assert_eq!(drain(&mut buffer, Instant::now()), vec![(65534, 0)]);
+        push(&mut buffer, 2);
+        push(&mut buffer, 3);
+        assert_eq!(drain(&mut buffer, Instant::now()), vec![]);
+
+        // 先頭のパケットが届いてから latency を過ぎたら、欠けた 65535〜1 を諦める
+        let expired = Instant::now() + LATENCY;
+        assert_eq!(buffer.time_until_release(expired), Some(Duration::ZERO));
+        assert_eq!(drain(&mut buffer, expired), vec![(65535, 3), (2, 0), (3, 0)]);
+        assert_eq!(buffer.lost(), 3);
+
+        // 諦めた後に届いたパケットは遅延到着として捨てる
+        push(&mut buffer, 0);
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.late(), 1);
+    }
+
+    #[test]
+    fn duplicate_and_late_packets_are_dropped() {
+        let mut buffer = JitterBuffer::new(LATENCY);
+        push(&mut buffer, 10);
+        push(&mut buffer, 12);
+        // 送り出す前の重複はバッファに1つだけ残る
+        push(&mut buffer, 12);
+        assert_eq!(drain(&mut buffer, Instant::now()), vec![(10, 0)]);
+        // 送り出し済みの番号
+        push(&mut buffer, 10);
+        push(&mut buffer, 9);
+        assert_eq!(buffer.late(), 2);
+
+        push(&mut buffer, 11);
+        assert_eq!(drain(&mut buffer, Instant::now()), vec![(11, 0), (12, 0)]);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn jump_beyond_max_dropout_resets() {
+        let mut buffer = JitterBuffer::new(LATENCY);
+        push(&mut buffer, 100);
+        push(&mut buffer, 102);
+        assert_eq!(drain(&mut buffer, Instant::now()), vec![(100, 0)]);
+
+        // MAX_DROPOUT を超えて飛んだら作り直し、待っていた 101 も 102 も捨てる
+        let jumped = 102 + MAX_DROPOUT as u16 + 1;
+        push(&mut buffer, jumped);
+        push(&mut buffer, jumped + 1);
+        assert_eq!(drain(&mut buffer, Instant::now()), vec![(jumped, 0), (jumped + 1, 0)]);
+        assert_eq!(buffer.lost(), 0);
+
+        // 後ろへ大きく戻った場合も同じ
+        push(&mut buffer, 5);
+        assert_eq!(drain(&mut buffer, Instant::now()), vec![(5, 0)]);
+        assert_eq!(buffer.late(), 0);
+    }
+
+    #[test]
+    fn overflow_releases_without_waiting() {
+        let mut buffer = JitterBuffer::new(LATENCY);
+        push(&mut buffer, 0);
+        assert_eq!(drain(&mut buffer, Instant::now()), vec![(0, 0)]);
+
+        // 1 が欠けたまま MAX_PACKETS - 1 個溜まっても待つ
+        for seq in 2..MAX_PACKETS as u16 + 1 {
+            push(&mut buffer, seq);
+        }
+        assert_eq!(buffer.pop(Instant::now()).map(|_| ()), None);
+        assert!(buffer.time_until_release(Instant::now()).unwrap() > Duration::ZERO);
+
+        // MAX_PACKETS 個目で待たずに欠落を報告する
+        push(&mut buffer, MAX_PACKETS as u16 + 1);
+        assert_eq!(buffer.time_until_release(Instant::now()), Some(Duration::ZERO));
+        let released = drain(&mut buffer, Instant::now());
+        assert_eq!(released[0], (1, 1));
+        assert_eq!(released.len(), MAX_PACKETS + 1);
+        assert_eq!(released.last(), Some(&(MAX_PACKETS as u16 + 1, 0)));
+        assert_eq!(buffer.lost(), 1);
+    }
+}
@@ -1,5 +1,11 @@
 use std::io::{self, Write, Seek, SeekFrom};
 use std::fs::File;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::h264;
+use crate::rtcp;
+
+/// 1904-01-01（MP4 の時刻の起点）から 1970-01-01 までの秒数
+const MP4_UNIX_OFFSET: u64 = 2_082_844_800;
 
 // ============================================================
 // データ構造
@@ -10,12 +16,27 @@ use std::fs::File;
 struct SampleInfo {
     /// ファイル内の絶対オフセット（length-prefixの先頭）
     offset: u64,
-    /// サンプルのバイト数（length-prefixの4バイトを含む）
+    /// サンプルのバイト数（各NALのlength-prefix 4バイトを含む）
     size: u32,
-    /// RTPタイムスタンプ（90kHz基準）
-    dts: u32,
+    /// RTPタイムスタンプ（90kHz基準）。RTP のタイムスタンプは表示時刻
+    pts: u32,
+    /// ピクチャ順序カウント（スライスヘッダを解析できた場合）
+    poc: Option<i32>,
     /// IDRフレームかどうか
     is_keyframe: bool,
+    /// 撮影時刻（RTCP Sender Report から求めた送信側の壁時計）
+    capture_time: Option<SystemTime>,
+}
+
+/// cslg（CompositionToDecodeBox）の値
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct CompositionShift {
+    /// compositionToDTSShift（表示時刻に足すと復号時刻以降になる量）
+    shift: i32,
+    least: i32,
+    greatest: i32,
+    start: i32,
+    end: i32,
 }
 
 /// MP4ファイルライター
@@ -29,7 +50,7 @@ struct SampleInfo {
 /// mp4.set_sps_pps(sps, pps);
 ///
 /// // フレームごとに呼ぶ
-/// mp4.write_sample(&nal_data, rtp_timestamp, is_idr)?;
+/// mp4.write_sample(&nal_units, rtp_timestamp, poc, is_idr, capture_time)?;
 ///
 /// // 録画終了
 /// mp4.finalize()?;
@@ -43,6 +64,8 @@ pub struct Mp4Writer {
     sps: Vec<u8>,
     /// PPS（スタートコードなし）
     pps: Vec<u8>,
+    /// 解析済みの SPS（avcC の拡張・VUI のフレームレートや色情報に使う）
+    sps_info: Option<h264::Sps>,
     /// 映像の幅（ピクセル）
     width: u16,
     /// 映像の高さ（ピクセル）
@@ -55,6 +78,8 @@ pub struct Mp4Writer {
     mdat_data_start: u64,
     /// finalize() 済みフラグ（二重呼び出し防止）
     finalized: bool,
+    /// ライターを作成した時刻（撮影時刻が分からない場合の creation_time）
+    created: SystemTime,
 }
 
 // ============================================================
@@ -74,12 +99,14 @@ impl Mp4Writer {
             samples: Vec::new(),
             sps: Vec::new(),
             pps: Vec::new(),
+            sps_info: None,
             width,
             height,
             timescale: 90000,
             mdat_size_pos: 0,
             mdat_data_start: 0,
             finalized: false,
+            created: SystemTime::now(),
         }
     }
 
@@ -93,6 +120,13 @@ impl Mp4Writer {
     /// スタートコード（00 00 00 01）を除いた生NALデータを渡すこと。
     /// write_header() より後、最初の write_sample() より前に呼ぶこと。
     pub fn set_sps_pps(&mut self, sps: Vec<u8>, pps: Vec<u8>) {
+        self.sps_info = match h264::Sps::parse(&sps) {
+            Ok(info) => Some(info),
+            Err(e) => {
+                eprintln!("Mp4Writer: failed to parse SPS: {}", e);
+                None
+            }
+        };
         self.sps = sps;
         self.pps = pps;
     }
@@ -108,26 +142,34 @@ impl Mp4Writer {
         Ok(())
     }
 
-    /// 1フレーム分のNALユニットを書き込む。
+    /// 1フレーム（アクセスユニット）分のNALユニットを1サンプルとして書き込む。
     ///
     /// # 引数
-    /// * `nal`        - スタートコードなしの生NALデータ
-    /// * `dts`        - RTPタイムスタンプ（90kHz基準）
+    /// * `nals`       - スタートコードなしの生NALデータ（復号順）
+    /// * `pts`        - RTPタイムスタンプ（90kHz基準、表示時刻）
+    /// * `poc`        - ピクチャ順序カウント（分かる場合）。表示時刻と表示順の食い違いの確認に使う
     /// * `is_keyframe`- IDRフレームなら true
-    pub fn write_sample(&mut self, nal: &[u8], dts: u32, is_keyframe: bool) -> io::Result<()> {
+    /// * `capture_time` - 撮影時刻（分かる場合）。moov/udta/wclk に記録する
+    pub fn write_sample(&mut self, nals: &[Vec<u8>], pts: u32, poc: Option<i32>, is_keyframe: bool, capture_time: Option<SystemTime>) -> io::Result<()> {
         println!("@@@@ write_sample sample_count={}", self.samples.len());
         let offset = self.writer.stream_position()?;
-        let nal_size = nal.len() as u32;
+        let mut size = 0u32;
 
-        // length-prefix（4バイトBE）＋NALデータ
-        self.writer.write_all(&nal_size.to_be_bytes())?;
-        self.writer.write_all(nal)?;
+        // NALユニットごとに length-prefix（4バイトBE）＋NALデータ
+        for nal in nals {
+            let nal_size = nal.len() as u32;
+            self.writer.write_all(&nal_size.to_be_bytes())?;
+            self.writer.write_all(nal)?;
+            size += nal_size + 4;
+        }
 
         self.samples.push(SampleInfo {
             offset,
-            size: nal_size + 4,
-            dts,
+            size,
+            pts,
+            poc,
             is_keyframe,
+            capture_time,
         });
 
         Ok(())
@@ -154,6 +196,10 @@ impl Mp4Writer {
         self.writer.seek(SeekFrom::Start(end_pos))?;
 
         // 2. moov を書く
+        let mismatches = self.presentation_order_mismatches();
+        if mismatches > 0 {
+            eprintln!("Mp4Writer: {} samples have RTP timestamps that disagree with the POC order", mismatches);
+        }
         self.write_moov()?;
 
         self.writer.flush()?;
@@ -239,6 +285,65 @@ impl Mp4Writer {
         self.write_box(b"moov", |s| {
             s.write_mvhd()?;
             s.write_trak()?;
+            s.write_udta()?;
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    /// mvhd / tkhd / mdhd の creation_time（1904 年からの秒）。
+    /// 撮影時刻が分かるサンプルがあれば先頭サンプルの撮影時刻、なければ録画開始時刻。
+    fn creation_time(&self) -> u32 {
+        let pts = self.presentation_times();
+        let first = pts.iter().copied().min().unwrap_or(0);
+        let start = match self.samples.iter().zip(&pts).find_map(|(s, &t)| s.capture_time.map(|c| (t, c))) {
+            // 撮影時刻の分かる最初のサンプルから、最初に表示されるサンプルまで遡る
+            Some((pts, time)) => {
+                let ticks = (pts - first) as u64;
+                time - std::time::Duration::from_millis(ticks * 1000 / self.timescale as u64)
+            }
+            None => self.created,
+        };
+        let unix = start.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        (unix + MP4_UNIX_OFFSET) as u32
+    }
+
+    /// サンプルごとの撮影時刻を moov/udta/wclk に書き込む。
+    ///
+    /// wclk はこのツール独自の FullBox で、レイアウトは次のとおり（すべてビッグエンディアン）。
+    ///
+    /// ```text
+    /// aligned(8) class WallClockBox extends FullBox('wclk', version = 0, flags = 0) {
+    ///     unsigned int(32) entry_count;
+    ///     for (i = 0; i < entry_count; i++) {
+    ///         unsigned int(32) sample_number;  // stbl のサンプル番号（1 始まり、復号順）
+    ///         unsigned int(64) ntp_timestamp;  // 撮影時刻（NTP 形式: 上位 32bit が 1900 年からの秒、下位 32bit が秒の小数部）
+    ///     }
+    /// }
+    /// ```
+    ///
+    /// 撮影時刻の分からないサンプルは含めないので、sample_number は飛ぶことがある。
+    /// 標準の仕組みでは udta/©day はファイル全体で1つの日時しか持てず、prft は
+    /// フラグメント MP4（moof）用なので、サンプルごとの時刻を残すために独自の箱を使う。
+    /// 未知の箱は ISO BMFF のリーダーが読み飛ばすので、通常の再生には影響しない。
+    fn write_udta(&mut self) -> io::Result<()> {
+        let entries: Vec<(u32, u64)> = self.samples.iter()
+            .enumerate()
+            .filter_map(|(i, s)| s.capture_time.map(|t| (i as u32 + 1, rtcp::system_time_to_ntp(t))))
+            .collect();
+        if entries.is_empty() {
+            return Ok(());
+        }
+        self.write_box(b"udta", |s| {
+            s.write_box(b"wclk", |s| {
+                s.writer.write_all(&0u32.to_be_bytes())?;                   // version & flags
+                s.writer.write_all(&(entries.len() as u32).to_be_bytes())?; // entry_count
+                for (sample_number, ntp) in &entries {
+                    s.writer.write_all(&sample_number.to_be_bytes())?;
+                    s.writer.write_all(&ntp.to_be_bytes())?;
+                }
+                Ok(())
+            })?;
             Ok(())
         })?;
         Ok(())
@@ -246,10 +351,11 @@ impl Mp4Writer {
 
     fn write_mvhd(&mut self) -> io::Result<()> {
         let duration_ms = self.calc_duration_ms();
+        let creation_time = self.creation_time();
         self.write_box(b"mvhd", |s| {
             s.writer.write_all(&0u32.to_be_bytes())?;          // version=0, flags=0
-            s.writer.write_all(&0u32.to_be_bytes())?;          // creation_time
-            s.writer.write_all(&0u32.to_be_bytes())?;          // modification_time
+            s.writer.write_all(&creation_time.to_be_bytes())?; // creation_time
+            s.writer.write_all(&creation_time.to_be_bytes())?; // modification_time
             s.writer.write_all(&1000u32.to_be_bytes())?;       // timescale = ms 単位
             s.writer.write_all(&duration_ms.to_be_bytes())?;   // duration
             s.writer.write_all(&0x00010000u32.to_be_bytes())?; // rate = 1.0
@@ -282,11 +388,12 @@ impl Mp4Writer {
         let duration_ms = self.calc_duration_ms();
         let width = self.width;
         let height = self.height;
+        let creation_time = self.creation_time();
         self.write_box(b"tkhd", |s| {
             // version=0, flags=3 (enabled | in_movie)
             s.writer.write_all(&3u32.to_be_bytes())?;
-            s.writer.write_all(&0u32.to_be_bytes())?;         // creation_time
-            s.writer.write_all(&0u32.to_be_bytes())?;         // modification_time
+            s.writer.write_all(&creation_time.to_be_bytes())?; // creation_time
+            s.writer.write_all(&creation_time.to_be_bytes())?; // modification_time
             s.writer.write_all(&1u32.to_be_bytes())?;         // track_id = 1
             s.writer.write_all(&0u32.to_be_bytes())?;         // reserved
             s.writer.write_all(&duration_ms.to_be_bytes())?;  // duration (mvhd と同じ timescale)
@@ -323,10 +430,11 @@ impl Mp4Writer {
     fn write_mdhd(&mut self) -> io::Result<()> {
         let duration = self.calc_duration_ticks();
         let timescale = self.timescale;
+        let creation_time = self.creation_time();
         self.write_box(b"mdhd", |s| {
             s.writer.write_all(&0u32.to_be_bytes())?;         // version=0, flags=0
-            s.writer.write_all(&0u32.to_be_bytes())?;         // creation_time
-            s.writer.write_all(&0u32.to_be_bytes())?;         // modification_time
+            s.writer.write_all(&creation_time.to_be_bytes())?; // creation_time
+            s.writer.write_all(&creation_time.to_be_bytes())?; // modification_time
             s.writer.write_all(&timescale.to_be_bytes())?;    // timescale (90000)
             s.writer.write_all(&duration.to_be_bytes())?;     // duration (ticks)
             s.writer.write_all(&0x55C4u16.to_be_bytes())?;    // language = "und"
@@ -399,6 +507,8 @@ impl Mp4Writer {
         self.write_box(b"stbl", |s| {
             s.write_stsd()?;
             s.write_stts()?;
+            s.write_ctts()?;
+            s.write_cslg()?;
             s.write_stss()?;
             s.write_stsc()?;
             s.write_stsz()?;
@@ -441,6 +551,8 @@ impl Mp4Writer {
             s.writer.write_all(&0x0018u16.to_be_bytes())?;             // depth = 24
             s.writer.write_all(&0xFFFFu16.to_be_bytes())?;             // pre_defined = -1
             s.write_avcc(&sps, &pps)?;
+            s.write_pasp()?;
+            s.write_colr()?;
             Ok(())
         })?;
         Ok(())
@@ -463,6 +575,51 @@ impl Mp4Writer {
             s.writer.write_all(&[0x01])?;  // numPictureParameterSets = 1
             s.writer.write_all(&(pps.len() as u16).to_be_bytes())?;
             s.writer.write_all(pps)?;
+            // High 系プロファイルでは色差フォーマットとビット深度を追記する（ISO/IEC 14496-15）
+            if let Some(ref parsed) = s.sps_info {
+                if matches!(parsed.profile_idc, 100 | 110 | 122 | 144) {
+                    s.writer.write_all(&[
+                        0xFC | parsed.chroma_format_idc as u8,       // reserved(6) + chroma_format
+                        0xF8 | parsed.bit_depth_luma_minus8 as u8,   // reserved(5) + bit_depth_luma_minus8
+                        0xF8 | parsed.bit_depth_chroma_minus8 as u8, // reserved(5) + bit_depth_chroma_minus8
+                        0x00,                                        // numOfSequenceParameterSetExt = 0
+                    ])?;
+                }
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    /// VUI の aspect_ratio_info があればサンプルアスペクト比を pasp に書く。
+    fn write_pasp(&mut self) -> io::Result<()> {
+        let Some((h_spacing, v_spacing)) = self.vui().and_then(|v| v.sample_aspect_ratio()) else {
+            return Ok(());
+        };
+        self.write_box(b"pasp", |s| {
+            s.writer.write_all(&(h_spacing as u32).to_be_bytes())?; // hSpacing
+            s.writer.write_all(&(v_spacing as u32).to_be_bytes())?; // vSpacing
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    /// VUI の video_signal_type があれば色情報を colr (nclx) に書く。
+    fn write_colr(&mut self) -> io::Result<()> {
+        let Some(signal) = self.vui().and_then(|v| v.video_signal_type) else {
+            return Ok(());
+        };
+        // colour_description がなければ「未指定」(2)
+        let (primaries, transfer, matrix) = match signal.colour_description {
+            Some(c) => (c.colour_primaries, c.transfer_characteristics, c.matrix_coefficients),
+            None => (2, 2, 2),
+        };
+        self.write_box(b"colr", |s| {
+            s.writer.write_all(b"nclx")?;                               // colour_type
+            s.writer.write_all(&(primaries as u16).to_be_bytes())?;    // colour_primaries
+            s.writer.write_all(&(transfer as u16).to_be_bytes())?;     // transfer_characteristics
+            s.writer.write_all(&(matrix as u16).to_be_bytes())?;       // matrix_coefficients
+            s.writer.write_all(&[(signal.video_full_range_flag as u8) << 7])?; // full_range_flag + reserved
             Ok(())
         })?;
         Ok(())
@@ -490,17 +647,14 @@ impl Mp4Writer {
         if n == 0 {
             return vec![];
         }
+        let dts = self.decode_times();
         let mut entries: Vec<(u32, u32)> = Vec::new();
         for i in 0..n {
             let delta = if i + 1 < n {
                 // 次フレームとのDTS差分
-                self.samples[i + 1].dts.wrapping_sub(self.samples[i].dts)
-            } else if n >= 2 {
-                // 最終フレームは1つ前と同じdeltaを使う
-                self.samples[n - 1].dts.wrapping_sub(self.samples[n - 2].dts)
+                (dts[i + 1] - dts[i]) as u32
             } else {
-                // フレームが1枚だけ: 30fps想定のフォールバック
-                3000
+                self.last_sample_duration()
             };
             match entries.last_mut() {
                 Some(last) if last.1 == delta => last.0 += 1,
@@ -510,6 +664,61 @@ impl Mp4Writer {
         entries
     }
 
+    // ----- ctts -----
+
+    /// 表示時刻と復号時刻の差（composition offset）をランレングス圧縮して書く。
+    /// B フレームは復号時刻より前に表示されるので、負の値を書ける version 1 を使う。
+    /// 並べ替えのないストリームでは書かない。
+    fn write_ctts(&mut self) -> io::Result<()> {
+        let entries = self.build_ctts_entries();
+        if entries.iter().all(|&(_, offset)| offset == 0) {
+            return Ok(());
+        }
+        self.write_box(b"ctts", |s| {
+            s.writer.write_all(&0x01000000u32.to_be_bytes())?;         // version=1, flags=0
+            s.writer.write_all(&(entries.len() as u32).to_be_bytes())?;
+            for (count, offset) in &entries {
+                s.writer.write_all(&count.to_be_bytes())?;
+                s.writer.write_all(&offset.to_be_bytes())?;
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    fn build_ctts_entries(&self) -> Vec<(u32, i32)> {
+        let mut entries: Vec<(u32, i32)> = Vec::new();
+        for offset in self.composition_offsets() {
+            let offset = offset as i32;
+            match entries.last_mut() {
+                Some(last) if last.1 == offset => last.0 += 1,
+                _ => entries.push((1, offset)),
+            }
+        }
+        entries
+    }
+
+    // ----- cslg -----
+
+    /// composition offset に負の値がある場合に、復号時刻と表示時刻の関係を cslg に書く
+    /// （ISO/IEC 14496-12 8.6.1.4）。表示時刻に compositionToDTSShift を足すと
+    /// どのサンプルも復号時刻以降に表示される。負の値がなければ書かない。
+    fn write_cslg(&mut self) -> io::Result<()> {
+        let Some(cslg) = self.composition_shift() else {
+            return Ok(());
+        };
+        self.write_box(b"cslg", |s| {
+            s.writer.write_all(&0u32.to_be_bytes())?;                  // version=0, flags=0
+            s.writer.write_all(&cslg.shift.to_be_bytes())?;           // compositionToDTSShift
+            s.writer.write_all(&cslg.least.to_be_bytes())?;           // leastDecodeToDisplayDelta
+            s.writer.write_all(&cslg.greatest.to_be_bytes())?;        // greatestDecodeToDisplayDelta
+            s.writer.write_all(&cslg.start.to_be_bytes())?;           // compositionStartTime
+            s.writer.write_all(&cslg.end.to_be_bytes())?;             // compositionEndTime
+            Ok(())
+        })?;
+        Ok(())
+    }
+
     // ----- stss -----
 
     /// キーフレーム（IDR）のサンプル番号（1-based）を書く。
@@ -590,6 +799,118 @@ impl Mp4Writer {
 // ============================================================
 
 impl Mp4Writer {
+    /// 先頭サンプルを 0 とした各サンプルの表示時刻（RTP タイムスタンプの折り返しを展開する）。
+    fn presentation_times(&self) -> Vec<i64> {
+        let mut times = Vec::with_capacity(self.samples.len());
+        let mut t = 0i64;
+        for (i, s) in self.samples.iter().enumerate() {
+            if i > 0 {
+                // B フレームでは前のサンプルより戻ることがあるので符号付きの差分
+                t += s.pts.wrapping_sub(self.samples[i - 1].pts) as i32 as i64;
+            }
+            times.push(t);
+        }
+        times
+    }
+
+    /// 各サンプルの復号時刻（presentation_times() と同じ基準）。
+    ///
+    /// サンプルは受信した順（復号順）に並んでいるので、i 番目のサンプルには表示時刻を
+    /// 小さい順に並べた i 番目の値を割り当てる。最初の復号時刻と最初に表示される時刻が
+    /// どちらも 0 になり、B フレームの composition offset は負になる（ctts version 1 と cslg）。
+    /// 同じ時刻が続く場合も stts の差分が 0 にならないよう 1 ずつ進める。
+    fn decode_times(&self) -> Vec<i64> {
+        let mut dts = self.presentation_times();
+        dts.sort_unstable();
+        for i in 1..dts.len() {
+            if dts[i] <= dts[i - 1] {
+                dts[i] = dts[i - 1] + 1;
+            }
+        }
+        dts
+    }
+
+    /// 表示時刻（RTP タイムスタンプ）の前後が POC の前後と食い違うサンプルの数。
+    /// ctts は表示時刻から作るので、カメラのタイムスタンプが壊れていると表示順が崩れる。
+    /// POC は IDR ごとに基準が戻るので、同じ IDR 区間の直前 16 サンプル（DPB の最大）とだけ比べる。
+    fn presentation_order_mismatches(&self) -> usize {
+        let pts = self.presentation_times();
+        let mut idr = 0;
+        let mut count = 0;
+        for (i, s) in self.samples.iter().enumerate() {
+            if s.is_keyframe {
+                idr = i;
+            }
+            let Some(poc) = s.poc else {
+                continue;
+            };
+            let from = idr.max(i.saturating_sub(16));
+            let mismatch = (from..i).any(|j| match self.samples[j].poc {
+                Some(prev) if prev != poc => (prev < poc) != (pts[j] < pts[i]),
+                _ => false,
+            });
+            count += mismatch as usize;
+        }
+        count
+    }
+
+    /// 各サンプルの composition offset（表示時刻 - 復号時刻）
+    fn composition_offsets(&self) -> Vec<i64> {
+        let pts = self.presentation_times();
+        let dts = self.decode_times();
+        pts.iter().zip(&dts).map(|(p, d)| p - d).collect()
+    }
+
+    /// composition offset に負の値があれば cslg の値を求める（時刻は先頭サンプルの復号時刻を 0 とする）。
+    fn composition_shift(&self) -> Option<CompositionShift> {
+        let offsets = self.composition_offsets();
+        let least = *offsets.iter().min()?;
+        if least >= 0 {
+            return None;
+        }
+        let greatest = *offsets.iter().max()?;
+        let pts = self.presentation_times();
+        let dts0 = *self.decode_times().first()?;
+        let start = pts.iter().min()? - dts0;
+        Some(CompositionShift {
+            shift: (-least) as i32,
+            least: least as i32,
+            greatest: greatest as i32,
+            start: start as i32,
+            end: (start + self.presentation_duration_ticks()) as i32,
+        })
+    }
+
+    /// 表示される範囲の長さ（timescale 単位）。最後に表示されるフレームの長さも含める。
+    fn presentation_duration_ticks(&self) -> i64 {
+        let pts = self.presentation_times();
+        match (pts.iter().min(), pts.iter().max()) {
+            (Some(&first), Some(&last)) => last - first + self.last_sample_duration() as i64,
+            _ => 0,
+        }
+    }
+
+    /// SPS の VUI（あれば）
+    fn vui(&self) -> Option<&h264::VuiParameters> {
+        self.sps_info.as_ref()?.vui.as_ref()
+    }
+
+    /// 最終サンプルの長さ（次のサンプルがないので DTS 差分が取れない）。
+    /// VUI のフレームレートがあればそれを、なければ1つ前の差分を使う。
+    fn last_sample_duration(&self) -> u32 {
+        if let Some(duration) = self.vui().and_then(|v| v.frame_duration(self.timescale)) {
+            return duration;
+        }
+        let dts = self.decode_times();
+        let n = dts.len();
+        if n >= 2 {
+            (dts[n - 1] - dts[n - 2]) as u32
+        } else {
+            // フレームが1枚だけ: 30fps想定のフォールバック
+            self.timescale / 30
+        }
+    }
+
     /// 総再生時間（timescale 単位）。
     /// 最終フレームのdeltaを加算して最終フレーム分の尺も含める。
     fn calc_duration_ticks(&self) -> u32 {
@@ -597,21 +918,177 @@ impl Mp4Writer {
         if n == 0 {
             return 0;
         }
-        let last_delta = if n >= 2 {
-            self.samples[n - 1].dts.wrapping_sub(self.samples[n - 2].dts)
-        } else {
-            3000
-        };
-        self.samples[n - 1]
-            .dts
-            .wrapping_sub(self.samples[0].dts)
-            .wrapping_add(last_delta)
+        let dts = self.decode_times();
+        let last_delta = self.last_sample_duration();
+        ((dts[n - 1] - dts[0]) as u32).wrapping_add(last_delta)
     }
 
     /// 総再生時間（ミリ秒）。mvhd / tkhd の duration フィールド用。
     fn calc_duration_ms(&self) -> u32 {
-        let ticks = self.calc_duration_ticks() as u64;
+        let ticks = self.presentation_duration_ticks().max(0) as u64;
         (ticks * 1000 / self.timescale as u64) as u32
     }
 }
 
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Dahua の sprop-parameter-sets（VUI に timing_info なし）
+    const SPS: &str = "Z2QAH6wsaoFAFumoCAgIEA==";
+    const PPS: &str = "aO48sA==";
+
+    fn temp_writer(name: &str) -> (Mp4Writer, std::path::PathBuf) {
+        let path = std::env::temp_dir().join(format!("rtsp-client-test-{}-{}.mp4", std::process::id(), name));
+        let mut writer = Mp4Writer::new(File::create(&path).unwrap(), 1280, 720);
+        writer.write_header().unwrap();
+        let sps = h264::parse_sprop_parameter_sets(SPS).remove(0);
+        let pps = h264::parse_sprop_parameter_sets(PPS).remove(0);
+        writer.set_sps_pps(sps, pps);
+        (writer, path)
+    }
+
+    /// (RTP タイムスタンプ, POC, IDR か) を復号順に書き込む
+    fn write_samples(writer: &mut Mp4Writer, samples: &[(u32, Option<i32>, bool)]) {
+        for &(pts, poc, key) in samples {
+            writer.write_sample(&[vec![0x65, 0x88, 0x84]], pts, poc, key, None).unwrap();
+        }
+    }
+
+    /// ファイル中の箱の本体（サイズと種類の後ろ）を返す
+    fn find_box<'a>(data: &'a [u8], name: &[u8; 4]) -> Option<&'a [u8]> {
+        let pos = data.windows(4).position(|w| w == name)?;
+        let size = u32::from_be_bytes(data[pos - 4..pos].try_into().unwrap()) as usize;
+        Some(&data[pos + 4..pos - 4 + size])
+    }
+
+    #[test]
+    fn i_p_b_b_ctts_and_cslg() {
+        let (mut writer, path) = temp_writer("ipbb");
+        // 表示順 I0 B1 B2 P3 B4 B5 P6 を I0 P3 B1 B2 P6 B4 B5 の順に復号する（1フレーム 3000）
+        let base = 0xFFFF_F000u32; // RTP タイムスタンプの折り返しをまたぐ
+        let frames = [(0, 0, true), (3, 6, false), (1, 2, false), (2, 4, false), (6, 12, false), (4, 8, false), (5, 10, false)];
+        let samples: Vec<_> = frames.iter()
+            .map(|&(n, poc, key)| (base.wrapping_add(n * 3000), Some(poc), key))
+            .collect();
+        write_samples(&mut writer, &samples);
+
+        let pts = writer.presentation_times();
+        let dts = writer.decode_times();
+        assert_eq!(pts, vec![0, 9000, 3000, 6000, 18000, 12000, 15000]);
+        assert_eq!(dts, vec![0, 3000, 6000, 9000, 12000, 15000, 18000]);
+        assert_eq!(writer.build_stts_entries(), vec![(7, 3000)]);
+        assert_eq!(writer.build_ctts_entries(), vec![(1, 0), (1, 6000), (2, -3000), (1, 6000), (2, -3000)]);
+
+        let cslg = writer.composition_shift().unwrap();
+        assert_eq!(cslg, CompositionShift { shift: 3000, least: -3000, greatest: 6000, start: 0, end: 21000 });
+        // cslg の分だけ表示を遅らせれば、どのサンプルも復号してから表示される
+        for (p, d) in pts.iter().zip(&dts) {
+            assert!(d <= &(p + cslg.shift as i64), "dts {} > pts {} + shift", d, p);
+        }
+        assert_eq!(writer.presentation_order_mismatches(), 0);
+
+        writer.finalize().unwrap();
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let ctts = find_box(&data, b"ctts").unwrap();
+        assert_eq!(ctts[0], 1, "ctts must be version 1");
+        assert_eq!(u32::from_be_bytes(ctts[4..8].try_into().unwrap()), 5);
+        let cslg = find_box(&data, b"cslg").unwrap();
+        assert_eq!(i32::from_be_bytes(cslg[4..8].try_into().unwrap()), 3000);
+        assert!(find_box(&data, b"edts").is_none());
+    }
+
+    #[test]
+    fn no_reordering_writes_no_ctts() {
+        let (mut writer, path) = temp_writer("ipp");
+        write_samples(&mut writer, &[(1000, Some(0), true), (4000, Some(2), false), (7000, Some(4), false)]);
+        assert_eq!(writer.decode_times(), writer.presentation_times());
+        assert_eq!(writer.build_stts_entries(), vec![(3, 3000)]);
+        assert!(writer.composition_shift().is_none());
+
+        writer.finalize().unwrap();
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(find_box(&data, b"ctts").is_none());
+        assert!(find_box(&data, b"cslg").is_none());
+    }
+
+    #[test]
+    fn equal_timestamps_keep_stts_positive() {
+        let (mut writer, path) = temp_writer("equal");
+        write_samples(&mut writer, &[(0, None, true), (3000, None, false), (3000, None, false), (6000, None, false)]);
+        let stts = writer.build_stts_entries();
+        assert!(stts.iter().all(|&(_, delta)| delta >= 1), "{:?}", stts);
+        assert_eq!(writer.decode_times(), vec![0, 3000, 3001, 6000]);
+        // 1 だけ進めたサンプルは表示時刻より復号時刻が後になるので cslg で補う
+        assert_eq!(writer.composition_shift().map(|c| c.shift), Some(1));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn poc_order_mismatch_is_detected() {
+        let (mut writer, path) = temp_writer("mismatch");
+        // POC では P が後なのに、RTP タイムスタンプが戻っている
+        write_samples(&mut writer, &[(3000, Some(0), true), (6000, Some(4), false), (0, Some(8), false)]);
+        assert_eq!(writer.presentation_order_mismatches(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn vui_writes_pasp_colr_and_last_duration() {
+        let (mut writer, path) = temp_writer("vui");
+        // aspect_ratio_idc = 255 (4:3)、BT.470BG / BT.2100 PQ、timing_info 1/50
+        let vui = h264::VuiParameters {
+            aspect_ratio_info: Some(h264::AspectRatioInfo { aspect_ratio_idc: 255, sar_width: 4, sar_height: 3 }),
+            overscan_appropriate: None,
+            video_signal_type: Some(h264::VideoSignalType {
+                video_format: 5,
+                video_full_range_flag: true,
+                colour_description: Some(h264::ColourDescription {
+                    colour_primaries: 5,
+                    transfer_characteristics: 16,
+                    matrix_coefficients: 6,
+                }),
+            }),
+            chroma_sample_loc_type: None,
+            timing_info: Some(h264::TimingInfo { num_units_in_tick: 1, time_scale: 50, fixed_frame_rate_flag: true }),
+            nal_hrd_parameters: None,
+            vcl_hrd_parameters: None,
+            low_delay_hrd_flag: false,
+            pic_struct_present_flag: false,
+            bitstream_restriction: None,
+        };
+        writer.sps_info.as_mut().unwrap().vui = Some(vui);
+        write_samples(&mut writer, &[(0, None, true), (3000, None, false)]);
+        // 最終サンプルの長さは VUI の timing_info（25fps = 3600）から取る
+        assert_eq!(writer.last_sample_duration(), 3600);
+        assert_eq!(writer.build_stts_entries(), vec![(1, 3000), (1, 3600)]);
+
+        writer.finalize().unwrap();
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let pasp = find_box(&data, b"pasp").unwrap();
+        assert_eq!(pasp, [0, 0, 0, 4, 0, 0, 0, 3]);
+        let colr = find_box(&data, b"colr").unwrap();
+        assert_eq!(colr, [b'n', b'c', b'l', b'x', 0, 5, 0, 16, 0, 6, 0x80]);
+    }
+
+    #[test]
+    fn without_timing_info_uses_measured_interval() {
+        let (mut writer, path) = temp_writer("no-timing");
+        write_samples(&mut writer, &[(0, None, true), (3000, None, false), (6000, None, false)]);
+        assert_eq!(writer.last_sample_duration(), 3000);
+        assert_eq!(writer.build_stts_entries(), vec![(3, 3000)]);
+
+        writer.finalize().unwrap();
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        // Dahua の VUI は aspect_ratio_info がなく、video_signal_type（BT.709、limited range）だけ
+        assert!(find_box(&data, b"pasp").is_none());
+        let colr = find_box(&data, b"colr").unwrap();
+        assert_eq!(colr, [b'n', b'c', b'l', b'x', 0, 1, 0, 1, 0, 1, 0]);
+    }
+}
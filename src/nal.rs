@@ -3,6 +3,11 @@ pub enum NalEvent<'a> {
     Video { data: &'a [u8], ts: u32, is_key: bool, },
     Sps(&'a [u8]),
     Pps(&'a [u8]),
-    Sei,
+    Sei { data: &'a [u8], ts: u32, },
+    Aud { data: &'a [u8], ts: u32, },
     End,
+    /// RTP のマーカービット（アクセスユニットの最後のパケット）
+    AccessUnitEnd,
+    /// RTP パケットの欠落で NAL ユニットが失われた
+    Loss,
 }
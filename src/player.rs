@@ -1,7 +1,13 @@
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
+use std::sync::Arc;
 use std::thread;
 use eframe::egui;
 use openh264::decoder::Decoder;
+use crate::h264;
+use crate::h264_depacketizer::H264Depacketizer;
+use crate::nal::NalEvent;
+use crate::session::{SessionOptions, StreamSession};
 
 // ============================================================
 // GUI アプリ
@@ -14,6 +20,8 @@ struct PlayerApp {
     texture: Option<egui::TextureHandle>,
     /// フレームの幅・高さ（アスペクト比計算用）
     frame_size: [usize; 2],
+    /// Ctrl+C で false になったらウィンドウを閉じる
+    running: Arc<AtomicBool>,
 }
 
 impl eframe::App for PlayerApp {
@@ -57,6 +65,11 @@ impl eframe::App for PlayerApp {
                 }
             });
 
+        // Ctrl+C が押されたらウィンドウを閉じる（受信スレッドは TEARDOWN して終わる）
+        if !self.running.load(Ordering::SeqCst) {
+            ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+        }
+
         // 新フレームが届いた際にすぐ再描画されるよう常にリペイントを要求
         ctx.request_repaint();
     }
@@ -66,13 +79,17 @@ impl eframe::App for PlayerApp {
 // エントリポイント
 // ============================================================
 
-pub fn run_player(rtsp_url: String) {
+/// `running` は Ctrl+C ハンドラと共有するフラグ。false になると再生を終える。
+pub fn run_player(rtsp_url: String, session_options: SessionOptions, running: Arc<AtomicBool>) {
     // バックグラウンドスレッドとのチャンネル（バッファ 2 フレーム）
     // GUI が処理しきれない場合は古いフレームを捨てる
     let (tx, rx) = mpsc::sync_channel::<(Vec<u8>, usize, usize)>(2);
 
-    thread::spawn(move || {
-        rtp_decode_loop(rtsp_url, tx);
+    // ウィンドウを閉じたら受信ループを止めて TEARDOWN させる
+    let r = running.clone();
+    let app_running = running.clone();
+    let handle = thread::spawn(move || {
+        rtp_decode_loop(rtsp_url, session_options, r, tx);
     });
 
     let options = eframe::NativeOptions {
@@ -89,19 +106,28 @@ pub fn run_player(rtsp_url: String) {
             Ok(Box::new(PlayerApp {
                 rx,
                 texture: None,
+                running: app_running,
                 frame_size: [1280, 720],
             }))
         }),
     ) {
         eprintln!("eframe error: {}", e);
     }
+
+    running.store(false, Ordering::SeqCst);
+    let _ = handle.join();
 }
 
 // ============================================================
 // RTP受信・デコードループ（バックグラウンドスレッド）
 // ============================================================
 
-fn rtp_decode_loop(rtsp_url: String, tx: mpsc::SyncSender<(Vec<u8>, usize, usize)>) {
+fn rtp_decode_loop(
+    rtsp_url: String,
+    session_options: SessionOptions,
+    running: Arc<AtomicBool>,
+    tx: mpsc::SyncSender<(Vec<u8>, usize, usize)>,
+) {
     let api = openh264::OpenH264API::from_source();
     let mut decoder = match Decoder::new(api) {
         Ok(d) => d,
@@ -111,53 +137,54 @@ fn rtp_decode_loop(rtsp_url: String, tx: mpsc::SyncSender<(Vec<u8>, usize, usize
         }
     };
 
-    let rtp_receiver = crate::rtp::RTPReceiver::new();
-    let rtp_port = rtp_receiver.get_rtp_port();
-    println!("RTP port: {}", rtp_port);
-
-    let mut client = match crate::rtsp_client::RTSPClient::new(rtsp_url, rtp_port) {
-        Ok(c) => c,
+    let mut session = match StreamSession::open(&rtsp_url, &session_options) {
+        Ok(s) => s,
         Err(e) => {
-            eprintln!("Failed to connect RTSP: {}", e);
+            eprintln!("Failed to start RTSP session: {}", e);
             return;
         }
     };
 
-    client.options().ok();
-    client.describe().ok();
-    client.setup_track1().ok();
-    client.setup_track2().ok();
-    client.play().ok();
-    println!("RTSP PLAY sent, waiting for stream...");
+    let mut depacketizer = H264Depacketizer::new();
+    depacketizer.set_interleaving_depth(session.video_interleaving_depth());
 
-    let mut fragment_buf: Vec<u8> = Vec::new();
-
-    loop {
-        let (_header, payload) = match rtp_receiver.receive() {
+    'receive: while running.load(Ordering::SeqCst) {
+        let (header, payload) = match session.receive() {
             Ok(r) => r,
-            Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut => continue,
+            // タイムアウト・再接続中
+            Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut || e.kind() == std::io::ErrorKind::NotConnected => continue,
             Err(e) => {
                 eprintln!("RTP receive error: {}", e);
                 break;
             }
         };
 
-        if payload.is_empty() {
-            continue;
+        if session.take_reconnected() {
+            // 並べ替え待ちの NAL ユニットを先にデコーダに渡す
+            for ev in depacketizer.flush() {
+                if let Some(frame) = decode_to_rgb(&mut decoder, ev) {
+                    let _ = tx.try_send(frame);
+                }
+            }
+            depacketizer.reset();
+            depacketizer.set_interleaving_depth(session.video_interleaving_depth());
         }
 
-        if let Some((rgb, w, h)) = decode_to_rgb(&mut decoder, &mut fragment_buf, &payload) {
+        for ev in depacketizer.push(&header, &payload) {
+            let Some((rgb, w, h)) = decode_to_rgb(&mut decoder, ev) else {
+                continue;
+            };
             match tx.try_send((rgb, w, h)) {
                 Ok(_) => {}
                 // チャンネルが満杯 → フレームを捨てる（表示遅延防止）
                 Err(mpsc::TrySendError::Full(_)) => {}
                 // ウィンドウが閉じた → ループ終了
-                Err(mpsc::TrySendError::Disconnected(_)) => break,
+                Err(mpsc::TrySendError::Disconnected(_)) => break 'receive,
             }
         }
     }
 
-    client.shutdown();
+    session.shutdown();
     println!("Player stopped.");
 }
 
@@ -165,52 +192,26 @@ fn rtp_decode_loop(rtsp_url: String, tx: mpsc::SyncSender<(Vec<u8>, usize, usize
 // NALユニット → RGB 変換
 // ============================================================
 
-fn decode_to_rgb(
-    decoder: &mut Decoder,
-    fragment_buf: &mut Vec<u8>,
-    payload: &[u8],
-) -> Option<(Vec<u8>, usize, usize)> {
-    let nal_header = payload[0];
-    let nal_unit_type = nal_header & 0x1F;
-
-    // デコーダに渡すデータを組み立てる
-    let nal_data: Option<Vec<u8>> = match nal_unit_type {
-        // Single NAL unit（SPS / PPS / IDR / Non-IDR）
-        crate::rtp::NAL_UNIT_TYPE_SPS
-        | crate::rtp::NAL_UNIT_TYPE_PPS
-        | crate::rtp::NAL_UNIT_TYPE_IDR
-        | crate::rtp::NAL_UNIT_TYPE_NON_IDR => {
-            let mut d = vec![0x00, 0x00, 0x00, 0x01];
-            d.extend_from_slice(payload);
-            Some(d)
-        }
-        // FU-A（フラグメント）
-        28 => {
-            if payload.len() < 2 {
-                return None;
-            }
-            let fu_header = payload[1];
-            let start_bit      = (fu_header >> 7) & 0x01;
-            let end_bit        = (fu_header >> 6) & 0x01;
-            let fu_nal_unit_type = fu_header & 0x1F;
-            let fu_nal_header   = (nal_header & 0xE0) | fu_nal_unit_type;
-
-            if start_bit == 1 {
-                fragment_buf.clear();
-                fragment_buf.extend_from_slice(&[0x00, 0x00, 0x00, 0x01, fu_nal_header]);
-            }
-            fragment_buf.extend_from_slice(&payload[2..]);
-
-            if end_bit == 1 {
-                Some(fragment_buf.clone())
-            } else {
-                None // まだ組み立て中
+fn decode_to_rgb(decoder: &mut Decoder, ev: NalEvent) -> Option<(Vec<u8>, usize, usize)> {
+    // SPS / PPS / IDR / Non-IDR をスタートコード付きでデコーダに渡す
+    let nal = match ev {
+        NalEvent::Sps(data) => {
+            // openh264 は 8bit 4:2:0 しか復号できない
+            match h264::Sps::parse(data) {
+                Ok(sps) if sps.chroma_format_idc != 1 || sps.bit_depth_luma() != 8 => {
+                    eprintln!("Unsupported stream for decoder: {} profile, chroma_format_idc={}, bit depth {}",
+                        sps.profile_name(), sps.chroma_format_idc, sps.bit_depth_luma());
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("Failed to parse SPS: {}", e),
             }
+            data
         }
+        NalEvent::Pps(data) | NalEvent::Video { data, .. } => data,
         _ => return None,
     };
-
-    let nal_data = nal_data?;
+    let mut nal_data = vec![0x00, 0x00, 0x00, 0x01];
+    nal_data.extend_from_slice(nal);
 
     match decoder.decode(&nal_data) {
         Ok(Some(yuv)) => {
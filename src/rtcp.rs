@@ -0,0 +1,714 @@
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crate::rtp::RTPHeader;
+
+pub const RTCP_PT_SR: u8 = 200;
+pub const RTCP_PT_RR: u8 = 201;
+pub const RTCP_PT_SDES: u8 = 202;
+pub const RTCP_PT_BYE: u8 = 203;
+
+const SDES_END: u8 = 0;
+const SDES_CNAME: u8 = 1;
+
+/// Receiver Report を送る間隔（RFC 3550 6.2 の最小間隔 5 秒）
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// これ以上 sequence_number が進んだら送信側がリセットされたとみなす（RFC 3550 A.1）
+const MAX_DROPOUT: u16 = 3000;
+const MAX_MISORDER: u16 = 100;
+
+// ============================================================
+// データ構造
+// ============================================================
+
+/// SR / RR に含まれる受信レポートブロック
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReportBlock {
+    pub ssrc: u32,
+    /// 前回のレポート以降の損失率（1/256 単位）
+    pub fraction_lost: u8,
+    /// 累積損失数（24bit 符号付き）
+    pub cumulative_lost: i32,
+    /// 拡張最大シーケンス番号（上位 16bit は折り返し回数）
+    pub highest_seq: u32,
+    /// 到着間隔ジッタ（RTP タイムスタンプ単位）
+    pub jitter: u32,
+    /// 最後に受け取った SR の NTP タイムスタンプの中央 32bit
+    pub last_sr: u32,
+    /// 最後の SR を受け取ってからの経過時間（1/65536 秒単位）
+    pub delay_since_last_sr: u32,
+}
+
+/// Sender Report (PT=200)
+#[derive(Debug, Clone, PartialEq)]
+pub struct SenderReport {
+    pub ssrc: u32,
+    /// NTP タイムスタンプ（上位 32bit が 1900 年からの秒、下位 32bit が小数部）
+    pub ntp_timestamp: u64,
+    /// ntp_timestamp と同じ時刻を表す RTP タイムスタンプ
+    pub rtp_timestamp: u32,
+    pub packet_count: u32,
+    pub octet_count: u32,
+    pub reports: Vec<ReportBlock>,
+}
+
+/// SDES のチャンク（SSRC ごとの項目）
+#[derive(Debug, Clone, PartialEq)]
+pub struct SdesChunk {
+    pub ssrc: u32,
+    /// (項目の種別, 値)。CNAME は種別 1
+    pub items: Vec<(u8, String)>,
+}
+
+/// RTCP パケット
+#[derive(Debug, Clone, PartialEq)]
+pub enum RtcpPacket {
+    SenderReport(SenderReport),
+    ReceiverReport { ssrc: u32, reports: Vec<ReportBlock> },
+    SourceDescription(Vec<SdesChunk>),
+    Goodbye { ssrcs: Vec<u32>, reason: Option<String> },
+    /// APP や XR など解析しないパケット
+    Other { packet_type: u8 },
+}
+
+#[derive(Debug)]
+pub struct RtcpError {
+    pub message: String,
+}
+
+impl fmt::Display for RtcpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RTCP: {}", self.message)
+    }
+}
+
+impl std::error::Error for RtcpError {}
+
+fn err(message: &str) -> RtcpError {
+    RtcpError { message: message.to_string() }
+}
+
+// ============================================================
+// 解析
+// ============================================================
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+fn parse_report_blocks(data: &[u8], count: usize) -> Result<Vec<ReportBlock>, RtcpError> {
+    if data.len() < count * 24 {
+        return Err(err("report blocks exceed packet length"));
+    }
+    let blocks = (0..count)
+        .map(|i| {
+            let b = &data[i * 24..];
+            // 24bit の符号付き整数を符号拡張する
+            let lost = ((read_u32(b, 4) << 8) as i32) >> 8;
+            ReportBlock {
+                ssrc: read_u32(b, 0),
+                fraction_lost: b[4],
+                cumulative_lost: lost,
+                highest_seq: read_u32(b, 8),
+                jitter: read_u32(b, 12),
+                last_sr: read_u32(b, 16),
+                delay_since_last_sr: read_u32(b, 20),
+            }
+        })
+        .collect();
+    Ok(blocks)
+}
+
+fn parse_sdes(body: &[u8], count: usize) -> Result<Vec<SdesChunk>, RtcpError> {
+    let mut chunks = Vec::new();
+    let mut offset = 0;
+    for _ in 0..count {
+        if offset + 4 > body.len() {
+            return Err(err("SDES chunk exceeds packet length"));
+        }
+        let ssrc = read_u32(body, offset);
+        offset += 4;
+        let mut items = Vec::new();
+        loop {
+            let item_type = *body.get(offset).ok_or_else(|| err("SDES item exceeds packet length"))?;
+            if item_type == SDES_END {
+                // チャンクは 32bit 境界まで 0 で埋められる
+                offset = (offset + 4) & !3;
+                break;
+            }
+            let len = *body.get(offset + 1).ok_or_else(|| err("SDES item exceeds packet length"))? as usize;
+            let value = body.get(offset + 2..offset + 2 + len).ok_or_else(|| err("SDES item exceeds packet length"))?;
+            items.push((item_type, String::from_utf8_lossy(value).into_owned()));
+            offset += 2 + len;
+        }
+        chunks.push(SdesChunk { ssrc, items });
+    }
+    Ok(chunks)
+}
+
+fn parse_bye(body: &[u8], count: usize) -> Result<RtcpPacket, RtcpError> {
+    if body.len() < count * 4 {
+        return Err(err("BYE SSRC list exceeds packet length"));
+    }
+    let ssrcs = (0..count).map(|i| read_u32(body, i * 4)).collect();
+    let reason = body.get(count * 4).and_then(|&len| {
+        body.get(count * 4 + 1..count * 4 + 1 + len as usize)
+            .map(|r| String::from_utf8_lossy(r).into_owned())
+    });
+    Ok(RtcpPacket::Goodbye { ssrcs, reason })
+}
+
+/// 複合 RTCP パケット（1つの UDP データグラム / $ フレーム）を解析する。
+pub fn parse_compound(mut data: &[u8]) -> Result<Vec<RtcpPacket>, RtcpError> {
+    let mut packets = Vec::new();
+    while !data.is_empty() {
+        if data.len() < 4 {
+            return Err(err("truncated header"));
+        }
+        if data[0] >> 6 != 2 {
+            return Err(err(&format!("unsupported version {}", data[0] >> 6)));
+        }
+        let padding = (data[0] >> 5) & 0x01 == 1;
+        let count = (data[0] & 0x1F) as usize;
+        let packet_type = data[1];
+        let length = (u16::from_be_bytes([data[2], data[3]]) as usize + 1) * 4;
+        if length > data.len() {
+            return Err(err("packet length exceeds datagram"));
+        }
+
+        let mut body = &data[4..length];
+        if padding {
+            let pad = *body.last().unwrap_or(&0) as usize;
+            if pad > body.len() {
+                return Err(err("invalid padding"));
+            }
+            body = &body[..body.len() - pad];
+        }
+
+        let packet = match packet_type {
+            RTCP_PT_SR => {
+                if body.len() < 24 {
+                    return Err(err("truncated SR"));
+                }
+                RtcpPacket::SenderReport(SenderReport {
+                    ssrc: read_u32(body, 0),
+                    ntp_timestamp: ((read_u32(body, 4) as u64) << 32) | read_u32(body, 8) as u64,
+                    rtp_timestamp: read_u32(body, 12),
+                    packet_count: read_u32(body, 16),
+                    octet_count: read_u32(body, 20),
+                    reports: parse_report_blocks(&body[24..], count)?,
+                })
+            }
+            RTCP_PT_RR => {
+                if body.len() < 4 {
+                    return Err(err("truncated RR"));
+                }
+                RtcpPacket::ReceiverReport {
+                    ssrc: read_u32(body, 0),
+                    reports: parse_report_blocks(&body[4..], count)?,
+                }
+            }
+            RTCP_PT_SDES => RtcpPacket::SourceDescription(parse_sdes(body, count)?),
+            RTCP_PT_BYE => parse_bye(body, count)?,
+            _ => RtcpPacket::Other { packet_type },
+        };
+        packets.push(packet);
+        data = &data[length..];
+    }
+    Ok(packets)
+}
+
+// ============================================================
+// 組み立て
+// ============================================================
+
+/// RTCP ヘッダを書き、body を続けて返す。body は 32bit 境界に揃っていること。
+fn build_packet(count: u8, packet_type: u8, body: &[u8]) -> Vec<u8> {
+    let length = (body.len() / 4) as u16;
+    let mut packet = Vec::with_capacity(4 + body.len());
+    packet.push(0x80 | (count & 0x1F));
+    packet.push(packet_type);
+    packet.extend_from_slice(&length.to_be_bytes());
+    packet.extend_from_slice(body);
+    packet
+}
+
+fn build_receiver_report(ssrc: u32, reports: &[ReportBlock]) -> Vec<u8> {
+    let mut body = ssrc.to_be_bytes().to_vec();
+    for r in reports {
+        body.extend_from_slice(&r.ssrc.to_be_bytes());
+        let lost = (r.cumulative_lost.clamp(-0x80_0000, 0x7F_FFFF) as u32) & 0xFF_FFFF;
+        body.extend_from_slice(&(((r.fraction_lost as u32) << 24) | lost).to_be_bytes());
+        body.extend_from_slice(&r.highest_seq.to_be_bytes());
+        body.extend_from_slice(&r.jitter.to_be_bytes());
+        body.extend_from_slice(&r.last_sr.to_be_bytes());
+        body.extend_from_slice(&r.delay_since_last_sr.to_be_bytes());
+    }
+    build_packet(reports.len() as u8, RTCP_PT_RR, &body)
+}
+
+fn build_sdes_cname(ssrc: u32, cname: &str) -> Vec<u8> {
+    let cname = &cname.as_bytes()[..cname.len().min(255)];
+    let mut body = ssrc.to_be_bytes().to_vec();
+    body.push(SDES_CNAME);
+    body.push(cname.len() as u8);
+    body.extend_from_slice(cname);
+    // 終端の 0 を含めて 32bit 境界まで埋める
+    body.push(SDES_END);
+    while !body.len().is_multiple_of(4) {
+        body.push(0);
+    }
+    build_packet(1, RTCP_PT_SDES, &body)
+}
+
+fn build_bye(ssrc: u32) -> Vec<u8> {
+    build_packet(1, RTCP_PT_BYE, &ssrc.to_be_bytes())
+}
+
+/// 1900-01-01（NTP の起点）から 1970-01-01 までの秒数
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+/// NTP タイムスタンプ（1900 年起点の 32.32 固定小数点）を SystemTime に変換する。
+pub fn ntp_to_system_time(ntp: u64) -> SystemTime {
+    let secs = (ntp >> 32).saturating_sub(NTP_UNIX_OFFSET);
+    let nanos = ((ntp & 0xFFFF_FFFF) * 1_000_000_000) >> 32;
+    UNIX_EPOCH + Duration::new(secs, nanos as u32)
+}
+
+/// SystemTime を NTP タイムスタンプ形式に変換する。
+pub fn system_time_to_ntp(time: SystemTime) -> u64 {
+    let since_unix = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_unix.as_secs() + NTP_UNIX_OFFSET;
+    let frac = ((since_unix.subsec_nanos() as u64) << 32) / 1_000_000_000;
+    (secs << 32) | frac
+}
+
+/// Sender Report から得た RTP タイムスタンプと壁時計の対応。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockReference {
+    pub rtp_timestamp: u32,
+    /// rtp_timestamp の時点の送信側の壁時計
+    pub wall_clock: SystemTime,
+    pub clock_rate: u32,
+}
+
+impl ClockReference {
+    /// RTP タイムスタンプに対応する壁時計の時刻（撮影時刻）を返す。
+    pub fn wall_clock_at(&self, rtp_ts: u32) -> SystemTime {
+        // SR の前後どちらにもなり得るので符号付きの差分で計算する
+        let ticks = rtp_ts.wrapping_sub(self.rtp_timestamp) as i32;
+        let offset = Duration::from_secs_f64(ticks.unsigned_abs() as f64 / self.clock_rate as f64);
+        if ticks >= 0 {
+            self.wall_clock + offset
+        } else {
+            self.wall_clock - offset
+        }
+    }
+}
+
+// ============================================================
+// 受信統計（RFC 3550 Appendix A）
+// ============================================================
+
+/// 送信元1つ分の受信統計
+struct ReceptionStats {
+    ssrc: u32,
+    base_seq: u32,
+    max_seq: u16,
+    /// 折り返し回数 << 16
+    cycles: u32,
+    received: u32,
+    expected_prior: u32,
+    received_prior: u32,
+    /// 直前のパケットの (到着時刻 - RTP タイムスタンプ)（RTP タイムスタンプ単位、32bit で折り返す）
+    transit: Option<u32>,
+    jitter: f64,
+}
+
+impl ReceptionStats {
+    fn new(ssrc: u32, seq: u16) -> Self {
+        Self {
+            ssrc,
+            base_seq: seq as u32,
+            max_seq: seq,
+            cycles: 0,
+            received: 0,
+            expected_prior: 0,
+            received_prior: 0,
+            transit: None,
+            jitter: 0.0,
+        }
+    }
+
+    fn extended_max(&self) -> u32 {
+        self.cycles.wrapping_add(self.max_seq as u32)
+    }
+
+    /// RTP パケットを1つ受信した。`arrival` は到着時刻を RTP タイムスタンプ単位にしたもの。
+    fn update(&mut self, seq: u16, rtp_ts: u32, arrival: i64) {
+        let udelta = seq.wrapping_sub(self.max_seq);
+        if udelta < MAX_DROPOUT {
+            // 順番どおり（欠落を含む）
+            if seq < self.max_seq {
+                self.cycles = self.cycles.wrapping_add(1 << 16);
+            }
+            self.max_seq = seq;
+        } else if udelta <= u16::MAX - MAX_MISORDER {
+            // 大きく飛んだので送信側がリセットされたとみなす
+            *self = ReceptionStats::new(self.ssrc, seq);
+        }
+        // それ以外は重複か順序の入れ替わり
+        self.received += 1;
+
+        // 到着間隔ジッタ（RFC 3550 A.8）。RTP タイムスタンプの折り返しをまたいでも
+        // 差が正しくなるように 32bit の剰余で引き、符号付きにしてから絶対値を取る
+        let transit = (arrival as u32).wrapping_sub(rtp_ts);
+        if let Some(prev) = self.transit {
+            let d = (transit.wrapping_sub(prev) as i32).unsigned_abs() as f64;
+            self.jitter += (d - self.jitter) / 16.0;
+        }
+        self.transit = Some(transit);
+    }
+
+    /// レポートブロックを作り、次の区間の損失率のために状態を進める。
+    fn report_block(&mut self, last_sr: u32, delay_since_last_sr: u32) -> ReportBlock {
+        let extended_max = self.extended_max();
+        let expected = extended_max.wrapping_sub(self.base_seq).wrapping_add(1);
+        let cumulative_lost = expected as i64 - self.received as i64;
+
+        let expected_interval = expected.wrapping_sub(self.expected_prior);
+        let received_interval = self.received.wrapping_sub(self.received_prior);
+        self.expected_prior = expected;
+        self.received_prior = self.received;
+        let lost_interval = expected_interval as i64 - received_interval as i64;
+        let fraction_lost = if expected_interval == 0 || lost_interval <= 0 {
+            0
+        } else {
+            ((lost_interval << 8) / expected_interval as i64).min(255) as u8
+        };
+
+        ReportBlock {
+            ssrc: self.ssrc,
+            fraction_lost,
+            cumulative_lost: cumulative_lost.clamp(i32::MIN as i64, i32::MAX as i64) as i32,
+            highest_seq: extended_max,
+            jitter: self.jitter as u32,
+            last_sr,
+            delay_since_last_sr,
+        }
+    }
+}
+
+// ============================================================
+// RTCP セッション
+// ============================================================
+
+/// 1つのトラックの RTCP を扱う。
+///
+/// 受信した RTP から統計を取り、定期的に Receiver Report（＋SDES CNAME）を作る。
+/// 受信した Sender Report は RTP タイムスタンプと壁時計の対応付けのために保持する。
+pub struct RtcpSession {
+    /// 自分の SSRC
+    ssrc: u32,
+    cname: String,
+    /// RTP のクロックレート（ジッタの単位変換用）
+    clock_rate: u32,
+    /// 到着時刻の基準
+    epoch: Instant,
+    stats: Option<ReceptionStats>,
+    /// 最後に受け取った SR とその受信時刻
+    last_sr: Option<(SenderReport, Instant)>,
+    /// take_clock_reference() の後に新しい SR を受け取ったか
+    new_sr: bool,
+    last_report: Instant,
+}
+
+impl RtcpSession {
+    pub fn new(clock_rate: u32) -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        let ssrc = nanos ^ std::process::id().rotate_left(16);
+        Self {
+            ssrc,
+            cname: format!("rtsp-client-{}@localhost", std::process::id()),
+            clock_rate,
+            epoch: Instant::now(),
+            stats: None,
+            last_sr: None,
+            new_sr: false,
+            last_report: Instant::now(),
+        }
+    }
+
+    /// RTP パケットを受信した（受信順に呼ぶこと）。
+    pub fn on_rtp(&mut self, header: &RTPHeader) {
+        let arrival = self.epoch.elapsed().as_secs_f64() * self.clock_rate as f64;
+        match self.stats {
+            Some(ref s) if s.ssrc == header.ssrc => {}
+            _ => self.stats = Some(ReceptionStats::new(header.ssrc, header.sequence_number)),
+        }
+        if let Some(ref mut s) = self.stats {
+            s.update(header.sequence_number, header.timestamp, arrival as i64);
+        }
+    }
+
+    /// 受信した RTCP を処理する。送信元が BYE を送ってきたら true を返す。
+    pub fn on_rtcp(&mut self, data: &[u8]) -> Result<bool, RtcpError> {
+        let mut bye = false;
+        for packet in parse_compound(data)? {
+            match packet {
+                RtcpPacket::SenderReport(sr) => {
+                    println!("RTCP SR: ssrc={:08x} ntp={:016x} rtp_ts={} packets={} octets={} reports={}",
+                        sr.ssrc, sr.ntp_timestamp, sr.rtp_timestamp, sr.packet_count, sr.octet_count, sr.reports.len());
+                    self.last_sr = Some((sr, Instant::now()));
+                    self.new_sr = true;
+                }
+                RtcpPacket::ReceiverReport { ssrc, reports } => {
+                    println!("RTCP RR: ssrc={:08x} reports={}", ssrc, reports.len());
+                }
+                RtcpPacket::SourceDescription(chunks) => {
+                    for chunk in chunks {
+                        if let Some((_, cname)) = chunk.items.iter().find(|(t, _)| *t == SDES_CNAME) {
+                            println!("RTCP SDES: ssrc={:08x} cname={}", chunk.ssrc, cname);
+                        }
+                    }
+                }
+                RtcpPacket::Goodbye { ssrcs, reason } => {
+                    // まだ RTP を受け取っていなければ、どの SSRC の BYE でも終了とみなす
+                    let media_ssrc = self.stats.as_ref().map(|s| s.ssrc);
+                    if media_ssrc.is_none_or(|ssrc| ssrcs.contains(&ssrc)) {
+                        println!("RTCP BYE received ({})", reason.as_deref().unwrap_or("no reason"));
+                        bye = true;
+                    }
+                }
+                RtcpPacket::Other { packet_type } => {
+                    println!("RTCP packet type {} ignored", packet_type);
+                }
+            }
+        }
+        Ok(bye)
+    }
+
+    /// 前回の呼び出し以降に新しい Sender Report を受け取っていれば、
+    /// そこから得た RTP タイムスタンプと壁時計の対応を返す。
+    pub fn take_clock_reference(&mut self) -> Option<ClockReference> {
+        if !std::mem::take(&mut self.new_sr) {
+            return None;
+        }
+        let (ref sr, _) = *self.last_sr.as_ref()?;
+        Some(ClockReference {
+            rtp_timestamp: sr.rtp_timestamp,
+            wall_clock: ntp_to_system_time(sr.ntp_timestamp),
+            clock_rate: self.clock_rate,
+        })
+    }
+
+    /// Receiver Report を送る時刻になっていれば true
+    pub fn report_due(&self) -> bool {
+        self.last_report.elapsed() >= REPORT_INTERVAL
+    }
+
+    /// Receiver Report と SDES CNAME からなる複合パケットを作る。
+    pub fn build_report(&mut self) -> Vec<u8> {
+        self.last_report = Instant::now();
+        let (last_sr, delay) = match self.last_sr {
+            Some((ref sr, received)) => {
+                let delay = (received.elapsed().as_secs_f64() * 65536.0) as u32;
+                ((sr.ntp_timestamp >> 16) as u32, delay)
+            }
+            None => (0, 0),
+        };
+        let reports: Vec<ReportBlock> = self.stats.as_mut()
+            .map(|s| s.report_block(last_sr, delay))
+            .into_iter()
+            .collect();
+
+        let mut packet = build_receiver_report(self.ssrc, &reports);
+        packet.extend(build_sdes_cname(self.ssrc, &self.cname));
+        packet
+    }
+
+    /// 受信を終えることを知らせる RR + SDES + BYE を作る。
+    pub fn build_bye(&mut self) -> Vec<u8> {
+        let mut packet = self.build_report();
+        packet.extend(build_bye(self.ssrc));
+        packet
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// カメラが送る SR（レポートブロックなし）+ SDES CNAME の複合パケット
+    const SR_SDES: [u8; 52] = [
+        0x80, 0xc8, 0x00, 0x06, 0x2a, 0x6f, 0x3b, 0x1c, 0xe8, 0xfe, 0x6f, 0x80, 0x80, 0x00, 0x00, 0x00,
+        0x5f, 0x2c, 0x11, 0xa0, 0x00, 0x00, 0x01, 0x2c, 0x00, 0x04, 0x93, 0xdf, 0x81, 0xca, 0x00, 0x05,
+        0x2a, 0x6f, 0x3b, 0x1c, 0x01, 0x0d, b'H', b'I', b'K', b'-', b'D', b'S', b'2', b'C', b'D', b'2',
+        b'1', b'4', b'3', 0x00,
+    ];
+
+    /// 2023-11-14T22:13:20.5Z（UNIX 時刻 1700000000.5）
+    const NTP_TIME: u64 = 0xE8FE_6F80_8000_0000;
+
+    #[test]
+    fn parse_sr_and_sdes() {
+        let packets = parse_compound(&SR_SDES).unwrap();
+        assert_eq!(packets, vec![
+            RtcpPacket::SenderReport(SenderReport {
+                ssrc: 0x2a6f3b1c,
+                ntp_timestamp: NTP_TIME,
+                rtp_timestamp: 0x5f2c11a0,
+                packet_count: 300,
+                octet_count: 299999,
+                reports: vec![],
+            }),
+            RtcpPacket::SourceDescription(vec![SdesChunk {
+                ssrc: 0x2a6f3b1c,
+                items: vec![(SDES_CNAME, "HIK-DS2CD2143".to_string())],
+            }]),
+        ]);
+
+        // 長さフィールドがデータグラムを超えている、ヘッダが途中で終わっている
+        assert!(parse_compound(&SR_SDES[..SR_SDES.len() - 4]).is_err());
+        assert!(parse_compound(&SR_SDES[..30]).is_err());
+        let mut v1 = SR_SDES;
+        v1[0] = 0x40;
+        assert!(parse_compound(&v1).is_err());
+    }
+
+    #[test]
+    fn build_and_parse_round_trip() {
+        let reports = vec![
+            ReportBlock {
+                ssrc: 0x2a6f3b1c,
+                fraction_lost: 46,
+                cumulative_lost: -5,
+                highest_seq: 0x0001_0004,
+                jitter: 19,
+                last_sr: 0x6f80_8000,
+                delay_since_last_sr: 0x0001_8000,
+            },
+            ReportBlock { ssrc: 1, cumulative_lost: 0x7F_FFFF, ..Default::default() },
+        ];
+        let rr = build_receiver_report(0x1234_5678, &reports);
+        assert_eq!(rr.len(), 8 + 2 * 24);
+        assert_eq!(parse_compound(&rr).unwrap(), vec![RtcpPacket::ReceiverReport { ssrc: 0x1234_5678, reports: reports.clone() }]);
+
+        // 24bit に収まらない累積損失数は飽和させる
+        let clamped = build_receiver_report(0, &[ReportBlock { cumulative_lost: -0x100_0000, ..Default::default() }]);
+        match &parse_compound(&clamped).unwrap()[0] {
+            RtcpPacket::ReceiverReport { reports, .. } => assert_eq!(reports[0].cumulative_lost, -0x80_0000),
+            p => panic!("unexpected {:?}", p),
+        }
+
+        // CNAME の長さによらず 32bit 境界に揃う
+        for cname in ["", "abc", "rtsp-client-1@localhost"] {
+            let sdes = build_sdes_cname(7, cname);
+            assert!(sdes.len().is_multiple_of(4));
+            assert_eq!(parse_compound(&sdes).unwrap(), vec![RtcpPacket::SourceDescription(vec![SdesChunk {
+                ssrc: 7,
+                items: vec![(SDES_CNAME, cname.to_string())],
+            }])]);
+        }
+
+        assert_eq!(parse_compound(&build_bye(9)).unwrap(), vec![RtcpPacket::Goodbye { ssrcs: vec![9], reason: None }]);
+    }
+
+    #[test]
+    fn session_report_and_bye() {
+        let mut session = RtcpSession::new(90000);
+        // SR を受け取ると壁時計との対応が1度だけ取り出せる
+        assert!(!session.on_rtcp(&SR_SDES).unwrap());
+        let reference = session.take_clock_reference().unwrap();
+        assert_eq!(reference.rtp_timestamp, 0x5f2c11a0);
+        assert_eq!(reference.wall_clock, UNIX_EPOCH + Duration::from_millis(1_700_000_000_500));
+        assert_eq!(session.take_clock_reference(), None);
+
+        // RR（まだ RTP を受け取っていないのでレポートブロックなし）+ SDES + BYE
+        let packets = parse_compound(&session.build_bye()).unwrap();
+        assert_eq!(packets.len(), 3);
+        assert!(matches!(&packets[0], RtcpPacket::ReceiverReport { reports, .. } if reports.is_empty()));
+        assert!(matches!(&packets[1], RtcpPacket::SourceDescription(chunks) if chunks[0].items[0].0 == SDES_CNAME));
+        assert!(matches!(&packets[2], RtcpPacket::Goodbye { .. }));
+
+        // 受信中の SSRC の BYE で終了
+        assert!(session.on_rtcp(&build_bye(0x2a6f3b1c)).unwrap());
+    }
+
+    #[test]
+    fn reception_stats_loss_and_sequence_wrap() {
+        let mut stats = ReceptionStats::new(0x2a6f3b1c, 65530);
+        // 65534 と 2 が欠けたまま 65535 → 0 で折り返す
+        for seq in [65530, 65531, 65532, 65533, 65535, 0, 1, 3, 4] {
+            stats.update(seq, 0, 0);
+        }
+        let block = stats.report_block(0, 0);
+        assert_eq!(block.highest_seq, 0x0001_0004);
+        assert_eq!(block.cumulative_lost, 2);
+        // 11 個中 2 個: 2 * 256 / 11
+        assert_eq!(block.fraction_lost, 46);
+
+        // 次の区間は欠落なし。累積損失数はそのまま
+        for seq in 5..15 {
+            stats.update(seq, 0, 0);
+        }
+        let block = stats.report_block(0, 0);
+        assert_eq!((block.fraction_lost, block.cumulative_lost, block.highest_seq), (0, 2, 0x0001_000E));
+
+        // 重複も受信数に数えるので、累積損失数は負にもなる（RFC 3550 A.3）
+        for seq in [10, 11, 12] {
+            stats.update(seq, 0, 0);
+        }
+        assert_eq!(stats.report_block(0, 0).cumulative_lost, -1);
+
+        // MAX_DROPOUT を超えて飛んだら数え直す
+        stats.update(20000, 0, 0);
+        let block = stats.report_block(0, 0);
+        assert_eq!((block.highest_seq, block.cumulative_lost), (20000, 0));
+    }
+
+    #[test]
+    fn reception_stats_jitter() {
+        // RTP タイムスタンプの折り返しをまたいで、3000 ごとに一定の遅延で届く
+        let base = 0xFFFF_F000u32;
+        let mut stats = ReceptionStats::new(1, 0);
+        for i in 0..4u16 {
+            let ts = base.wrapping_add(i as u32 * 3000);
+            stats.update(i, ts, base as i64 + i as i64 * 3000 + 500);
+        }
+        assert_eq!(stats.report_block(0, 0).jitter, 0);
+
+        // 1つだけ 160 遅れると J = 160 / 16
+        let ts = base.wrapping_add(4 * 3000);
+        stats.update(4, ts, base as i64 + 4 * 3000 + 500 + 160);
+        assert_eq!(stats.jitter, 10.0);
+        // 次が元の遅延に戻ると |D| = 160 なので J = 10 + (160 - 10) / 16
+        let ts = base.wrapping_add(5 * 3000);
+        stats.update(5, ts, base as i64 + 5 * 3000 + 500);
+        assert_eq!(stats.jitter, 19.375);
+        assert_eq!(stats.report_block(0, 0).jitter, 19);
+    }
+
+    #[test]
+    fn ntp_and_wall_clock() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_500);
+        assert_eq!(ntp_to_system_time(NTP_TIME), time);
+        assert_eq!(system_time_to_ntp(time), NTP_TIME);
+        // 1ns 未満の誤差で往復する
+        let time = UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+        let back = ntp_to_system_time(system_time_to_ntp(time));
+        assert!(time.duration_since(back).unwrap() <= Duration::from_nanos(1), "{:?}", back);
+        // NTP の起点より前（UNIX 時刻で表せない）は UNIX_EPOCH にする
+        assert_eq!(ntp_to_system_time(0), UNIX_EPOCH);
+
+        let reference = ClockReference { rtp_timestamp: 0xFFFF_5000, wall_clock: time, clock_rate: 90000 };
+        assert_eq!(reference.wall_clock_at(0xFFFF_5000), time);
+        // SR の後、RTP タイムスタンプの折り返しをまたいで 1 秒
+        assert_eq!(reference.wall_clock_at(0xFFFF_5000u32.wrapping_add(90000)), time + Duration::from_secs(1));
+        // SR より前のフレーム
+        assert_eq!(reference.wall_clock_at(0xFFFF_5000 - 45000), time - Duration::from_millis(500));
+    }
+}
@@ -1,6 +1,11 @@
-use std::net::{UdpSocket};
-use std::time::Duration;
+use std::fmt;
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::ops::RangeInclusive;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
 use std::io;
+use crate::rtsp_client::{InterleavedFrame, SharedStream};
 pub const NAL_UNIT_TYPE_UNSPECIFIED: u8 = 0;
 pub const NAL_UNIT_TYPE_NON_IDR: u8 = 1;
 pub const NAL_UNIT_TYPE_PARTITION_A: u8 = 2;
@@ -24,79 +29,626 @@ pub const NAL_UNIT_TYPE_FU_B: u8 = 29;
 
 #[derive(Debug)]
 pub struct RTPHeader {
-    pub version: u8,
-    pub padding: u8,
-    pub extension: u8,
-    pub csrc_count: u8,
     pub marker: u8,
     pub payload_type: u8,
     pub sequence_number: u16,
     pub timestamp: u32,
     pub ssrc: u32,
+    /// 寄与送信元（CSRC）のリスト
+    pub csrcs: Vec<u32>,
+    /// ヘッダ拡張（X ビットが立っている場合）
+    pub extension_header: Option<HeaderExtension>,
+}
+
+/// RFC 8285 の one-byte ヘッダ形式のプロファイル
+const EXTENSION_PROFILE_ONE_BYTE: u16 = 0xBEDE;
+/// RFC 8285 の two-byte ヘッダ形式のプロファイル（下位 4bit は appbits）
+const EXTENSION_PROFILE_TWO_BYTE: u16 = 0x1000;
+/// ONVIF Streaming Spec の再生用ヘッダ拡張のプロファイル
+const EXTENSION_PROFILE_ONVIF_REPLAY: u16 = 0xABAC;
+
+/// RTP ヘッダ拡張（RFC 3550 5.3.1）
+#[derive(Debug, Clone)]
+pub struct HeaderExtension {
+    /// プロファイル固有の 16bit 識別子
+    pub profile: u16,
+    /// 拡張データ（長さフィールドを除く、32bit 単位）
+    pub data: Vec<u8>,
+}
+
+/// RFC 8285 のヘッダ拡張要素
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtensionElement {
+    pub id: u8,
+    pub data: Vec<u8>,
+}
+
+/// ONVIF の再生用ヘッダ拡張（録画再生時のフレームの絶対時刻など）
+#[derive(Debug, Clone, PartialEq)]
+pub struct OnvifReplay {
+    /// フレームの絶対時刻（NTP タイムスタンプ形式）
+    pub ntp_timestamp: u64,
+    /// D: 直前のパケットとの間に不連続がある
+    pub discontinuity: bool,
+    /// T: 再生の最後のパケット
+    pub terminal: bool,
+}
+
+impl HeaderExtension {
+    /// RFC 8285 の one-byte / two-byte 形式の拡張要素を取り出す。
+    /// それ以外のプロファイルなら None。
+    pub fn elements(&self) -> Option<Vec<ExtensionElement>> {
+        let two_byte = if self.profile == EXTENSION_PROFILE_ONE_BYTE {
+            false
+        } else if self.profile & 0xFFF0 == EXTENSION_PROFILE_TWO_BYTE {
+            true
+        } else {
+            return None;
+        };
+
+        let mut elements = Vec::new();
+        let data = &self.data;
+        let mut offset = 0;
+        while offset < data.len() {
+            // 0 はパディング
+            if data[offset] == 0 {
+                offset += 1;
+                continue;
+            }
+            let (id, len, header_len) = if two_byte {
+                let len = *data.get(offset + 1)? as usize;
+                (data[offset], len, 2)
+            } else {
+                let id = data[offset] >> 4;
+                // id=15 は以降の解析を打ち切る予約値
+                if id == 15 {
+                    break;
+                }
+                (id, (data[offset] & 0x0F) as usize + 1, 1)
+            };
+            let value = data.get(offset + header_len..offset + header_len + len)?;
+            elements.push(ExtensionElement { id, data: value.to_vec() });
+            offset += header_len + len;
+        }
+        Some(elements)
+    }
+
+    /// ONVIF の再生用ヘッダ拡張であれば解析して返す。
+    pub fn onvif_replay(&self) -> Option<OnvifReplay> {
+        if self.profile != EXTENSION_PROFILE_ONVIF_REPLAY || self.data.len() < 12 {
+            return None;
+        }
+        // NTP タイムスタンプ(8) + C E D T mbz(1) + CSeq(1) + padding(2)
+        let d = &self.data;
+        let flags = d[8];
+        Some(OnvifReplay {
+            ntp_timestamp: u64::from_be_bytes([d[0], d[1], d[2], d[3], d[4], d[5], d[6], d[7]]),
+            discontinuity: flags & 0x20 != 0,
+            terminal: flags & 0x10 != 0,
+        })
+    }
+}
+
+/// RTP パケットの解析エラー
+#[derive(Debug, PartialEq)]
+pub enum RtpError {
+    /// 固定ヘッダ・CSRC・拡張ヘッダの途中で終わっている
+    Truncated,
+    /// RTP バージョン 2 以外
+    UnsupportedVersion(u8),
+    /// パディング長がパケット長を超えている
+    InvalidPadding,
+}
+
+impl fmt::Display for RtpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RtpError::Truncated => write!(f, "truncated RTP packet"),
+            RtpError::UnsupportedVersion(v) => write!(f, "unsupported RTP version {}", v),
+            RtpError::InvalidPadding => write!(f, "invalid RTP padding"),
+        }
+    }
+}
+
+impl std::error::Error for RtpError {}
+
+/// ポート範囲の指定がない場合に、偶数・奇数のポート対を探す試行回数
+const PORT_PAIR_ATTEMPTS: usize = 20;
+
+/// RTP パケットの受信元
+enum RtpSource {
+    /// RTP/RTCP それぞれの UDP ソケット
+    Udp {
+        rtp_socket: UdpSocket,
+        rtcp_socket: UdpSocket,
+        rtp_port: u16,
+        /// RTCP の送り先（サーバーの RTCP ポート）
+        rtcp_peer: Option<SocketAddr>,
+        /// RTP を受け付ける送信元（サーバーのアドレスと RTP ポート）
+        rtp_peer: Option<(IpAddr, Option<u16>)>,
+    },
+    /// RTSP 接続上の $ フレーム（rtp_channel が RTP、+1 が RTCP）
+    Interleaved {
+        rx: mpsc::Receiver<InterleavedFrame>,
+        rtp_channel: u8,
+        /// RTP を待つ間に届いた RTCP
+        pending_rtcp: Vec<Vec<u8>>,
+        /// RTCP を $ フレームで送るための RTSP 接続
+        writer: SharedStream,
+    },
 }
 
 pub struct RTPReceiver {
-    rtp_socket: UdpSocket,
-    rtcp_socket: UdpSocket,
+    source: RtpSource,
+    /// 受け付ける SSRC（Transport の ssrc= か、最初に受信したパケットの SSRC）
+    ssrc: Option<u32>,
+}
+
+/// 送信元アドレスが SETUP で知ったサーバーのものか確認する。
+/// ポートが分からない場合はアドレスだけを比べる。
+fn is_expected_peer(expected: Option<(IpAddr, Option<u16>)>, from: SocketAddr) -> bool {
+    match expected {
+        Some((ip, port)) => from.ip() == ip && port.is_none_or(|p| p == from.port()),
+        None => true,
+    }
+}
+
+/// SSRC を確認する。まだ決まっていなければ最初に見た SSRC に固定する。
+fn accept_ssrc(expected: &mut Option<u32>, ssrc: u32) -> bool {
+    match *expected {
+        Some(s) => s == ssrc,
+        None => {
+            println!("Locked to RTP SSRC {:08X}", ssrc);
+            *expected = Some(ssrc);
+            true
+        }
+    }
+}
+
+/// RTP パケットを解析し、ヘッダとペイロード（パディングを除く）を返す。
+///
+/// CSRC とヘッダ拡張を読み飛ばしてペイロードの位置を求める。
+pub fn parse_rtp_packet(data: &[u8]) -> Result<(RTPHeader, &[u8]), RtpError> {
+    if data.len() < 12 {
+        return Err(RtpError::Truncated);
+    }
+    let version = data[0] >> 6;
+    if version != 2 {
+        return Err(RtpError::UnsupportedVersion(version));
+    }
+    let padding = (data[0] >> 5) & 0x01;
+    let extension = (data[0] >> 4) & 0x01;
+    let csrc_count = data[0] & 0x0F;
+    let marker = data[1] >> 7;
+    let payload_type = data[1] & 0x7F;
+    let sequence_number = u16::from_be_bytes([data[2], data[3]]);
+    let timestamp = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+    let ssrc = u32::from_be_bytes([data[8], data[9], data[10], data[11]]);
+
+    let mut offset = 12;
+    let csrc_end = offset + csrc_count as usize * 4;
+    let csrcs = data.get(offset..csrc_end)
+        .ok_or(RtpError::Truncated)?
+        .chunks_exact(4)
+        .map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]]))
+        .collect();
+    offset = csrc_end;
+
+    let extension_header = if extension == 1 {
+        let ext = data.get(offset..offset + 4).ok_or(RtpError::Truncated)?;
+        let profile = u16::from_be_bytes([ext[0], ext[1]]);
+        let length = u16::from_be_bytes([ext[2], ext[3]]) as usize * 4;
+        let ext_data = data.get(offset + 4..offset + 4 + length).ok_or(RtpError::Truncated)?;
+        offset += 4 + length;
+        Some(HeaderExtension { profile, data: ext_data.to_vec() })
+    } else {
+        None
+    };
+
+    let mut end = data.len();
+    if padding == 1 {
+        // 最後の1バイトがパディング長（自身を含む）
+        let pad = data[end - 1] as usize;
+        if pad == 0 || offset + pad > end {
+            return Err(RtpError::InvalidPadding);
+        }
+        end -= pad;
+    }
+
+    let header = RTPHeader {
+        marker,
+        payload_type,
+        sequence_number,
+        timestamp,
+        ssrc,
+        csrcs,
+        extension_header,
+    };
+    Ok((header, &data[offset..end]))
+}
+
+/// RTP（偶数）と RTCP（奇数）のポート対を bind する。
+fn bind_pair(rtp_port: u16) -> Result<(UdpSocket, UdpSocket), io::Error> {
+    let rtp_socket = UdpSocket::bind(("0.0.0.0", rtp_port))?;
+    let rtcp_socket = UdpSocket::bind(("0.0.0.0", rtp_port + 1))?;
+    Ok((rtp_socket, rtcp_socket))
+}
+
+/// 範囲内の偶数ポートから順に、空いているポート対を探す。
+fn bind_pair_in_range(range: RangeInclusive<u16>) -> Result<(UdpSocket, UdpSocket), io::Error> {
+    let first = *range.start() + *range.start() % 2;
+    let mut port = first;
+    while port < *range.end() {
+        match bind_pair(port) {
+            Ok(pair) => return Ok(pair),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => {}
+            Err(e) => return Err(e),
+        }
+        port = match port.checked_add(2) {
+            Some(p) => p,
+            None => break,
+        };
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        format!("no free RTP/RTCP port pair in {}-{}", range.start(), range.end()),
+    ))
+}
+
+/// OS が割り当てたポートが偶数で、次の奇数ポートも空いていればそれを使う。
+fn bind_ephemeral_pair() -> Result<(UdpSocket, UdpSocket), io::Error> {
+    for _ in 0..PORT_PAIR_ATTEMPTS {
+        let rtp_socket = UdpSocket::bind("0.0.0.0:0")?;
+        let port = rtp_socket.local_addr()?.port();
+        if port % 2 != 0 {
+            continue;
+        }
+        match UdpSocket::bind(("0.0.0.0", port + 1)) {
+            Ok(rtcp_socket) => return Ok((rtp_socket, rtcp_socket)),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::AddrInUse, "no free RTP/RTCP port pair"))
 }
 
 impl RTPReceiver {
-    pub fn parse_rtp_header(&self, data: &[u8]) -> RTPHeader {
-        let version = data[0] >> 6;
-        let padding = (data[0] >> 5) & 0x01;
-        let extension = (data[0] >> 4) & 0x01;
-        let csrc_count = data[0] & 0x0F;
-        let marker = data[1] >> 7;
-        let payload_type = data[1] & 0x7F;
-        let sequence_number = u16::from_be_bytes([data[2], data[3]]);
-        let timestamp = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
-        let ssrc = u32::from_be_bytes([data[8], data[9], data[10], data[11]]);
-        RTPHeader {
-            version,
-            padding,
-            extension,
-            csrc_count,
-            marker,
-            payload_type,
-            sequence_number,
-            timestamp,
-            ssrc,
-        }
-    }
-
-    pub fn receive(&self) -> Result<(RTPHeader, Vec<u8>), io::Error> {
-        let mut buffer = [0; 1500];
-        let mut header: Option<RTPHeader> = None;
-        let mut payload: Vec<u8> = Vec::new();
-        self.rtp_socket.set_read_timeout(Some(Duration::from_secs(3))).unwrap();
-        match self.rtp_socket.recv_from(&mut buffer) {
-            Ok((size, _)) => {
-                let header = self.parse_rtp_header(&buffer);
-                let payload = buffer[12..size].to_vec();
-                return Ok((header, payload));
+    /// 最大 `timeout` だけ待って RTP パケットを1つ受信する。
+    pub fn receive(&mut self, timeout: Duration) -> Result<(RTPHeader, Vec<u8>), io::Error> {
+        // set_read_timeout に 0 を渡すとエラーになる
+        let timeout = timeout.max(Duration::from_millis(1));
+        match self.source {
+            RtpSource::Udp { ref rtp_socket, rtp_peer, .. } => {
+                let deadline = Instant::now() + timeout;
+                let mut buffer = [0; 1500];
+                loop {
+                    let remaining = deadline.saturating_duration_since(Instant::now()).max(Duration::from_millis(1));
+                    rtp_socket.set_read_timeout(Some(remaining))?;
+                    match rtp_socket.recv_from(&mut buffer) {
+                        Ok((_, from)) if !is_expected_peer(rtp_peer, from) => {
+                            // サーバー以外からのパケットは録画に混ぜない
+                            eprintln!("Dropping RTP packet from unexpected source {}", from);
+                        }
+                        Ok((size, _)) => match parse_rtp_packet(&buffer[..size]) {
+                            Ok((header, _)) if !accept_ssrc(&mut self.ssrc, header.ssrc) => {
+                                eprintln!("Dropping RTP packet with unexpected SSRC {:08X}", header.ssrc);
+                            }
+                            Ok((header, payload)) => return Ok((header, payload.to_vec())),
+                            // 壊れたパケットは捨てて次を待つ
+                            Err(e) => eprintln!("Dropping RTP packet: {}", e),
+                        },
+                        Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut || e.kind() == std::io::ErrorKind::WouldBlock => {
+                            return Err(io::Error::new(io::ErrorKind::TimedOut, "recv timed out"));
+                        }
+                        Err(e) => return Err(e),
+                    }
+                    if Instant::now() >= deadline {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "recv timed out"));
+                    }
+                }
             }
-            Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut => {
-                Err(io::Error::new(io::ErrorKind::TimedOut, "recv timed out"))
+            RtpSource::Interleaved { ref rx, rtp_channel, ref mut pending_rtcp, .. } => {
+                let deadline = Instant::now() + timeout;
+                loop {
+                    match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                        Ok(frame) => {
+                            // RTCP は receive_rtcp() で取り出す
+                            if frame.channel == rtp_channel.wrapping_add(1) {
+                                pending_rtcp.push(frame.data);
+                                continue;
+                            }
+                            if frame.channel != rtp_channel {
+                                continue;
+                            }
+                            match parse_rtp_packet(&frame.data) {
+                                Ok((header, _)) if !accept_ssrc(&mut self.ssrc, header.ssrc) => {
+                                    eprintln!("Dropping RTP packet with unexpected SSRC {:08X}", header.ssrc);
+                                }
+                                Ok((header, payload)) => return Ok((header, payload.to_vec())),
+                                Err(e) => eprintln!("Dropping RTP packet: {}", e),
+                            }
+                        }
+                        Err(mpsc::RecvTimeoutError::Timeout) => {
+                            return Err(io::Error::new(io::ErrorKind::TimedOut, "recv timed out"));
+                        }
+                        Err(mpsc::RecvTimeoutError::Disconnected) => {
+                            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "RTSP connection closed"));
+                        }
+                    }
+                }
             }
-            Err(e) => panic!("recv error: {:?}", e),
         }
     }
 
-    pub fn new() -> RTPReceiver {
-        let rtp_socket = UdpSocket::bind("0.0.0.0:0").unwrap();
-        let port = rtp_socket.local_addr().unwrap().port();
-        let rtcp_socket = UdpSocket::bind(format!("0.0.0.0:{}", (port+1))).unwrap();
+    /// UDP で RTP/RTCP を受信する。
+    ///
+    /// RFC 3550 に従い RTP に偶数ポート、RTCP にその次の奇数ポートを割り当てる。
+    /// `port_range` を指定した場合はその範囲内で空いているポート対を探し、
+    /// 指定しない場合は OS が割り当てたポートから偶数のものを探す。
+    pub fn new(port_range: Option<RangeInclusive<u16>>) -> Result<RTPReceiver, io::Error> {
+        let (rtp_socket, rtcp_socket) = match port_range {
+            Some(range) => bind_pair_in_range(range)?,
+            None => bind_ephemeral_pair()?,
+        };
+        let rtp_port = rtp_socket.local_addr()?.port();
+        // RTCP は receive_rtcp() でポーリングする
+        rtcp_socket.set_nonblocking(true)?;
+        Ok(RTPReceiver {
+            source: RtpSource::Udp { rtp_socket, rtcp_socket, rtp_port, rtcp_peer: None, rtp_peer: None },
+            ssrc: None,
+        })
+    }
+
+    /// RTSP 接続上のインターリーブフレームから RTP を受信する。
+    /// `writer` は RTCP を送るための RTSP 接続（RTSP リクエストと共有する）。
+    pub fn interleaved(rx: mpsc::Receiver<InterleavedFrame>, rtp_channel: u8, writer: SharedStream) -> RTPReceiver {
         RTPReceiver {
-            rtp_socket,
-            rtcp_socket
+            source: RtpSource::Interleaved { rx, rtp_channel, pending_rtcp: Vec::new(), writer },
+            ssrc: None,
+        }
+    }
+
+    /// UDP の場合の RTCP の送り先を設定する。
+    pub fn set_rtcp_peer(&mut self, addr: SocketAddr) {
+        if let RtpSource::Udp { ref mut rtcp_peer, .. } = self.source {
+            *rtcp_peer = Some(addr);
+        }
+    }
+
+    /// UDP の場合に RTP を受け付ける送信元を設定する。
+    /// `port` が None の場合はアドレスだけで判定する。
+    pub fn set_rtp_peer(&mut self, ip: IpAddr, port: Option<u16>) {
+        if let RtpSource::Udp { ref mut rtp_peer, .. } = self.source {
+            *rtp_peer = Some((ip, port));
+        }
+    }
+
+    /// Transport ヘッダの ssrc= で通知された SSRC 以外のパケットを捨てる。
+    pub fn set_ssrc(&mut self, ssrc: u32) {
+        self.ssrc = Some(ssrc);
+    }
+
+    /// 受信済みの RTP パケットをすべて読み捨て、ヘッダだけを返す（ブロックしない）。
+    /// 録画しないトラックの受信統計（RTCP Receiver Report）のために使う。
+    pub fn drain(&mut self) -> Vec<RTPHeader> {
+        let mut headers = Vec::new();
+        match self.source {
+            RtpSource::Udp { ref rtp_socket, rtp_peer, .. } => {
+                if rtp_socket.set_nonblocking(true).is_err() {
+                    return headers;
+                }
+                let mut buffer = [0; 1500];
+                while let Ok((size, from)) = rtp_socket.recv_from(&mut buffer) {
+                    if !is_expected_peer(rtp_peer, from) {
+                        continue;
+                    }
+                    if let Ok((header, _)) = parse_rtp_packet(&buffer[..size]) {
+                        if accept_ssrc(&mut self.ssrc, header.ssrc) {
+                            headers.push(header);
+                        }
+                    }
+                }
+                // receive() は読み込みタイムアウト付きのブロッキングで使う
+                let _ = rtp_socket.set_nonblocking(false);
+            }
+            RtpSource::Interleaved { ref rx, rtp_channel, ref mut pending_rtcp, .. } => {
+                while let Ok(frame) = rx.try_recv() {
+                    if frame.channel == rtp_channel.wrapping_add(1) {
+                        pending_rtcp.push(frame.data);
+                        continue;
+                    }
+                    if frame.channel != rtp_channel {
+                        continue;
+                    }
+                    if let Ok((header, _)) = parse_rtp_packet(&frame.data) {
+                        if accept_ssrc(&mut self.ssrc, header.ssrc) {
+                            headers.push(header);
+                        }
+                    }
+                }
+            }
+        }
+        headers
+    }
+
+    /// 受信済みの RTCP パケットをすべて取り出す（ブロックしない）。
+    pub fn receive_rtcp(&mut self) -> Vec<Vec<u8>> {
+        match self.source {
+            RtpSource::Udp { ref rtcp_socket, rtp_peer, .. } => {
+                let mut packets = Vec::new();
+                let mut buffer = [0; 1500];
+                while let Ok((size, from)) = rtcp_socket.recv_from(&mut buffer) {
+                    // RTCP もサーバーのアドレスから届いたものだけを受け付ける
+                    if !is_expected_peer(rtp_peer.map(|(ip, _)| (ip, None)), from) {
+                        eprintln!("Dropping RTCP packet from unexpected source {}", from);
+                        continue;
+                    }
+                    packets.push(buffer[..size].to_vec());
+                }
+                packets
+            }
+            RtpSource::Interleaved { ref mut pending_rtcp, .. } => std::mem::take(pending_rtcp),
+        }
+    }
+
+    /// RTCP パケットをサーバーに送る。
+    pub fn send_rtcp(&mut self, data: &[u8]) -> Result<(), io::Error> {
+        match self.source {
+            RtpSource::Udp { ref rtcp_socket, rtcp_peer, .. } => {
+                let peer = rtcp_peer.ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "RTCP peer unknown"))?;
+                rtcp_socket.send_to(data, peer)?;
+                Ok(())
+            }
+            RtpSource::Interleaved { rtp_channel, ref writer, .. } => {
+                // $ + チャンネル + 長さ(2) + データ を1回で書く（RTSP リクエストと混ざらないように）
+                let mut frame = vec![b'$', rtp_channel.wrapping_add(1)];
+                frame.extend_from_slice(&(data.len() as u16).to_be_bytes());
+                frame.extend_from_slice(data);
+                writer.lock().unwrap().write_all(&frame)
+            }
         }
     }
 
     pub fn get_rtp_port(&self) -> u16 {
-        return self.rtp_socket.local_addr().unwrap().port();
+        match self.source {
+            RtpSource::Udp { rtp_port, .. } => rtp_port,
+            RtpSource::Interleaved { .. } => 0,
+        }
     }
     pub fn get_rtcp_port(&self) -> u16 {
-        return self.rtcp_socket.local_addr().unwrap().port();
+        match self.source {
+            RtpSource::Udp { rtp_port, .. } => rtp_port + 1,
+            RtpSource::Interleaved { .. } => 0,
+        }
     }
 
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 固定ヘッダ（M=1, PT=96, seq=0x1234, timestamp=0x00015F90, SSRC=0xDEADBEEF）の後ろに `rest` を続ける
+    fn packet(first: u8, rest: &[u8]) -> Vec<u8> {
+        let mut data = vec![first, 0xE0, 0x12, 0x34, 0x00, 0x01, 0x5F, 0x90, 0xDE, 0xAD, 0xBE, 0xEF];
+        data.extend_from_slice(rest);
+        data
+    }
+
+    #[test]
+    fn parse_fixed_header_and_csrcs() {
+        // CC=2
+        let data = packet(0x82, &[0x11, 0x11, 0x11, 0x11, 0x22, 0x22, 0x22, 0x22, 0x65, 0x88]);
+        let (header, payload) = parse_rtp_packet(&data).unwrap();
+        assert_eq!(header.marker, 1);
+        assert_eq!(header.payload_type, 96);
+        assert_eq!(header.sequence_number, 0x1234);
+        assert_eq!(header.timestamp, 90000);
+        assert_eq!(header.ssrc, 0xDEADBEEF);
+        assert_eq!(header.csrcs, vec![0x11111111, 0x22222222]);
+        assert!(header.extension_header.is_none());
+        assert_eq!(payload, [0x65, 0x88]);
+
+        // CSRC の途中で終わっている
+        assert_eq!(parse_rtp_packet(&packet(0x82, &[0x11, 0x11, 0x11, 0x11])).err(), Some(RtpError::Truncated));
+    }
+
+    #[test]
+    fn parse_one_byte_extension() {
+        // X=1、0xBEDE、長さ 2 ワード: id 1 (1バイト)、パディング、id 2 (3バイト)、パディング
+        let data = packet(0x90, &[0xBE, 0xDE, 0x00, 0x02, 0x10, 0xAA, 0x00, 0x22, 0x01, 0x02, 0x03, 0x00, 0x65]);
+        let (header, payload) = parse_rtp_packet(&data).unwrap();
+        assert_eq!(payload, [0x65]);
+        let ext = header.extension_header.unwrap();
+        assert_eq!(ext.profile, 0xBEDE);
+        assert_eq!(ext.elements(), Some(vec![
+            ExtensionElement { id: 1, data: vec![0xAA] },
+            ExtensionElement { id: 2, data: vec![0x01, 0x02, 0x03] },
+        ]));
+        assert_eq!(ext.onvif_replay(), None);
+
+        // id 15 より後ろは読まない
+        let data = packet(0x90, &[0xBE, 0xDE, 0x00, 0x01, 0x10, 0xAA, 0xF0, 0x33]);
+        let ext = parse_rtp_packet(&data).unwrap().0.extension_header.unwrap();
+        assert_eq!(ext.elements(), Some(vec![ExtensionElement { id: 1, data: vec![0xAA] }]));
+    }
+
+    #[test]
+    fn parse_two_byte_extension() {
+        // 0x100 + appbits、長さ 2 ワード: id 1 (2バイト)、id 5 (0バイト)、パディング
+        let data = packet(0x90, &[0x10, 0x03, 0x00, 0x02, 0x01, 0x02, 0xAA, 0xBB, 0x05, 0x00, 0x00, 0x00]);
+        let (header, payload) = parse_rtp_packet(&data).unwrap();
+        assert!(payload.is_empty());
+        let ext = header.extension_header.unwrap();
+        assert_eq!(ext.elements(), Some(vec![
+            ExtensionElement { id: 1, data: vec![0xAA, 0xBB] },
+            ExtensionElement { id: 5, data: vec![] },
+        ]));
+
+        // 要素の長さが拡張ヘッダを超えている
+        let data = packet(0x90, &[0x10, 0x00, 0x00, 0x01, 0x01, 0x04, 0xAA, 0xBB]);
+        assert_eq!(parse_rtp_packet(&data).unwrap().0.extension_header.unwrap().elements(), None);
+    }
+
+    #[test]
+    fn parse_onvif_replay_extension() {
+        // 0xABAC、長さ 3 ワード: NTP タイムスタンプ、C|D|T、CSeq、パディング
+        let data = packet(0x90, &[
+            0xAB, 0xAC, 0x00, 0x03,
+            0xE7, 0x1A, 0x2B, 0x3C, 0x80, 0x00, 0x00, 0x00,
+            0xB0, 0x05, 0x00, 0x00,
+            0x65,
+        ]);
+        let (header, payload) = parse_rtp_packet(&data).unwrap();
+        assert_eq!(payload, [0x65]);
+        let ext = header.extension_header.unwrap();
+        assert_eq!(ext.elements(), None);
+        assert_eq!(ext.onvif_replay(), Some(OnvifReplay {
+            ntp_timestamp: 0xE71A2B3C_80000000,
+            discontinuity: true,
+            terminal: true,
+        }));
+
+        // E のみ
+        let data = packet(0x90, &[0xAB, 0xAC, 0x00, 0x03, 0, 0, 0, 1, 0, 0, 0, 0, 0x40, 0x06, 0x00, 0x00]);
+        let replay = parse_rtp_packet(&data).unwrap().0.extension_header.unwrap().onvif_replay().unwrap();
+        assert_eq!((replay.ntp_timestamp, replay.discontinuity, replay.terminal), (1 << 32, false, false));
+    }
+
+    #[test]
+    fn parse_extension_truncated() {
+        // 拡張ヘッダの4バイトがない
+        assert_eq!(parse_rtp_packet(&packet(0x90, &[0xBE, 0xDE])).err(), Some(RtpError::Truncated));
+        // 長さフィールドがパケットを超えている
+        assert_eq!(parse_rtp_packet(&packet(0x90, &[0xBE, 0xDE, 0x00, 0x02, 0x10, 0xAA, 0x00, 0x00])).err(), Some(RtpError::Truncated));
+    }
+
+    #[test]
+    fn parse_removes_padding() {
+        // P=1、最後のバイトがパディング長（自身を含む）
+        let data = packet(0xA0, &[0x65, 0x88, 0x84, 0x00, 0x00, 0x03]);
+        let (_, payload) = parse_rtp_packet(&data).unwrap();
+        assert_eq!(payload, [0x65, 0x88, 0x84]);
+
+        // パディングだけのパケット
+        let data = packet(0xA0, &[0x00, 0x02]);
+        assert!(parse_rtp_packet(&data).unwrap().1.is_empty());
+
+        assert_eq!(parse_rtp_packet(&packet(0xA0, &[0x65, 0x00])).err(), Some(RtpError::InvalidPadding));
+        assert_eq!(parse_rtp_packet(&packet(0xA0, &[0x65, 0x03])).err(), Some(RtpError::InvalidPadding));
+        // CSRC の上にかかるパディング
+        let data = packet(0xA1, &[0x11, 0x11, 0x11, 0x11, 0x06]);
+        assert_eq!(parse_rtp_packet(&data).err(), Some(RtpError::InvalidPadding));
+    }
+
+    #[test]
+    fn parse_rejects_short_and_non_v2_packets() {
+        let data = packet(0x80, &[]);
+        assert!(parse_rtp_packet(&data).is_ok());
+        for len in 0..12 {
+            assert_eq!(parse_rtp_packet(&data[..len]).err(), Some(RtpError::Truncated), "len {}", len);
+        }
+        for (first, version) in [(0x00, 0), (0x40, 1), (0xC0, 3)] {
+            assert_eq!(parse_rtp_packet(&packet(first, &[0x65])).err(), Some(RtpError::UnsupportedVersion(version)));
+        }
+    }
+}
@@ -1,42 +1,291 @@
 extern crate base64;
+use std::fmt;
+use std::io;
 use std::io::Read;
 use std::io::Write;
 use std::io::BufRead;
-use std::io::{BufReader, BufWriter};
-use std::net::TcpStream;
-use url::Url;
+use std::io::BufReader;
 use std::collections::HashMap;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::ops::RangeInclusive;
+use std::time::Duration;
+use url::Url;
+use crate::auth::{DigestAuth, DigestChallenge};
+use crate::rtp::RTPReceiver;
+use crate::sdp::{Direction, MediaDescription, SessionDescription};
 
 pub struct RTSPClient {
     user_agent: &'static str,
     rtsp_url: String,
     host: String,
-    port: u16,
-    server_port: u16,
-    client_port: u16,
     with_auth: bool,
     username: String,
     password: String,
+    digest: Option<DigestAuth>,
     c_seq: u32,
     session: String,
-    stream: TcpStream,
+    /// Session ヘッダの timeout パラメータ（省略時は RFC 2326 の既定値 60 秒）
+    session_timeout: Duration,
+    /// OPTIONS の Public ヘッダに GET_PARAMETER が含まれていたか
+    supports_get_parameter: bool,
+    /// RTSP 接続。RTSP リクエストとインターリーブの RTCP が同じ接続に書くので Mutex で排他する
+    stream: SharedStream,
+    /// 受信スレッドから届く RTSP レスポンス
+    responses: mpsc::Receiver<Result<RtspResponse, RtspError>>,
+    /// インターリーブチャンネル番号ごとのフレーム送り先（受信スレッドと共有）
+    interleaved_routes: InterleavedRoutes,
+    transport: TransportMode,
     base_url: String,
-    tracks: Vec<Track>,
+    /// DESCRIBE で取得した SDP
+    sdp: Option<SessionDescription>,
+    /// UDP で受信する場合の RTP/RTCP ポートの範囲（None なら OS に任せる）
+    rtp_port_range: Option<RangeInclusive<u16>>,
+}
+
+/// RTP/RTCP の転送方式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransportMode {
+    /// RTP/AVP (UDP)
+    Udp,
+    /// RTP/AVP/TCP (RTSP 接続上の $ フレーム)
+    Tcp,
+}
+
+/// RTSP 接続上で受信した $ フレーム
+#[derive(Debug)]
+pub struct InterleavedFrame {
+    pub channel: u8,
+    pub data: Vec<u8>,
+}
+
+type InterleavedRoutes = Arc<Mutex<HashMap<u8, mpsc::Sender<InterleavedFrame>>>>;
+
+/// 書き込みを共有する RTSP 接続。1つのリクエストや $ フレームは、ロックを取って1回の write_all で書く。
+pub type SharedStream = Arc<Mutex<TcpStream>>;
+
+/// SETUP 済みのトラック。SDP 上のトラックとその受信口を結びつける。
+pub struct TrackHandle {
+    /// SDP 上のトラック番号（m= の出現順）
+    pub index: usize,
+    /// video / audio など
+    pub media: String,
+    /// SETUP に使った URL
+    pub control_url: String,
+    /// サーバー側の RTP ポート（UDP の場合）
+    pub server_port: Option<u16>,
+    pub receiver: RTPReceiver,
+}
+
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// RTSP リクエストの失敗理由
+#[derive(Debug)]
+pub enum RtspError {
+    /// ソケットの読み書きに失敗した
+    Io(io::Error),
+    /// レスポンスが RTSP として解釈できない
+    InvalidResponse(String),
+    /// 401 Unauthorized
+    Unauthorized,
+    /// 404 Not Found
+    NotFound,
+    /// 454 Session Not Found
+    SessionNotFound,
+    /// 461 Unsupported Transport
+    UnsupportedTransport,
+    /// 上記以外の 2xx 以外のステータス
+    Status(u16, String),
+    /// SDP などの内容が不足していてリクエストを組み立てられない
+    Other(String),
+}
+
+impl fmt::Display for RtspError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RtspError::Io(e) => write!(f, "I/O error: {}", e),
+            RtspError::InvalidResponse(msg) => write!(f, "invalid RTSP response: {}", msg),
+            RtspError::Unauthorized => write!(f, "401 Unauthorized"),
+            RtspError::NotFound => write!(f, "404 Not Found"),
+            RtspError::SessionNotFound => write!(f, "454 Session Not Found"),
+            RtspError::UnsupportedTransport => write!(f, "461 Unsupported Transport"),
+            RtspError::Status(code, reason) => write!(f, "{} {}", code, reason),
+            RtspError::Other(msg) => write!(f, "{}", msg),
+        }
+    }
 }
 
-#[derive(Clone)]
-struct Track {
-    media: String,
-    port: u16,
-    proto: String,                 // RTP/AVP ...etc
-    formats: Vec<String>,          // 96, 97 ...etc
-    connection: Option<String>,    // c=
-    bandwidth: Vec<String>,        // b=
-    attributes: Vec<(String,String)>, // a=key:value
+impl std::error::Error for RtspError {}
+
+impl From<io::Error> for RtspError {
+    fn from(e: io::Error) -> Self {
+        RtspError::Io(e)
+    }
+}
+
+/// RTSP レスポンス（ステータス行・ヘッダ・ボディ）
+#[derive(Debug, Clone)]
+pub struct RtspResponse {
+    pub status_code: u16,
+    pub reason: String,
+    /// 受信順のヘッダ。同名ヘッダが複数あればすべて保持する
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl RtspResponse {
+    /// ステータス行からボディまでを1レスポンス分読み込む。
+    /// ボディは Content-Length の分だけ読む。
+    pub fn read_from<R: BufRead>(reader: &mut R) -> Result<RtspResponse, RtspError> {
+        // status line (先行する空行は読み飛ばす)
+        let mut status_line = String::new();
+        while status_line.trim().is_empty() {
+            status_line.clear();
+            if reader.read_line(&mut status_line)? == 0 {
+                return Err(RtspError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by server")));
+            }
+        }
+
+        let mut headers = Vec::new();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                // ヘッダの終わりの空行より前に切断された
+                return Err(RtspError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed in headers")));
+            }
+            let line = line.trim_end_matches(['\r', '\n']);
+            if line.is_empty() {
+                break;
+            }
+            if let Some((key, value)) = line.split_once(':') {
+                headers.push((key.trim().to_string(), value.trim().to_string()));
+            }
+        }
+
+        let mut response = RtspResponse { status_code: 0, reason: String::new(), headers, body: Vec::new() };
+        let content_length = response.header("Content-Length")
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(0);
+        if content_length > 0 {
+            response.body = vec![0; content_length];
+            reader.read_exact(&mut response.body)?;
+        }
+
+        // サーバーからのリクエスト（ANNOUNCE など）もボディまで読み切ってから弾く
+        let mut parts = status_line.trim_end().splitn(3, ' ');
+        let version = parts.next().unwrap_or("");
+        if !version.starts_with("RTSP/") {
+            return Err(RtspError::InvalidResponse(format!("unexpected status line: {}", status_line.trim_end())));
+        }
+        response.status_code = parts.next()
+            .and_then(|s| s.parse::<u16>().ok())
+            .ok_or_else(|| RtspError::InvalidResponse(format!("invalid status code: {}", status_line.trim_end())))?;
+        response.reason = parts.next().unwrap_or("").to_string();
+        Ok(response)
+    }
+
+    /// ヘッダを大文字小文字を区別せずに検索し、最初の値を返す。
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.header_values(name).next()
+    }
+
+    /// 同名ヘッダの値をすべて返す（大文字小文字は区別しない）。
+    pub fn header_values<'a, 'b>(&'a self, name: &'b str) -> impl Iterator<Item = &'a str> + 'b
+    where
+        'a: 'b,
+    {
+        self.headers.iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// 2xx 以外のステータスを対応する RtspError に変換する。
+    pub fn error_for_status(self) -> Result<RtspResponse, RtspError> {
+        match self.status_code {
+            200..=299 => Ok(self),
+            401 => Err(RtspError::Unauthorized),
+            404 => Err(RtspError::NotFound),
+            454 => Err(RtspError::SessionNotFound),
+            461 => Err(RtspError::UnsupportedTransport),
+            code => Err(RtspError::Status(code, self.reason)),
+        }
+    }
+}
+
+/// SDP の a=control を Content-Base に対して解決する。
+/// `*` や省略時は集約 URL（Content-Base）そのもの、絶対 URL はそのまま使う。
+fn resolve_control_url(base_url: &str, control: Option<&str>) -> String {
+    let control = match control {
+        None | Some("") | Some("*") => return base_url.to_string(),
+        Some(c) => c,
+    };
+    let lower = control.to_ascii_lowercase();
+    if lower.starts_with("rtsp://") || lower.starts_with("rtsps://") || lower.starts_with("rtspt://") {
+        return control.to_string();
+    }
+    // "/path" はホスト直下のパスとして解決する
+    if control.starts_with('/') {
+        if let Ok(url) = Url::parse(base_url).and_then(|b| b.join(control)) {
+            return url.to_string();
+        }
+    }
+    // 相対パスは Content-Base の末尾に '/' の有無にかかわらず連結する
+    format!("{}/{}", base_url.trim_end_matches('/'), control)
+}
+
+/// RTSP 接続を読み続け、$ フレームとレスポンスを振り分ける受信スレッド
+fn reader_loop(
+    mut reader: BufReader<TcpStream>,
+    responses: mpsc::Sender<Result<RtspResponse, RtspError>>,
+    routes: InterleavedRoutes,
+) {
+    loop {
+        let first = match reader.fill_buf() {
+            Ok([]) => {
+                let eof = io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by server");
+                let _ = responses.send(Err(RtspError::Io(eof)));
+                break;
+            }
+            Ok(buf) => buf[0],
+            Err(e) => {
+                let _ = responses.send(Err(RtspError::Io(e)));
+                break;
+            }
+        };
+
+        if first == b'$' {
+            // '$' + channel(1) + length(2, BE) + data
+            let mut header = [0u8; 4];
+            if let Err(e) = reader.read_exact(&mut header) {
+                let _ = responses.send(Err(RtspError::Io(e)));
+                break;
+            }
+            let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+            let mut data = vec![0u8; length];
+            if let Err(e) = reader.read_exact(&mut data) {
+                let _ = responses.send(Err(RtspError::Io(e)));
+                break;
+            }
+            // SETUP されていないチャンネルや受け取り手のいないフレームは捨てる
+            let channel = header[1];
+            if let Some(tx) = routes.lock().unwrap().get(&channel) {
+                let _ = tx.send(InterleavedFrame { channel, data });
+            }
+            continue;
+        }
+
+        let result = RtspResponse::read_from(&mut reader);
+        let fatal = matches!(result, Err(RtspError::Io(_)));
+        if responses.send(result).is_err() || fatal {
+            break;
+        }
+    }
 }
 
 impl RTSPClient {
-    pub fn new(rtsp_url: String, client_port: u16) -> Result<RTSPClient, String> {
+    pub fn new(rtsp_url: String) -> Result<RTSPClient, String> {
         // check if url starts with rtsp:// or rtspt://
         if !rtsp_url.starts_with("rtsp://") && !rtsp_url.starts_with("rtspt://") {
             return Err("URL must start with rtsp:// or rtspt://".to_string());
@@ -75,419 +324,481 @@ impl RTSPClient {
             None => return Err(format!("failed to parse url: {}", url)),
         };
 
+        // Request-URI や Digest の uri= にパスワードを載せないよう、ユーザー情報を取り除く
+        let mut request_url = url.clone();
+        let _ = request_url.set_username("");
+        let _ = request_url.set_password(None);
+
         let mut port = 554;
         if let Some(p) = url.port() {
             port = p;
         };
 
         // connect to RTSP server, if failed, return Err
-        let stream = match TcpStream::connect(&format!("{}:{}", host, port)) {
+        let stream = match TcpStream::connect(format!("{}:{}", host, port)) {
             Ok(s) => s,
             Err(_) => return Err(format!("failed to connect to {}:{}", host, port)),
         };
-    
+        let reader = match stream.try_clone() {
+            Ok(s) => BufReader::new(s),
+            Err(e) => return Err(format!("failed to clone stream: {}", e)),
+        };
+        let (response_tx, response_rx) = mpsc::channel();
+        let interleaved_routes: InterleavedRoutes = Arc::new(Mutex::new(HashMap::new()));
+        let routes = interleaved_routes.clone();
+        thread::spawn(move || reader_loop(reader, response_tx, routes));
+
+        // rtspt:// は RTP over RTSP (TCP) を使う
+        let transport = if rtsp_url.starts_with("rtspt://") {
+            TransportMode::Tcp
+        } else {
+            TransportMode::Udp
+        };
+
         Ok(RTSPClient {
             user_agent: "my-rtsp-client",
-            rtsp_url: rtsp_url.to_string(),
+            rtsp_url: request_url.to_string(),
             host: host.to_string(),
-            port: port,
-            server_port: 0,
-            client_port: client_port,
-            with_auth: with_auth,
+            with_auth,
             username: username.to_string(),
             password: password.to_string(),
+            digest: None,
             session: String::new(),
+            session_timeout: DEFAULT_SESSION_TIMEOUT,
+            supports_get_parameter: false,
             c_seq: 0,
-            stream: stream.try_clone().unwrap(),
+            stream: Arc::new(Mutex::new(stream)),
+            responses: response_rx,
+            interleaved_routes,
+            transport,
             base_url: String::new(),
-            tracks: Vec::new(),
+            sdp: None,
+            rtp_port_range: None,
         })
     }
 
-    pub fn options(&mut self) -> Result<(), String> {
-        self.c_seq += 1;
-        let mut request = String::new();
-        request += &format!("OPTIONS {} RTSP/1.0\r\n", self.rtsp_url);
-        let mut req_headers: HashMap<String, String> = HashMap::new();
-        req_headers.insert("CSeq".to_string(), self.c_seq.to_string());
-        req_headers.insert("User-Agent".to_string(), self.user_agent.to_string());
-        for (key, value) in &req_headers {
-            request += &format!("{}: {}\r\n", key, value);
-        }
-        request+= "\r\n";
-   
-        let mut writer = BufWriter::new(&self.stream);
-        if let Err(e) = writer.write_all(request.as_bytes()) {
-            return Err(format!("failed to send OPTIONS request: {}", e));
-        }
-        let _ = writer.flush();
-
-        let mut reader = BufReader::new(&self.stream);
-        loop {
-            let mut line = String::new();
-            match reader.read_line(&mut line) {
-                Ok(len) => {
-                    println!("line[{}]", line.trim_end_matches("\r\n").to_string());
-                    if len == 0 {
-                        break;
-                    }
-                    if line == "\r\n" {
-                        break;
-                    }
-                },
-                Err(e) => {
-                    return Err(format!("failed to read_line: {}", e));
+    /// リクエストを送信してレスポンスを受け取る。
+    /// 401 で Digest チャレンジが返ってきた場合は認証情報を付けて再送する。
+    /// 2xx 以外のステータスは RtspError として返す。
+    fn send_request(&mut self, method: &str, url: &str, extra_headers: &[(&str, String)]) -> Result<RtspResponse, RtspError> {
+        let mut response = self.send_once(method, url, extra_headers)?;
+
+        if response.status_code == 401 && self.with_auth {
+            if let Some(challenge) = DigestChallenge::select(response.header_values("WWW-Authenticate")) {
+                // 同じ nonce で拒否された場合は資格情報が誤っているので再送しない
+                let retry = match self.digest {
+                    Some(ref d) => d.nonce() != challenge.nonce || challenge.stale,
+                    None => true,
+                };
+                if retry {
+                    println!("Digest authentication: realm={}, algorithm={:?}", challenge.realm, challenge.algorithm);
+                    self.digest = Some(DigestAuth::new(challenge));
+                    response = self.send_once(method, url, extra_headers)?;
                 }
             }
         }
 
-        Ok(())
+        response.error_for_status()
     }
 
-    pub fn describe(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+    /// リクエストを1回送信してレスポンスを受け取る。
+    /// CSeq / User-Agent / Authorization / Session は共通で付与する。
+    fn send_once(&mut self, method: &str, url: &str, extra_headers: &[(&str, String)]) -> Result<RtspResponse, RtspError> {
         self.c_seq += 1;
         let mut request = String::new();
-        request += &format!("DESCRIBE {} RTSP/1.0\r\n", self.rtsp_url);
-
-        let mut req_headers: HashMap<String, String> = HashMap::new();
-        req_headers.insert("CSeq".to_string(), self.c_seq.to_string());
-        req_headers.insert("User-Agent".to_string(), self.user_agent.to_string());
-
-        // basic authentication
-        if self.with_auth {
-            let auth = base64::encode(&format!("{}:{}", self.username, self.password));
-            req_headers.insert("Authorization".to_string(), format!("Basic {}", auth));
+        request += &format!("{} {} RTSP/1.0\r\n", method, url);
+        request += &format!("CSeq: {}\r\n", self.c_seq);
+        request += &format!("User-Agent: {}\r\n", self.user_agent);
+
+        if let Some(ref mut digest) = self.digest {
+            let auth = digest.authorization(&self.username, &self.password, method, url);
+            request += &format!("Authorization: {}\r\n", auth);
+        } else if self.with_auth {
+            // basic authentication
+            let auth = base64::encode(format!("{}:{}", self.username, self.password));
+            request += &format!("Authorization: Basic {}\r\n", auth);
         }
-        for (key, value) in &req_headers {
-            request += &format!("{}: {}\r\n", key, value);
+        if !self.session.is_empty() {
+            request += &format!("Session: {}\r\n", self.session);
         }
-        request+= "\r\n";
-
-        let mut writer = BufWriter::new(&self.stream);
-        if let Err(e) = writer.write_all(request.as_bytes()) {
-            eprintln!("failed to send DESCRIBE request: {}", e);
-            return Err(format!("failed to send DESCRIBE request: {}", e).into());
+        for (key, value) in extra_headers {
+            request += &format!("{}: {}\r\n", key, value);
         }
-        let _ = writer.flush();
+        request += "\r\n";
 
-        let mut reader = BufReader::new(&self.stream);
-        let mut headers = HashMap::new();
-
-        // read status line
-        let mut status_line = String::new();
-        reader.read_line(&mut status_line)?;
-        let parts: Vec<&str> = status_line.split_whitespace().collect();
-        let status_code = parts.get(1).and_then(|s| s.parse::<u16>().ok());
-        if status_code != Some(200) {
-            return Err(format!("DESCRIBE request failed with status code: {:?}", status_code).into());
+        {
+            let mut stream = self.stream.lock().unwrap();
+            stream.write_all(request.as_bytes())?;
+            stream.flush()?;
         }
 
-        loop {
-            let mut line = String::new();
-            match reader.read_line(&mut line) {
-                Ok(len) => {
-                    println!("line[{}]", line.trim_end_matches("\r\n").to_string());
-                    if len == 0 {
-                        break;
-                    }
-                    if line == "\r\n" {
-                        break;
-                    }
-                    if let Some((key, value)) = line.split_once(":") {
-                        headers.insert(key.trim().to_string(), value.trim().to_string());
-                    }
-                },
-                Err(e) => {
-                    return Err(format!("failed to read_line: {}", e).into());
+        // 自分の CSeq に対応するレスポンスが来るまで読む（古いレスポンスは捨てる）
+        let response = loop {
+            let response = match self.responses.recv_timeout(RESPONSE_TIMEOUT) {
+                Ok(Err(RtspError::InvalidResponse(msg))) => {
+                    println!("ignore message from server: {}", msg);
+                    continue;
                 }
+                Ok(r) => r?,
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    return Err(RtspError::Io(io::Error::new(io::ErrorKind::TimedOut, format!("no response to {}", method))));
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    return Err(RtspError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by server")));
+                }
+            };
+            let c_seq = response.header("CSeq").and_then(|v| v.parse::<u32>().ok());
+            match c_seq {
+                Some(c) if c < self.c_seq => {
+                    println!("discard stale response CSeq:{}", c);
+                    continue;
+                }
+                _ => break response,
             }
+        };
+        println!("{} {} -> {} {}", method, url, response.status_code, response.reason);
+        for (key, value) in &response.headers {
+            println!("  {}: {}", key, value);
         }
 
+        Ok(response)
+    }
+
+    pub fn options(&mut self) -> Result<RtspResponse, RtspError> {
+        let url = self.rtsp_url.clone();
+        let response = self.send_request("OPTIONS", &url, &[])?;
+        if let Some(public) = response.header("Public") {
+            self.supports_get_parameter = public.split(',').any(|m| m.trim().eq_ignore_ascii_case("GET_PARAMETER"));
+        }
+        Ok(response)
+    }
+
+    pub fn describe(&mut self) -> Result<RtspResponse, RtspError> {
+        let url = self.rtsp_url.clone();
+        let response = self.send_request("DESCRIBE", &url, &[("Accept", "application/sdp".to_string())])?;
+
         // Content-Base
-        if let Some(content_base) = headers.get("Content-Base") {
+        if let Some(content_base) = response.header("Content-Base") {
             self.base_url = content_base.to_string();
         } else {
             self.base_url = self.rtsp_url.clone();
         }
         println!("base_url: {}", self.base_url);
 
-        // Content-Length
-        let content_length = headers.get("Content-Length").and_then(|v| v.parse::<usize>().ok()).unwrap_or(0);
-        let mut body = vec![0; content_length];
-        reader.read_exact(&mut body)?;
-        let sdp = String::from_utf8_lossy(&body);
-        println!("SDP:\n{}", sdp);
-
-        self.tracks.clear();
-        let mut current: Option<Track> = None;
-        for line in sdp.lines() {
-            if line.starts_with("m=") {
-                // 前のトラックを確定
-                if let Some(t) = current.take() {
-                    self.tracks.push(t);
-                }
-
-                // create new track
-                let parts: Vec<&str> = line.split_whitespace().collect();
-                current = Some(Track {
-                    media: parts[0].trim_start_matches("m=").to_string(),
-                    port: parts.get(1).and_then(|v| v.parse().ok()).unwrap_or(0),
-                    proto: parts.get(2).unwrap_or(&"").to_string(),
-                    formats: parts.iter().skip(3).map(|s| s.to_string()).collect(),
-                    connection: None,
-                    bandwidth: vec![],
-                    attributes: vec![],
-                });
-
-                continue;
-            }
+        let text = String::from_utf8_lossy(&response.body).to_string();
+        println!("SDP:\n{}", text);
+        let sdp = SessionDescription::parse(&text)
+            .map_err(|e| RtspError::InvalidResponse(format!("invalid SDP: {}", e)))?;
 
-            if let Some(t) = current.as_mut() {
-                if line.starts_with("c=") {
-                    t.connection = Some(line.trim_start_matches("c=").to_string());
-                } else if line.starts_with("b=") {
-                    t.bandwidth.push(line.trim_start_matches("b=").to_string());
-                } else if line.starts_with("a=") {
-                    if let Some((key, value)) = line.trim_start_matches("a=").split_once(":") {
-                        t.attributes.push((key.to_string(), value.to_string()));
-                    }
-                }
-            }
+        // dump session / tracks
+        println!("Session: {} ({})", sdp.session_name, sdp.direction());
+        if let Some(ref o) = sdp.origin {
+            println!("  origin: {} {} {}", o.username, o.session_id, o.unicast_address);
         }
-        // 確定していないトラックを追加
-        if let Some(t) = current.take() {
-            self.tracks.push(t);
+        if let Some(range) = sdp.range() {
+            println!("  range: {}={}-{}", range.unit, range.start, range.end.unwrap_or_default());
         }
-
-        // dump tracks
-        for (i, t) in self.tracks.iter().enumerate() {
+        for (i, m) in sdp.media.iter().enumerate() {
             println!("Track {}:", i);
-            println!("  media: {}", t.media);
-            println!("  port: {}", t.port);
-            println!("  proto: {}", t.proto);
-            println!("  formats: {:?}", t.formats);
-            println!("  connection: {:?}", t.connection);
-            println!("  bandwidth: {:?}", t.bandwidth);
-            println!("  attributes:");
-            for (key, value) in &t.attributes {
-                println!("    {}: {}", key, value);
+            println!("  media: {}", m.media);
+            println!("  port: {}", m.port);
+            println!("  proto: {}", m.proto);
+            println!("  formats: {:?}", m.formats);
+            println!("  connection: {:?}", m.connection);
+            println!("  bandwidth: {:?}", m.bandwidth);
+            println!("  control: {:?}", m.control());
+            println!("  direction: {}", m.direction().unwrap_or(Direction::SendRecv));
+            if let Some(rtpmap) = m.rtpmap() {
+                println!("  rtpmap: {} {}/{}", rtpmap.payload_type, rtpmap.encoding_name, rtpmap.clock_rate);
+            }
+            if let Some(fmtp) = m.fmtp() {
+                println!("  fmtp: {:?}", fmtp.params);
+            }
+            if let Some(framerate) = m.framerate() {
+                println!("  framerate: {}", framerate);
             }
         }
+        self.sdp = Some(sdp);
+
+        Ok(response)
+    }
 
-        Ok(())
+    /// DESCRIBE で取得した SDP
+    pub fn sdp(&self) -> Option<&SessionDescription> {
+        self.sdp.as_ref()
     }
 
-    pub fn setup_tracks(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let tracks = self.tracks.clone();
+    fn tracks(&self) -> &[MediaDescription] {
+        self.sdp.as_ref().map(|s| s.media.as_slice()).unwrap_or(&[])
+    }
 
-        for track in tracks {
-            if track.media == "video" {
-                self.setup_track1()?;
-            } else if track.media == "audio" {
-                self.setup_track2()?;
+    /// SDP の video / audio トラックをすべて SETUP する。
+    /// 映像以外のトラックの SETUP に失敗した場合は、そのトラックを飛ばして映像の録画を続ける。
+    pub fn setup_tracks(&mut self) -> Result<Vec<TrackHandle>, RtspError> {
+        let mut handles = Vec::new();
+        for index in 0..self.tracks().len() {
+            let media = self.tracks()[index].media.clone();
+            if media != "video" && media != "audio" {
+                continue;
+            }
+            match self.setup_track(index) {
+                Ok(handle) => handles.push(handle),
+                Err(e) if media != "video" => {
+                    eprintln!("SETUP of {} track #{} failed, skipping: {}", media, index, e);
+                }
+                Err(e) => return Err(e),
             }
         }
-        Ok(())
+        Ok(handles)
     }
 
-    pub fn setup_track1(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        self.c_seq += 1;
-        let mut request = String::new();
-
-        // video trackを取得
-        let video_track = self.tracks.iter().find(|t| t.media == "video");
-        let track = video_track.ok_or("no video track found")?;
-
-        // attributesからcontrolを取得
-        let control_attr = track.attributes.iter().find(|(k, _)| k == "control");
-        let control = control_attr.ok_or("no control attribute found in video track")?.1.clone();
-        let setup_url = if control.starts_with("rtsp://") {
-            control.clone()
-        } else {
-            format!("{}{}", self.base_url, control)
+    /// SDP 上の index 番目のトラックを SETUP する。
+    /// UDP の場合はトラックごとに RTP/RTCP のポート組を割り当て、
+    /// TCP の場合はインターリーブチャンネル 2*index, 2*index+1 を使う。
+    pub fn setup_track(&mut self, index: usize) -> Result<TrackHandle, RtspError> {
+        let track = self.tracks().get(index)
+            .ok_or_else(|| RtspError::Other(format!("no track #{} in SDP", index)))?;
+        let media = track.media.clone();
+        let setup_url = resolve_control_url(&self.base_url, track.control().map(|c| c.trim()));
+        println!("setup_url[{}] ({}): {}", index, media, setup_url);
+
+        let udp_receiver = match self.transport {
+            TransportMode::Udp => Some(RTPReceiver::new(self.rtp_port_range.clone())?),
+            TransportMode::Tcp => None,
         };
-        println!("setup_url:\n{}", setup_url);
-        request += &format!("SETUP {} RTSP/1.0\r\n", setup_url);
-
-        let mut req_headers: HashMap<String, String> = HashMap::new();
-        req_headers.insert("CSeq".to_string(), self.c_seq.to_string());
-        req_headers.insert("User-Agent".to_string(), self.user_agent.to_string());
-        if self.with_auth {
-            let auth = base64::encode(&format!("{}:{}", self.username, self.password));
-            req_headers.insert("Authorization".to_string(), format!("Basic {}", auth));
-        }
-
-        let transport = format!("RTP/AVP;unicast;client_port={}-{}", self.client_port, self.client_port+1);
-        req_headers.insert("Transport".to_string(), transport);
-        for (key, value) in &req_headers {
-            request += &format!("{}: {}\r\n", key, value);
+        let channel = (index * 2) as u8;
+        let transport = match udp_receiver {
+            Some(ref r) => format!("RTP/AVP;unicast;client_port={}-{}", r.get_rtp_port(), r.get_rtcp_port()),
+            None => format!("RTP/AVP/TCP;unicast;interleaved={}-{}", channel, channel+1),
+        };
+        let response = self.send_request("SETUP", &setup_url, &[("Transport", transport)])?;
+
+        if let Some(session) = response.header("Session") {
+            let mut params = session.split(';');
+            self.session = params.next().unwrap_or("").trim().to_string();
+            for p in params {
+                if let Some(Ok(timeout)) = p.trim().strip_prefix("timeout=").map(|v| v.trim().parse::<u64>()) {
+                    if timeout > 0 {
+                        self.session_timeout = Duration::from_secs(timeout);
+                    }
+                }
+            }
+            println!("session:{} timeout:{:?}", self.session, self.session_timeout);
         }
-        request+= "\r\n";
-        println!("SETUP request:\n{}", request);
 
-        let mut writer = BufWriter::new(&self.stream);
-        if let Err(e) = writer.write_all(request.as_bytes()) {
-            return Err(format!("failed to send SETUP request: {}", e).into());
+        let mut server_port = None;
+        let mut server_rtcp_port = None;
+        let mut rtp_channel = channel;
+        let mut ssrc = None;
+        let mut source = None;
+        if let Some(transport) = response.header("Transport") {
+            for p in transport.split(';') {
+                if let Some(v) = p.trim().strip_prefix("ssrc=") {
+                    ssrc = u32::from_str_radix(v.trim(), 16).ok();
+                }
+                if let Some(v) = p.trim().strip_prefix("source=") {
+                    source = Some(v.trim().to_string());
+                }
+                if let Some(ports) = p.trim().strip_prefix("server_port=") {
+                    let mut ports = ports.split('-').map(|v| v.trim().parse::<u16>().ok());
+                    server_port = ports.next().flatten();
+                    server_rtcp_port = ports.next().flatten();
+                }
+                if let Some(channels) = p.trim().strip_prefix("interleaved=") {
+                    if let Some(Ok(c)) = channels.split('-').next().map(|v| v.parse::<u8>()) {
+                        rtp_channel = c;
+                    }
+                }
+            }
         }
-        let _ = writer.flush();
 
-        let mut reader = BufReader::new(&self.stream);
-        loop {
-            let mut line = String::new();
-            match reader.read_line(&mut line) {
-                Ok(len) => {
-                    println!("line[{}]", line.trim_end_matches("\r\n").to_string());
-                    
-                    // check if session is in the response
-                    let pair = line.split(":").collect::<Vec<&str>>();
-                    if pair.len() == 2 {
-                        if pair[0].trim() == "Session" {
-                            let tmp = pair[1].trim().to_string();
-                            let session = tmp.split(";").collect::<Vec<&str>>()[0].to_string();
-                            self.session = session;
-                            println!("session:{}", self.session);
-                        }
-
-                        if pair[0].trim() == "Transport" {
-                            let tmp = pair[1].trim().to_string();
-                            let pair = tmp.split(";").collect::<Vec<&str>>();
-                            for p in pair {
-                                if p.contains("server_port") {
-                                    let tmp = p.split("=").collect::<Vec<&str>>()[1];
-                                    let server_port = tmp.split("-").collect::<Vec<&str>>()[0].parse::<u16>().unwrap();
-                                    self.server_port = server_port;
-                                }
-                            }
-                        }
-                    }
-            
-                    if len == 0 {
-                        break;
-                    }
-                    if line == "\r\n" {
-                        break;
-                    }
-                },
-                Err(e) => {
-                    return Err(format!("failed to read_line: {}", e).into());
+        let mut receiver = match udp_receiver {
+            Some(mut r) => {
+                // RTP の送信元は source= があればそのアドレス、なければ RTSP の接続先
+                let source_ip = source.as_deref()
+                    .filter(|host| *host != self.host)
+                    .and_then(|host| (host, 0).to_socket_addrs().ok()?.next())
+                    .map(|a| a.ip());
+                let server_ip = match source_ip {
+                    Some(ip) => ip,
+                    None => self.stream.lock().unwrap().peer_addr()?.ip(),
+                };
+                println!("RTP source: {} port {:?}", server_ip, server_port);
+                r.set_rtp_peer(server_ip, server_port);
+                // RTCP はサーバーの RTCP ポート（省略時は RTP ポート + 1）に送る
+                let rtcp_port = server_rtcp_port.or(server_port.map(|p| p.wrapping_add(1)));
+                if let Some(port) = rtcp_port {
+                    r.set_rtcp_peer(SocketAddr::new(server_ip, port));
                 }
+                r
             }
+            None => {
+                // サーバーが返したチャンネル（RTP と RTCP）をこのトラックに振り分ける
+                let (tx, rx) = mpsc::channel();
+                let mut routes = self.interleaved_routes.lock().unwrap();
+                routes.insert(rtp_channel, tx.clone());
+                routes.insert(rtp_channel.wrapping_add(1), tx);
+                RTPReceiver::interleaved(rx, rtp_channel, self.stream.clone())
+            }
+        };
+        if let Some(ssrc) = ssrc {
+            println!("RTP SSRC: {:08X}", ssrc);
+            receiver.set_ssrc(ssrc);
         }
 
-        Ok(())
+        Ok(TrackHandle {
+            index,
+            media,
+            control_url: setup_url,
+            server_port,
+            receiver,
+        })
     }
 
-    pub fn setup_track2(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        self.c_seq += 1;
-        let mut request = String::new();
-        request += &format!("SETUP {}/track2 RTSP/1.0\r\n", self.rtsp_url);
-        let mut req_headers: HashMap<String, String> = HashMap::new();
-        req_headers.insert("CSeq".to_string(), self.c_seq.to_string());
-        req_headers.insert("User-Agent".to_string(), self.user_agent.to_string());
-        if self.with_auth {
-            let auth = base64::encode(&format!("{}:{}", self.username, self.password));
-            req_headers.insert("Authorization".to_string(), format!("Basic {}", auth));
+    /// PLAY / TEARDOWN に使う集約 URL。
+    /// SDP にセッションレベルの a=control があればそれを Content-Base に対して解決する。
+    fn aggregate_url(&self) -> String {
+        match self.sdp.as_ref().and_then(|s| s.control()) {
+            Some(control) => resolve_control_url(&self.base_url, Some(control.trim())),
+            None => self.rtsp_url.clone(),
         }
-        req_headers.insert("Session".to_string(), self.session.clone());
-    
-        // TODO: port number should be dynamic
-        let transport = format!("RTP/AVP;unicast;client_port={}-{}", self.client_port, self.client_port+1);
-        req_headers.insert("Transport".to_string(), transport);
-        for (key, value) in &req_headers {
-            request += &format!("{}: {}\r\n", key, value);
-        }
-        request+= "\r\n";
+    }
 
-        let mut writer = BufWriter::new(&self.stream);
-        if let Err(e) = writer.write_all(request.as_bytes()) {
-            return Err(format!("failed to send SETUP request: {}", e).into());
-        }
-        let _ = writer.flush();
+    pub fn play(&mut self) -> Result<RtspResponse, RtspError> {
+        let url = self.aggregate_url();
+        self.send_request("PLAY", &url, &[("Range", "npt=0.000-".to_string())])
+    }
 
-        let mut reader = BufReader::new(&self.stream);
-        loop {
-            let mut line = String::new();
-            match reader.read_line(&mut line) {
-                Ok(len) => {
-                    println!("line[{}]", line.trim_end_matches("\r\n").to_string());
-                    if len == 0 {
-                        break;
-                    }
-                    if line == "\r\n" {
-                        break;
-                    }
-                },
-                Err(e) => {
-                    return Err(format!("failed to read_line: {}", e).into());
+    /// セッション維持のためのリクエストを送る。
+    /// サーバーが GET_PARAMETER をサポートしていればそれを、なければ OPTIONS を使う。
+    pub fn keep_alive(&mut self) -> Result<RtspResponse, RtspError> {
+        let url = self.rtsp_url.clone();
+        if self.supports_get_parameter {
+            match self.send_request("GET_PARAMETER", &url, &[]) {
+                Ok(response) => return Ok(response),
+                Err(RtspError::Status(code, reason)) => {
+                    // 405 / 501 などで拒否された場合は次回から OPTIONS を使う
+                    println!("GET_PARAMETER rejected ({} {}), using OPTIONS for keep-alive", code, reason);
+                    self.supports_get_parameter = false;
                 }
+                Err(e) => return Err(e),
             }
         }
+        self.send_request("OPTIONS", &url, &[])
+    }
 
-        Ok(())
+    pub fn session_timeout(&self) -> Duration {
+        self.session_timeout
     }
 
-    pub fn play(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        self.c_seq += 1;
-        let mut request = String::new();
-        request += &format!("PLAY {} RTSP/1.0\r\n", self.rtsp_url);
-        let mut req_headers: HashMap<String, String> = HashMap::new();
-        req_headers.insert("CSeq".to_string(), self.c_seq.to_string());
-        req_headers.insert("User-Agent".to_string(), self.user_agent.to_string());
-        if self.with_auth {
-            let auth = base64::encode(&format!("{}:{}", self.username, self.password));
-            req_headers.insert("Authorization".to_string(), format!("Basic {}", auth));
-        }
-        req_headers.insert("Session".to_string(), self.session.clone());
-        req_headers.insert("Range".to_string(), "npt=0.000-".to_string());
-        for (key, value) in &req_headers {
-            request += &format!("{}: {}\r\n", key, value);
-        }
-        request+= "\r\n";
+    /// セッションを終了する。成否にかかわらず保持しているセッション ID は破棄する。
+    pub fn teardown(&mut self) -> Result<RtspResponse, RtspError> {
+        let url = self.aggregate_url();
+        let response = self.send_request("TEARDOWN", &url, &[]);
+        self.session.clear();
+        response
+    }
 
-        let mut writer = BufWriter::new(&self.stream);
-        if let Err(e) = writer.write_all(request.as_bytes()) {
-            return Err(format!("failed to send PLAY request: {}", e).into());
-        }
-        let _ = writer.flush();
+    /// UDP の RTP/RTCP に使うローカルポートの範囲を設定する。SETUP より前に呼ぶこと。
+    pub fn set_rtp_port_range(&mut self, range: Option<RangeInclusive<u16>>) {
+        self.rtp_port_range = range;
+    }
 
-        let mut reader = BufReader::new(&self.stream);
-        loop {
-            let mut line = String::new();
-            match reader.read_line(&mut line) {
-                Ok(len) => {
-                    println!("line[{}]", line.trim_end_matches("\r\n").to_string());
-                    if len == 0 {
-                        break;
-                    }
-                    if line == "\r\n" {
-                        break;
-                    }
-                },
-                Err(e) => {
-                    return Err(format!("failed to read_line: {}", e).into());
-                }
+    /// 転送方式を変更する。SETUP より前に呼ぶこと。
+    pub fn set_transport(&mut self, transport: TransportMode) {
+        self.transport = transport;
+    }
+
+    pub fn transport(&self) -> TransportMode {
+        self.transport
+    }
+
+    /// セッションがあれば TEARDOWN してから接続を閉じる。
+    pub fn shutdown(&mut self) {
+        if !self.session.is_empty() {
+            if let Err(e) = self.teardown() {
+                eprintln!("TEARDOWN request failed: {}", e);
             }
         }
-        Ok(())
+        let _ = self.stream.lock().unwrap().shutdown(std::net::Shutdown::Both);
     }
+}
 
-    pub fn shutdown(&mut self) {
-        self.stream.shutdown(std::net::Shutdown::Both).unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn read(data: &str) -> Result<RtspResponse, RtspError> {
+        RtspResponse::read_from(&mut Cursor::new(data.as_bytes()))
     }
 
-    pub fn get_host(&self) -> String {
-        self.host.clone()
+    #[test]
+    fn headers_are_case_insensitive_and_multi_valued() {
+        let response = read(
+            "RTSP/1.0 401 Unauthorized\r\n\
+             CSeq: 2\r\n\
+             WWW-Authenticate: Digest realm=\"IP Camera\", nonce=\"abc\", algorithm=\"MD5\"\r\n\
+             www-authenticate: Digest realm=\"IP Camera\", nonce=\"def\", algorithm=\"SHA-256\"\r\n\
+             WWW-AUTHENTICATE: Basic realm=\"IP Camera\"\r\n\
+             \r\n",
+        ).unwrap();
+        assert_eq!(response.status_code, 401);
+        assert_eq!(response.reason, "Unauthorized");
+        assert_eq!(response.header("cseq"), Some("2"));
+        assert_eq!(response.header("Content-Length"), None);
+        let values: Vec<&str> = response.header_values("WWW-Authenticate").collect();
+        assert_eq!(values, vec![
+            "Digest realm=\"IP Camera\", nonce=\"abc\", algorithm=\"MD5\"",
+            "Digest realm=\"IP Camera\", nonce=\"def\", algorithm=\"SHA-256\"",
+            "Basic realm=\"IP Camera\"",
+        ]);
+        assert!(matches!(response.error_for_status(), Err(RtspError::Unauthorized)));
     }
 
-    pub fn get_port(&self) -> u16 {
-        self.port
+    #[test]
+    fn body_is_read_by_content_length() {
+        // ボディに空行を含む SDP と、続けて届く次のレスポンス
+        let sdp = "v=0\r\n\r\ns=x\r\n";
+        let data = format!(
+            "\r\nRTSP/1.0 200 OK\r\nCSeq: 3\r\ncontent-length: {}\r\nContent-Type: application/sdp\r\n\r\n{}\
+             RTSP/1.0 200 OK\r\nCSeq: 4\r\n\r\n",
+            sdp.len(), sdp
+        );
+        let mut reader = Cursor::new(data.as_bytes());
+        let first = RtspResponse::read_from(&mut reader).unwrap();
+        assert_eq!(first.header("CSeq"), Some("3"));
+        assert_eq!(first.body, sdp.as_bytes());
+        let second = RtspResponse::read_from(&mut reader).unwrap();
+        assert_eq!(second.header("CSeq"), Some("4"));
+        assert!(second.body.is_empty());
+        // 接続が閉じられた
+        assert!(matches!(RtspResponse::read_from(&mut reader), Err(RtspError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof));
     }
 
-    pub fn get_server_port(&self) -> u16 {
-        self.server_port
+    #[test]
+    fn malformed_status_line() {
+        assert!(matches!(read("HTTP/1.1 200 OK\r\n\r\n"), Err(RtspError::InvalidResponse(_))));
+        assert!(matches!(read("RTSP/1.0 OK\r\n\r\n"), Err(RtspError::InvalidResponse(_))));
+        assert!(matches!(read("RTSP/1.0\r\n\r\n"), Err(RtspError::InvalidResponse(_))));
+
+        // サーバーからのリクエストはボディまで読んでから弾き、次のレスポンスを読めるようにする
+        let data = "ANNOUNCE rtsp://cam/stream RTSP/1.0\r\nCSeq: 10\r\nContent-Length: 4\r\n\r\nv=0\n\
+                    RTSP/1.0 200 OK\r\nCSeq: 5\r\n\r\n";
+        let mut reader = Cursor::new(data.as_bytes());
+        assert!(matches!(RtspResponse::read_from(&mut reader), Err(RtspError::InvalidResponse(_))));
+        assert_eq!(RtspResponse::read_from(&mut reader).unwrap().header("CSeq"), Some("5"));
     }
 
-    pub fn get_client_port(&self) -> u16 {
-        self.client_port
+    #[test]
+    fn eof_before_end_of_response() {
+        let eof = |r: Result<RtspResponse, RtspError>| matches!(r, Err(RtspError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof);
+        assert!(eof(read("")));
+        assert!(eof(read("\r\n\r\n")));
+        // ヘッダの途中
+        assert!(eof(read("RTSP/1.0 200 OK\r\nCSeq: 1\r\n")));
+        assert!(eof(read("RTSP/1.0 200 OK\r\nCSeq: 1")));
+        // ボディの途中
+        assert!(eof(read("RTSP/1.0 200 OK\r\nContent-Length: 10\r\n\r\nv=0\r\n")));
     }
-}
\ No newline at end of file
+}
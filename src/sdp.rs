@@ -0,0 +1,735 @@
+use std::fmt;
+
+// ============================================================
+// データ構造
+// ============================================================
+
+/// SDP セッション記述（RFC 4566）
+#[derive(Debug, Clone, Default)]
+pub struct SessionDescription {
+    /// v=
+    pub version: u32,
+    /// o=
+    pub origin: Option<Origin>,
+    /// s=
+    pub session_name: String,
+    /// i=
+    pub session_info: Option<String>,
+    /// u=
+    pub uri: Option<String>,
+    /// c=
+    pub connection: Option<String>,
+    /// b=
+    pub bandwidth: Vec<String>,
+    /// t=
+    pub timing: Vec<Timing>,
+    /// セッションレベルの a=
+    pub attributes: Vec<Attribute>,
+    /// m= 以降のメディア記述
+    pub media: Vec<MediaDescription>,
+}
+
+/// o=<username> <sess-id> <sess-version> <nettype> <addrtype> <unicast-address>
+#[derive(Debug, Clone, PartialEq)]
+pub struct Origin {
+    pub username: String,
+    pub session_id: String,
+    pub session_version: String,
+    pub net_type: String,
+    pub addr_type: String,
+    pub unicast_address: String,
+}
+
+/// t=<start-time> <stop-time>
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timing {
+    pub start: u64,
+    pub stop: u64,
+}
+
+/// a=<name>[:<value>]。値のない a=recvonly などは value が None になる。
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attribute {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+/// メディア記述（m= から次の m= まで）
+#[derive(Debug, Clone, Default)]
+pub struct MediaDescription {
+    /// video / audio / application ...
+    pub media: String,
+    pub port: u16,
+    /// m=video 5000/2 ... の "/2"
+    pub num_ports: Option<u16>,
+    /// RTP/AVP ...etc
+    pub proto: String,
+    /// 96, 97 ...etc
+    pub formats: Vec<String>,
+    /// i=
+    pub title: Option<String>,
+    /// c=
+    pub connection: Option<String>,
+    /// b=
+    pub bandwidth: Vec<String>,
+    /// a=
+    pub attributes: Vec<Attribute>,
+}
+
+/// a=rtpmap:<payload type> <encoding name>/<clock rate>[/<encoding parameters>]
+#[derive(Debug, Clone, PartialEq)]
+pub struct RtpMap {
+    pub payload_type: u8,
+    pub encoding_name: String,
+    pub clock_rate: u32,
+    pub encoding_params: Option<String>,
+}
+
+/// a=fmtp:<payload type> <key>=<value>;...
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fmtp {
+    pub payload_type: u8,
+    pub params: Vec<(String, String)>,
+}
+
+/// a=range:npt=<start>-[<end>] などの再生範囲（値はそのまま保持する）
+#[derive(Debug, Clone, PartialEq)]
+pub struct Range {
+    /// npt / clock / smpte ...
+    pub unit: String,
+    pub start: String,
+    pub end: Option<String>,
+}
+
+/// 送受信方向の属性
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    SendRecv,
+    SendOnly,
+    RecvOnly,
+    Inactive,
+}
+
+/// SDP の解析エラー
+#[derive(Debug, Clone, PartialEq)]
+pub struct SdpError {
+    /// 1 始まりの行番号
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SdpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SDP line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for SdpError {}
+
+// ============================================================
+// 解析
+// ============================================================
+
+impl SessionDescription {
+    /// SDP テキストを解析する。改行は CRLF / LF のどちらでもよい。
+    ///
+    /// カメラの SDP には規格外の行が混じることが多いので、壊れた行や未知の種別の行は
+    /// 警告を出して読み飛ばす。壊れた m= 行の場合は次の m= までを丸ごと読み飛ばす。
+    /// v= が無い場合と、使える m= が1つも無い場合だけをエラーにする。
+    pub fn parse(sdp: &str) -> Result<SessionDescription, SdpError> {
+        let mut session = SessionDescription::default();
+        let mut current: Option<MediaDescription> = None;
+        let mut has_version = false;
+        // 壊れた m= 行の後ろ（次の m= まで）を読み飛ばしている間 true
+        let mut skipping_media = false;
+        let mut line_count = 0;
+
+        for (i, line) in sdp.lines().enumerate() {
+            let line_no = i + 1;
+            line_count = line_no;
+            let line = line.trim_end_matches('\r');
+            if line.trim().is_empty() {
+                continue;
+            }
+            let skip = |message: &str| eprintln!("SDP line {}: {}, skipping: {}", line_no, message, line);
+
+            let (kind, value) = match line.split_once('=') {
+                Some((k, v)) if k.len() == 1 => (k, v),
+                _ => {
+                    skip("invalid line");
+                    continue;
+                }
+            };
+
+            if kind == "m" {
+                // 前のトラックを確定
+                if let Some(m) = current.take() {
+                    session.media.push(m);
+                }
+                current = MediaDescription::parse_media_line(value);
+                skipping_media = current.is_none();
+                if skipping_media {
+                    skip("invalid m= line");
+                }
+                continue;
+            }
+            if skipping_media {
+                continue;
+            }
+
+            if let Some(m) = current.as_mut() {
+                match kind {
+                    "i" => m.title = Some(value.to_string()),
+                    "c" => m.connection = Some(value.to_string()),
+                    "b" => m.bandwidth.push(value.to_string()),
+                    "a" => m.attributes.push(Attribute::parse(value)),
+                    _ => {}
+                }
+                continue;
+            }
+
+            match kind {
+                "v" => match value.trim().parse() {
+                    Ok(version) => {
+                        session.version = version;
+                        has_version = true;
+                    }
+                    Err(_) => skip("invalid v= line"),
+                },
+                "o" => match Origin::parse(value) {
+                    Some(origin) => session.origin = Some(origin),
+                    None => skip("invalid o= line"),
+                },
+                "s" => session.session_name = value.to_string(),
+                "i" => session.session_info = Some(value.to_string()),
+                "u" => session.uri = Some(value.to_string()),
+                "c" => session.connection = Some(value.to_string()),
+                "b" => session.bandwidth.push(value.to_string()),
+                "t" => match Timing::parse(value) {
+                    Some(timing) => session.timing.push(timing),
+                    None => skip("invalid t= line"),
+                },
+                "a" => session.attributes.push(Attribute::parse(value)),
+                _ => {}
+            }
+        }
+        // 確定していないトラックを追加
+        if let Some(m) = current.take() {
+            session.media.push(m);
+        }
+
+        if !has_version {
+            return Err(SdpError { line: 1, message: "missing v= line".to_string() });
+        }
+        if session.media.is_empty() {
+            return Err(SdpError { line: line_count, message: "no usable m= section".to_string() });
+        }
+        Ok(session)
+    }
+
+    /// セッションレベルの属性値を返す。
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        find_attribute(&self.attributes, name)
+    }
+
+    /// セッションレベルの a=control（集約 URL）
+    pub fn control(&self) -> Option<&str> {
+        self.attribute("control")
+    }
+
+    /// a=range
+    pub fn range(&self) -> Option<Range> {
+        self.attribute("range").and_then(Range::parse)
+    }
+
+    /// セッションレベルの送受信方向（省略時は sendrecv）
+    pub fn direction(&self) -> Direction {
+        find_direction(&self.attributes).unwrap_or(Direction::SendRecv)
+    }
+}
+
+impl MediaDescription {
+    /// "video 0 RTP/AVP 96" を解析する。
+    fn parse_media_line(value: &str) -> Option<MediaDescription> {
+        let mut parts = value.split_whitespace();
+        let media = parts.next()?.to_string();
+        let port_field = parts.next()?;
+        let (port, num_ports) = match port_field.split_once('/') {
+            Some((p, n)) => (p.parse().ok()?, Some(n.parse().ok()?)),
+            None => (port_field.parse().ok()?, None),
+        };
+        let proto = parts.next()?.to_string();
+        let formats = parts.map(|s| s.to_string()).collect();
+        Some(MediaDescription {
+            media,
+            port,
+            num_ports,
+            proto,
+            formats,
+            ..Default::default()
+        })
+    }
+
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        find_attribute(&self.attributes, name)
+    }
+
+    /// a=control
+    pub fn control(&self) -> Option<&str> {
+        self.attribute("control")
+    }
+
+    /// 最初のフォーマットに対応する a=rtpmap
+    pub fn rtpmap(&self) -> Option<RtpMap> {
+        let pt = self.formats.first()?.parse::<u8>().ok()?;
+        self.rtpmap_for(pt)
+    }
+
+    pub fn rtpmap_for(&self, payload_type: u8) -> Option<RtpMap> {
+        self.attributes.iter()
+            .filter(|a| a.name.eq_ignore_ascii_case("rtpmap"))
+            .filter_map(|a| a.value.as_deref().and_then(RtpMap::parse))
+            .find(|m| m.payload_type == payload_type)
+    }
+
+    /// 最初のフォーマットに対応する a=fmtp
+    pub fn fmtp(&self) -> Option<Fmtp> {
+        let pt = self.formats.first()?.parse::<u8>().ok()?;
+        self.fmtp_for(pt)
+    }
+
+    pub fn fmtp_for(&self, payload_type: u8) -> Option<Fmtp> {
+        self.attributes.iter()
+            .filter(|a| a.name.eq_ignore_ascii_case("fmtp"))
+            .filter_map(|a| a.value.as_deref().and_then(Fmtp::parse))
+            .find(|f| f.payload_type == payload_type)
+    }
+
+    /// a=framerate
+    pub fn framerate(&self) -> Option<f64> {
+        self.attribute("framerate").and_then(|v| v.trim().parse().ok())
+    }
+
+    /// メディアレベルの送受信方向。省略時はセッションレベルに従うので None を返す。
+    pub fn direction(&self) -> Option<Direction> {
+        find_direction(&self.attributes)
+    }
+}
+
+impl Origin {
+    fn parse(value: &str) -> Option<Origin> {
+        let parts: Vec<&str> = value.split_whitespace().collect();
+        if parts.len() != 6 {
+            return None;
+        }
+        Some(Origin {
+            username: parts[0].to_string(),
+            session_id: parts[1].to_string(),
+            session_version: parts[2].to_string(),
+            net_type: parts[3].to_string(),
+            addr_type: parts[4].to_string(),
+            unicast_address: parts[5].to_string(),
+        })
+    }
+}
+
+impl Timing {
+    fn parse(value: &str) -> Option<Timing> {
+        let mut parts = value.split_whitespace();
+        let start = parts.next()?.parse().ok()?;
+        let stop = parts.next()?.parse().ok()?;
+        Some(Timing { start, stop })
+    }
+}
+
+impl Attribute {
+    fn parse(value: &str) -> Attribute {
+        match value.split_once(':') {
+            Some((name, v)) => Attribute { name: name.to_string(), value: Some(v.to_string()) },
+            None => Attribute { name: value.to_string(), value: None },
+        }
+    }
+}
+
+impl RtpMap {
+    /// "96 H264/90000" を解析する。
+    pub fn parse(value: &str) -> Option<RtpMap> {
+        let (pt, encoding) = value.trim().split_once(' ')?;
+        let mut parts = encoding.trim().splitn(3, '/');
+        let encoding_name = parts.next()?.to_string();
+        let clock_rate = parts.next()?.parse().ok()?;
+        let encoding_params = parts.next().map(|s| s.to_string());
+        Some(RtpMap {
+            payload_type: pt.parse().ok()?,
+            encoding_name,
+            clock_rate,
+            encoding_params,
+        })
+    }
+}
+
+impl Fmtp {
+    /// "96 packetization-mode=1;profile-level-id=42e01f;sprop-parameter-sets=..." を解析する。
+    pub fn parse(value: &str) -> Option<Fmtp> {
+        let (pt, params) = value.trim().split_once(' ')?;
+        let params = params.split(';')
+            .map(|p| p.trim())
+            .filter(|p| !p.is_empty())
+            .map(|p| match p.split_once('=') {
+                Some((k, v)) => (k.trim().to_string(), v.trim().to_string()),
+                None => (p.to_string(), String::new()),
+            })
+            .collect();
+        Some(Fmtp { payload_type: pt.parse().ok()?, params })
+    }
+
+    /// パラメータ名は大文字小文字を区別しない
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.params.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+impl Range {
+    /// "npt=0-" / "npt=0.000-12.5" / "clock=20240101T000000Z-" を解析する。
+    pub fn parse(value: &str) -> Option<Range> {
+        let (unit, range) = value.trim().split_once('=')?;
+        let (start, end) = range.split_once('-')?;
+        Some(Range {
+            unit: unit.to_string(),
+            start: start.to_string(),
+            end: if end.is_empty() { None } else { Some(end.to_string()) },
+        })
+    }
+}
+
+impl Direction {
+    fn from_name(name: &str) -> Option<Direction> {
+        match name.to_ascii_lowercase().as_str() {
+            "sendrecv" => Some(Direction::SendRecv),
+            "sendonly" => Some(Direction::SendOnly),
+            "recvonly" => Some(Direction::RecvOnly),
+            "inactive" => Some(Direction::Inactive),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Direction::SendRecv => "sendrecv",
+            Direction::SendOnly => "sendonly",
+            Direction::RecvOnly => "recvonly",
+            Direction::Inactive => "inactive",
+        }
+    }
+}
+
+fn find_attribute<'a>(attributes: &'a [Attribute], name: &str) -> Option<&'a str> {
+    attributes.iter()
+        .find(|a| a.name.eq_ignore_ascii_case(name))
+        .and_then(|a| a.value.as_deref())
+}
+
+fn find_direction(attributes: &[Attribute]) -> Option<Direction> {
+    attributes.iter()
+        .filter(|a| a.value.is_none())
+        .find_map(|a| Direction::from_name(&a.name))
+}
+
+// ============================================================
+// 書き出し
+// ============================================================
+
+impl fmt::Display for SessionDescription {
+    /// RFC 4566 の順序で CRLF 区切りの SDP を書き出す。
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "v={}\r\n", self.version)?;
+        if let Some(ref o) = self.origin {
+            write!(f, "o={} {} {} {} {} {}\r\n",
+                o.username, o.session_id, o.session_version, o.net_type, o.addr_type, o.unicast_address)?;
+        }
+        write!(f, "s={}\r\n", self.session_name)?;
+        if let Some(ref i) = self.session_info {
+            write!(f, "i={}\r\n", i)?;
+        }
+        if let Some(ref u) = self.uri {
+            write!(f, "u={}\r\n", u)?;
+        }
+        if let Some(ref c) = self.connection {
+            write!(f, "c={}\r\n", c)?;
+        }
+        for b in &self.bandwidth {
+            write!(f, "b={}\r\n", b)?;
+        }
+        for t in &self.timing {
+            write!(f, "t={} {}\r\n", t.start, t.stop)?;
+        }
+        for a in &self.attributes {
+            write!(f, "{}\r\n", a)?;
+        }
+        for m in &self.media {
+            write!(f, "{}", m)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for MediaDescription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "m={} {}", self.media, self.port)?;
+        if let Some(n) = self.num_ports {
+            write!(f, "/{}", n)?;
+        }
+        write!(f, " {}", self.proto)?;
+        for fmt in &self.formats {
+            write!(f, " {}", fmt)?;
+        }
+        write!(f, "\r\n")?;
+        if let Some(ref i) = self.title {
+            write!(f, "i={}\r\n", i)?;
+        }
+        if let Some(ref c) = self.connection {
+            write!(f, "c={}\r\n", c)?;
+        }
+        for b in &self.bandwidth {
+            write!(f, "b={}\r\n", b)?;
+        }
+        for a in &self.attributes {
+            write!(f, "{}\r\n", a)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Attribute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.value {
+            Some(ref v) => write!(f, "a={}:{}", self.name, v),
+            None => write!(f, "a={}", self.name),
+        }
+    }
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::h264;
+
+    /// Hikvision（DS-2CD 系）の DESCRIBE 応答。e= や独自属性を含む
+    const HIKVISION: &str = "v=0\r\n\
+        o=- 1109162014219182 1109162014219192 IN IP4 192.168.1.64\r\n\
+        s=Media Presentation\r\n\
+        e=NONE\r\n\
+        b=AS:5050\r\n\
+        t=0 0\r\n\
+        a=control:rtsp://192.168.1.64:554/Streaming/Channels/101/?transportmode=unicast\r\n\
+        m=video 0 RTP/AVP 96\r\n\
+        c=IN IP4 0.0.0.0\r\n\
+        b=AS:5000\r\n\
+        a=recvonly\r\n\
+        a=x-dimensions:1920,1080\r\n\
+        a=control:rtsp://192.168.1.64:554/Streaming/Channels/101/trackID=1?transportmode=unicast\r\n\
+        a=rtpmap:96 H264/90000\r\n\
+        a=fmtp:96 profile-level-id=420029; packetization-mode=1; sprop-parameter-sets=Z00AKpY1QPAET8s3AQEBAgAAAwACAAADAHkBAQ==,aO48gA==\r\n\
+        a=Media_header:MEDIAINFO=494D4B48010100000400000100000000000000000000000000000000000000000000000000000000;\r\n\
+        a=appversion:1.0\r\n";
+
+    /// Dahua の DESCRIBE 応答。相対 URL の a=control と PCMA の音声トラック
+    const DAHUA: &str = "v=0\r\n\
+        o=- 2251938202 2251938202 IN IP4 0.0.0.0\r\n\
+        s=Media Server\r\n\
+        c=IN IP4 0.0.0.0\r\n\
+        t=0 0\r\n\
+        a=control:*\r\n\
+        a=packetization-supported:DH\r\n\
+        a=rtppayload-supported:DH\r\n\
+        a=range:npt=now-\r\n\
+        m=video 0 RTP/AVP 96\r\n\
+        a=control:trackID=0\r\n\
+        a=framerate:25.000000\r\n\
+        a=rtpmap:96 H264/90000\r\n\
+        a=fmtp:96 packetization-mode=1;profile-level-id=64001F;sprop-parameter-sets=Z2QAH6wsaoFAFumoCAgIEA==,aO48sA==\r\n\
+        a=recvonly\r\n\
+        m=audio 0 RTP/AVP 8\r\n\
+        a=control:trackID=1\r\n\
+        a=rtpmap:8 PCMA/8000\r\n\
+        a=recvonly\r\n";
+
+    /// Axis（GStreamer ベース）の DESCRIBE 応答。LF 改行で ONVIF メタデータのトラック付き
+    const AXIS: &str = "v=0\n\
+        o=- 13145432457302837166 1 IN IP4 192.168.0.90\n\
+        s=Session streamed with GStreamer\n\
+        i=rtsp-server\n\
+        t=0 0\n\
+        a=tool:GStreamer\n\
+        a=type:broadcast\n\
+        a=range:npt=now-\n\
+        a=control:rtsp://192.168.0.90/axis-media/media.amp?videocodec=h264\n\
+        m=video 0 RTP/AVP 96\n\
+        c=IN IP4 0.0.0.0\n\
+        b=AS:50000\n\
+        a=rtpmap:96 H264/90000\n\
+        a=fmtp:96 packetization-mode=1;profile-level-id=4d0029;sprop-parameter-sets=Z00AKeKQDwBE/LgLcBAQGkHiRFQ=,aO48gA==\n\
+        a=control:rtsp://192.168.0.90/axis-media/media.amp/stream=0?videocodec=h264\n\
+        a=framerate:30.000000\n\
+        m=application 0 RTP/AVP 98\n\
+        c=IN IP4 0.0.0.0\n\
+        a=rtpmap:98 vnd.onvif.metadata/90000\n\
+        a=control:rtsp://192.168.0.90/axis-media/media.amp/stream=1?videocodec=h264\n\
+        a=recvonly\n";
+
+    /// sprop-parameter-sets に含まれる各 NAL の nal_unit_type を返す
+    fn sprop_nal_types(fmtp: &Fmtp) -> Vec<u8> {
+        h264::parse_sprop_parameter_sets(fmtp.get("sprop-parameter-sets").unwrap())
+            .iter()
+            .map(|nal| nal[0] & 0x1F)
+            .collect()
+    }
+
+    #[test]
+    fn parses_hikvision() {
+        let sdp = SessionDescription::parse(HIKVISION).unwrap();
+        assert_eq!(sdp.version, 0);
+        assert_eq!(sdp.session_name, "Media Presentation");
+        assert_eq!(sdp.origin.as_ref().unwrap().unicast_address, "192.168.1.64");
+        assert_eq!(sdp.bandwidth, vec!["AS:5050"]);
+        assert_eq!(sdp.control(), Some("rtsp://192.168.1.64:554/Streaming/Channels/101/?transportmode=unicast"));
+
+        assert_eq!(sdp.media.len(), 1);
+        let video = &sdp.media[0];
+        assert_eq!(video.media, "video");
+        assert_eq!(video.formats, vec!["96"]);
+        assert_eq!(video.control(), Some("rtsp://192.168.1.64:554/Streaming/Channels/101/trackID=1?transportmode=unicast"));
+        assert_eq!(video.direction(), Some(Direction::RecvOnly));
+        assert_eq!(video.attribute("x-dimensions"), Some("1920,1080"));
+
+        let rtpmap = video.rtpmap().unwrap();
+        assert_eq!((rtpmap.payload_type, rtpmap.encoding_name.as_str(), rtpmap.clock_rate), (96, "H264", 90000));
+
+        // "; " 区切りでも値が取れる
+        let fmtp = video.fmtp().unwrap();
+        assert_eq!(fmtp.get("packetization-mode"), Some("1"));
+        assert_eq!(fmtp.get("profile-level-id"), Some("420029"));
+        assert_eq!(sprop_nal_types(&fmtp), vec![7, 8]);
+    }
+
+    #[test]
+    fn parses_dahua() {
+        let sdp = SessionDescription::parse(DAHUA).unwrap();
+        assert_eq!(sdp.control(), Some("*"));
+        assert_eq!(sdp.connection.as_deref(), Some("IN IP4 0.0.0.0"));
+        let range = sdp.range().unwrap();
+        assert_eq!((range.unit.as_str(), range.start.as_str(), range.end), ("npt", "now", None));
+
+        assert_eq!(sdp.media.len(), 2);
+        let video = &sdp.media[0];
+        assert_eq!(video.control(), Some("trackID=0"));
+        assert_eq!(video.framerate(), Some(25.0));
+        let fmtp = video.fmtp().unwrap();
+        assert_eq!(fmtp.payload_type, 96);
+        assert_eq!(fmtp.get("Packetization-Mode"), Some("1"));
+        assert_eq!(sprop_nal_types(&fmtp), vec![7, 8]);
+
+        let audio = &sdp.media[1];
+        assert_eq!(audio.media, "audio");
+        assert_eq!(audio.control(), Some("trackID=1"));
+        let rtpmap = audio.rtpmap().unwrap();
+        assert_eq!((rtpmap.encoding_name.as_str(), rtpmap.clock_rate), ("PCMA", 8000));
+        assert!(audio.fmtp().is_none());
+    }
+
+    #[test]
+    fn parses_axis() {
+        let sdp = SessionDescription::parse(AXIS).unwrap();
+        assert_eq!(sdp.session_info.as_deref(), Some("rtsp-server"));
+        assert_eq!(sdp.control(), Some("rtsp://192.168.0.90/axis-media/media.amp?videocodec=h264"));
+        assert_eq!(sdp.direction(), Direction::SendRecv);
+
+        assert_eq!(sdp.media.len(), 2);
+        let video = &sdp.media[0];
+        assert_eq!(video.control(), Some("rtsp://192.168.0.90/axis-media/media.amp/stream=0?videocodec=h264"));
+        assert_eq!(video.bandwidth, vec!["AS:50000"]);
+        assert_eq!(video.framerate(), Some(30.0));
+        assert_eq!(video.direction(), None);
+        let fmtp = video.fmtp().unwrap();
+        assert_eq!(fmtp.get("profile-level-id"), Some("4d0029"));
+        assert_eq!(sprop_nal_types(&fmtp), vec![7, 8]);
+
+        let metadata = &sdp.media[1];
+        assert_eq!(metadata.media, "application");
+        assert_eq!(metadata.rtpmap().unwrap().encoding_name, "vnd.onvif.metadata");
+        assert_eq!(metadata.direction(), Some(Direction::RecvOnly));
+    }
+
+    #[test]
+    fn display_round_trip() {
+        // 既知の行だけからなる SDP はそのまま書き戻せる
+        let sdp = SessionDescription::parse(DAHUA).unwrap();
+        assert_eq!(sdp.to_string(), DAHUA);
+
+        // 未知の行（e=）を落とし、改行を CRLF にした以外は同じ内容になる
+        for text in [HIKVISION, AXIS] {
+            let sdp = SessionDescription::parse(text).unwrap();
+            let written = sdp.to_string();
+            let expected: String = text.lines()
+                .filter(|l| !l.starts_with("e="))
+                .map(|l| format!("{}\r\n", l.trim_end_matches('\r')))
+                .collect();
+            assert_eq!(written, expected);
+
+            let reparsed = SessionDescription::parse(&written).unwrap();
+            assert_eq!(reparsed.to_string(), written);
+            assert_eq!(reparsed.origin, sdp.origin);
+            assert_eq!(reparsed.media.len(), sdp.media.len());
+            for (a, b) in reparsed.media.iter().zip(&sdp.media) {
+                assert_eq!(a.attributes, b.attributes);
+                assert_eq!(a.fmtp(), b.fmtp());
+            }
+        }
+    }
+
+    #[test]
+    fn skips_malformed_lines() {
+        let text = "v=0\r\n\
+            o=broken origin\r\n\
+            s=Camera\r\n\
+            garbage without equals\r\n\
+            t=now later\r\n\
+            xx=not a type\r\n\
+            a=control:*\r\n\
+            m=video notaport RTP/AVP 96\r\n\
+            a=control:trackID=99\r\n\
+            m=video 0 RTP/AVP 96\r\n\
+            a=control:trackID=0\r\n\
+            z=unknown\r\n\
+            a=rtpmap:96 H264/90000\r\n";
+        let sdp = SessionDescription::parse(text).unwrap();
+        assert!(sdp.origin.is_none());
+        assert!(sdp.timing.is_empty());
+        assert_eq!(sdp.session_name, "Camera");
+        assert_eq!(sdp.control(), Some("*"));
+        // 壊れた m= の属性はどこにも付かない
+        assert_eq!(sdp.media.len(), 1);
+        assert_eq!(sdp.media[0].control(), Some("trackID=0"));
+        assert_eq!(sdp.media[0].rtpmap().unwrap().clock_rate, 90000);
+    }
+
+    #[test]
+    fn rejects_missing_version_or_media() {
+        let err = SessionDescription::parse("s=Camera\r\nm=video 0 RTP/AVP 96\r\n").unwrap_err();
+        assert_eq!(err.message, "missing v= line");
+
+        let err = SessionDescription::parse("v=0\r\ns=Camera\r\na=control:*\r\n").unwrap_err();
+        assert_eq!(err.message, "no usable m= section");
+
+        let err = SessionDescription::parse("v=0\r\ns=Camera\r\nm=video\r\n").unwrap_err();
+        assert_eq!(err.message, "no usable m= section");
+    }
+}
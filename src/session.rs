@@ -0,0 +1,465 @@
+use std::io;
+use std::ops::RangeInclusive;
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::h264;
+use crate::jitter_buffer::{JitterBuffer, JitterOutput};
+use crate::rtcp::{ClockReference, RtcpSession};
+use crate::rtp::{self, RTPHeader, RTPReceiver};
+use crate::rtsp_client::{RTSPClient, RtspError, TrackHandle, TransportMode};
+
+/// UDP で何も届かないまま、この回数だけ受信タイムアウトしたら TCP に切り替える
+const UDP_FALLBACK_TIMEOUTS: u32 = 2;
+/// この時間 RTP が途絶えたらストリーム断とみなして再接続する
+const STREAM_LOSS_TIMEOUT: Duration = Duration::from_secs(10);
+/// 再接続の待ち時間（失敗するたびに倍にする）
+const RECONNECT_BACKOFF_INITIAL: Duration = Duration::from_secs(1);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(30);
+/// 再接続待ちの間、receive() がブロックする最大時間
+const RECONNECT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// ジッタバッファが欠けたパケットを待つ時間の既定値
+pub const DEFAULT_JITTER_LATENCY: Duration = Duration::from_millis(200);
+/// packetization-mode=2 で sprop-interleaving-depth が無い場合の並べ替え深さ
+const DEFAULT_INTERLEAVING_DEPTH: usize = 8;
+/// SDP に a=rtpmap が無い場合の映像のクロックレート
+const DEFAULT_CLOCK_RATE: u32 = 90000;
+/// RTP の受信タイムアウトの既定値
+pub const DEFAULT_RECEIVE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// セッションの設定
+#[derive(Debug, Clone)]
+pub struct SessionOptions {
+    /// true なら URL に関わらずインターリーブ TCP を使う
+    pub force_tcp: bool,
+    /// 並べ替えのために欠けたパケットを待つ最大時間
+    pub jitter_latency: Duration,
+    /// receive() が RTP を待つ最大時間
+    pub receive_timeout: Duration,
+    /// UDP で受信する場合の RTP/RTCP ポートの範囲（None なら OS に任せる）
+    pub rtp_port_range: Option<RangeInclusive<u16>>,
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        SessionOptions {
+            force_tcp: false,
+            jitter_latency: DEFAULT_JITTER_LATENCY,
+            receive_timeout: DEFAULT_RECEIVE_TIMEOUT,
+            rtp_port_range: None,
+        }
+    }
+}
+
+/// OPTIONS から PLAY までを済ませた接続
+struct Connection {
+    client: RTSPClient,
+    /// SETUP 済みのトラック（音声なども含めて受信口を保持しておく）
+    tracks: Vec<TrackHandle>,
+    /// tracks のうち映像トラックの位置
+    video: usize,
+    /// トラックごとの RTCP（受信統計と Receiver Report）。tracks と同じ並び
+    rtcp: Vec<RtcpSession>,
+}
+
+impl Connection {
+    fn open(
+        rtsp_url: &str,
+        transport: Option<TransportMode>,
+        rtp_port_range: Option<RangeInclusive<u16>>,
+    ) -> Result<Connection, RtspError> {
+        let mut client = RTSPClient::new(rtsp_url.to_string()).map_err(RtspError::Other)?;
+        if let Some(transport) = transport {
+            client.set_transport(transport);
+        }
+        client.set_rtp_port_range(rtp_port_range);
+
+        client.options()?;
+        client.describe()?;
+        let tracks = client.setup_tracks()?;
+        let video = tracks.iter()
+            .position(|t| t.media == "video")
+            .ok_or_else(|| RtspError::Other("no video track found".to_string()))?;
+        for t in &tracks {
+            println!("track[{}] {} {}: rtp_port:{}, rtcp_port:{}, server_port:{:?}",
+                t.index, t.media, t.control_url, t.receiver.get_rtp_port(), t.receiver.get_rtcp_port(), t.server_port);
+        }
+        client.play()?;
+        println!("RTSP PLAY sent ({:?}), waiting for stream...", client.transport());
+
+        let rtcp = tracks.iter()
+            .map(|t| RtcpSession::new(track_clock_rate(&client, t.index)))
+            .collect();
+
+        Ok(Connection { client, tracks, video, rtcp })
+    }
+
+    fn video_receiver(&mut self) -> &mut RTPReceiver {
+        &mut self.tracks[self.video].receiver
+    }
+
+    fn video_rtcp(&mut self) -> &mut RtcpSession {
+        &mut self.rtcp[self.video]
+    }
+
+    /// すべてのトラックについて受信済みの RTCP を処理し、時刻になっていれば Receiver Report を送る。
+    /// 映像以外のトラックは RTP を読み捨てて受信統計だけを取る。
+    /// 映像トラックの送信元から BYE を受け取った場合は true を返す。
+    fn service_rtcp(&mut self) -> bool {
+        let mut bye = false;
+        for (i, (track, rtcp)) in self.tracks.iter_mut().zip(self.rtcp.iter_mut()).enumerate() {
+            if i != self.video {
+                for header in track.receiver.drain() {
+                    rtcp.on_rtp(&header);
+                }
+            }
+            for packet in track.receiver.receive_rtcp() {
+                match rtcp.on_rtcp(&packet) {
+                    Ok(true) if i == self.video => bye = true,
+                    Ok(true) => println!("RTCP BYE on {} track #{}", track.media, track.index),
+                    Ok(false) => {}
+                    Err(e) => eprintln!("Invalid RTCP packet on {} track #{}: {}", track.media, track.index, e),
+                }
+            }
+            if rtcp.report_due() {
+                let report = rtcp.build_report();
+                if let Err(e) = track.receiver.send_rtcp(&report) {
+                    eprintln!("Failed to send RTCP receiver report for {} track #{}: {}", track.media, track.index, e);
+                }
+            }
+        }
+        bye
+    }
+
+    /// すべてのトラックで RTCP BYE を送り、TEARDOWN して接続を閉じる。
+    fn close(&mut self) {
+        for (track, rtcp) in self.tracks.iter_mut().zip(self.rtcp.iter_mut()) {
+            let bye = rtcp.build_bye();
+            let _ = track.receiver.send_rtcp(&bye);
+        }
+        self.client.shutdown();
+    }
+
+    /// 映像トラックの a=fmtp の sprop-parameter-sets から SPS / PPS を取り出す。
+    fn video_parameter_sets(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        let index = self.tracks[self.video].index;
+        let media = self.client.sdp()?.media.get(index)?;
+        let sprop = media.fmtp()?.get("sprop-parameter-sets")?.to_string();
+
+        let mut sps = None;
+        let mut pps = None;
+        for nal in h264::parse_sprop_parameter_sets(&sprop) {
+            match nal[0] & 0x1F {
+                rtp::NAL_UNIT_TYPE_SPS if sps.is_none() => sps = Some(nal),
+                rtp::NAL_UNIT_TYPE_PPS if pps.is_none() => pps = Some(nal),
+                _ => {}
+            }
+        }
+        Some((sps?, pps?))
+    }
+
+    /// 映像トラックが packetization-mode=2 なら DON 並べ替えの深さを返す。
+    fn video_clock_rate(&self) -> u32 {
+        track_clock_rate(&self.client, self.tracks[self.video].index)
+    }
+
+    fn video_interleaving_depth(&self) -> Option<usize> {
+        let index = self.tracks[self.video].index;
+        let fmtp = self.client.sdp()?.media.get(index)?.fmtp()?;
+        if fmtp.get("packetization-mode")?.trim() != "2" {
+            return None;
+        }
+        let depth = fmtp.get("sprop-interleaving-depth")
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(DEFAULT_INTERLEAVING_DEPTH);
+        Some(depth)
+    }
+}
+
+/// SDP の a=rtpmap からトラックの RTP クロックレートを返す。
+fn track_clock_rate(client: &RTSPClient, index: usize) -> u32 {
+    client.sdp()
+        .and_then(|sdp| sdp.media.get(index))
+        .and_then(|m| m.rtpmap())
+        .map(|r| r.clock_rate)
+        .filter(|&rate| rate > 0)
+        .unwrap_or(DEFAULT_CLOCK_RATE)
+}
+
+/// RTSP セッション。
+/// ストリーム断（RTP の途絶・TCP の切断・RTSP エラー）を検知すると
+/// OPTIONS から PLAY までを指数バックオフでやり直す。
+pub struct StreamSession {
+    rtsp_url: String,
+    /// 明示的に指定された転送方式（None なら URL に従う）
+    transport: Option<TransportMode>,
+    rtp_port_range: Option<RangeInclusive<u16>>,
+    receive_timeout: Duration,
+    /// 再接続待ちの間は None
+    conn: Option<Connection>,
+    /// 現在の接続で1パケットでも受信したか
+    received: bool,
+    /// 連続した受信タイムアウト回数
+    timeouts: u32,
+    /// 最後に RTP を受信した（または接続した）時刻
+    last_packet: Instant,
+    /// 最後にキープアライブ（または PLAY）を送った時刻
+    last_keep_alive: Instant,
+    backoff: Duration,
+    next_attempt: Instant,
+    /// 再接続が完了したことを呼び出し側に伝えるフラグ
+    reconnected: bool,
+    /// 映像トラックの RTP を sequence_number 順に並べ直す
+    jitter: JitterBuffer,
+    /// サーバーから RTCP BYE を受け取ってセッションが終了した
+    ended: bool,
+}
+
+impl StreamSession {
+    /// セッションを開始する。
+    /// `options.force_tcp` が false でも rtspt:// の場合は TCP を使う。
+    pub fn open(rtsp_url: &str, options: &SessionOptions) -> Result<StreamSession, RtspError> {
+        let transport = if options.force_tcp { Some(TransportMode::Tcp) } else { None };
+        let conn = Connection::open(rtsp_url, transport, options.rtp_port_range.clone())?;
+        Ok(StreamSession {
+            rtsp_url: rtsp_url.to_string(),
+            transport,
+            rtp_port_range: options.rtp_port_range.clone(),
+            receive_timeout: options.receive_timeout,
+            conn: Some(conn),
+            received: false,
+            timeouts: 0,
+            last_packet: Instant::now(),
+            last_keep_alive: Instant::now(),
+            backoff: RECONNECT_BACKOFF_INITIAL,
+            next_attempt: Instant::now(),
+            reconnected: false,
+            jitter: JitterBuffer::new(options.jitter_latency),
+            ended: false,
+        })
+    }
+
+    /// RTP パケットを1つ受信する。
+    ///
+    /// * UDP で一度も受信できないままタイムアウトが続いた場合は、
+    ///   TEARDOWN してインターリーブ TCP で SETUP し直す。
+    /// * ストリーム断を検知した場合は再接続を行い、その間は
+    ///   `ErrorKind::NotConnected` を返す。
+    /// * サーバーから RTCP BYE を受け取った場合は `ErrorKind::ConnectionAborted` を返す。
+    ///   以降の呼び出しも同じエラーになる。
+    /// * パケットはジッタバッファで sequence_number 順に並べ直してから返す。
+    ///   待ち時間内に届かなかったパケットは欠落として読み飛ばす。
+    pub fn receive(&mut self) -> Result<(RTPHeader, Vec<u8>), io::Error> {
+        if self.ended {
+            return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "session ended by RTCP BYE"));
+        }
+        if self.conn.is_none() {
+            self.try_reconnect();
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "reconnecting"));
+        }
+
+        if let Err(e) = self.keep_alive_if_due() {
+            self.lose_stream(&format!("keep-alive failed: {}", e));
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "reconnecting"));
+        }
+
+        loop {
+            let now = Instant::now();
+            match self.jitter.pop(now) {
+                Some(JitterOutput::Packet(header, payload)) => return Ok((header, payload)),
+                Some(JitterOutput::Lost { first_seq, count }) => {
+                    eprintln!("RTP packet loss: {} packet(s) from seq {} (total lost: {}, late: {})",
+                        count, first_seq, self.jitter.lost(), self.jitter.late());
+                    continue;
+                }
+                None => {}
+            }
+
+            // 並べ替え待ちのパケットがあれば、その待ち時間が切れるまでだけ受信を待つ
+            let timeout = self.jitter.time_until_release(now).unwrap_or(self.receive_timeout);
+            let conn = self.conn.as_mut().unwrap();
+            let result = conn.video_receiver().receive(timeout);
+
+            if conn.service_rtcp() {
+                // サーバーが送信を終えたので再接続せずに終了する
+                if let Some(mut conn) = self.conn.take() {
+                    conn.close();
+                }
+                self.ended = true;
+                return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "session ended by RTCP BYE"));
+            }
+
+            match result {
+                Ok((header, payload)) => {
+                    conn.video_rtcp().on_rtp(&header);
+                    if !self.received {
+                        println!("first RTP packet: ssrc={:08x}, payload type={}, seq={}",
+                            header.ssrc, header.payload_type, header.sequence_number);
+                        log_header_extension(&header);
+                        log_contributing_sources(&header);
+                    }
+                    self.received = true;
+                    self.timeouts = 0;
+                    self.last_packet = Instant::now();
+                    self.jitter.push(header, payload);
+                }
+                // 待ち時間切れのパケットを送り出す
+                Err(e) if e.kind() == io::ErrorKind::TimedOut && !self.jitter.is_empty() => {}
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                    self.timeouts += 1;
+                    if !self.received
+                        && self.timeouts >= UDP_FALLBACK_TIMEOUTS
+                        && conn.client.transport() == TransportMode::Udp
+                    {
+                        self.fallback_to_tcp();
+                    } else if self.last_packet.elapsed() >= STREAM_LOSS_TIMEOUT {
+                        self.lose_stream("no RTP packets received");
+                    }
+                    return Err(e);
+                }
+                Err(e) => {
+                    self.lose_stream(&format!("RTP receive error: {}", e));
+                    return Err(io::Error::new(io::ErrorKind::NotConnected, "reconnecting"));
+                }
+            }
+        }
+    }
+
+    /// SDP で通知された映像の SPS / PPS（スタートコードなし）。
+    /// インバンドの SPS / PPS を待たずに録画を始めるために使う。
+    pub fn video_parameter_sets(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.conn.as_ref()?.video_parameter_sets()
+    }
+
+    /// 映像トラックの RTP クロックレート（SDP の a=rtpmap、無ければ 90000）
+    pub fn video_clock_rate(&self) -> u32 {
+        self.conn.as_ref().map_or(DEFAULT_CLOCK_RATE, |c| c.video_clock_rate())
+    }
+
+    /// 映像が packetization-mode=2（インターリーブモード）なら、
+    /// DON で並べ替えるために溜める NAL ユニット数を返す。
+    pub fn video_interleaving_depth(&self) -> Option<usize> {
+        self.conn.as_ref()?.video_interleaving_depth()
+    }
+
+    /// 前回の呼び出し以降に RTCP Sender Report を受け取っていれば、
+    /// 映像の RTP タイムスタンプと壁時計の対応を返す。
+    pub fn take_clock_reference(&mut self) -> Option<ClockReference> {
+        self.conn.as_mut()?.video_rtcp().take_clock_reference()
+    }
+
+    /// 前回の呼び出し以降に再接続が完了していれば true を返す。
+    /// RTP タイムスタンプや SPS が変わるので、呼び出し側は状態を作り直すこと。
+    pub fn take_reconnected(&mut self) -> bool {
+        std::mem::take(&mut self.reconnected)
+    }
+
+    /// セッションタイムアウトの半分が経過していればキープアライブを送る。
+    fn keep_alive_if_due(&mut self) -> Result<(), RtspError> {
+        let conn = match self.conn {
+            Some(ref mut c) => c,
+            None => return Ok(()),
+        };
+        if self.last_keep_alive.elapsed() < conn.client.session_timeout() / 2 {
+            return Ok(());
+        }
+        self.last_keep_alive = Instant::now();
+        conn.client.keep_alive()?;
+        Ok(())
+    }
+
+    /// UDP の接続を破棄してインターリーブ TCP で接続し直す。
+    /// 失敗した場合は再接続待ちに入り、以降も TCP で接続を試みる。
+    fn fallback_to_tcp(&mut self) {
+        println!("No RTP packets over UDP, falling back to RTP over RTSP (TCP)");
+        self.transport = Some(TransportMode::Tcp);
+        if let Some(mut conn) = self.conn.take() {
+            conn.close();
+        }
+
+        match Connection::open(&self.rtsp_url, self.transport, self.rtp_port_range.clone()) {
+            Ok(conn) => self.set_connection(conn),
+            Err(e) => self.lose_stream(&format!("TCP fallback failed: {}", e)),
+        }
+    }
+
+    /// 現在の接続を破棄し、再接続待ちに入る。
+    fn lose_stream(&mut self, reason: &str) {
+        eprintln!("Stream lost ({}), reconnecting...", reason);
+        if let Some(mut conn) = self.conn.take() {
+            conn.close();
+        }
+        self.backoff = RECONNECT_BACKOFF_INITIAL;
+        self.next_attempt = Instant::now();
+    }
+
+    /// 再接続の時刻になっていれば1回だけ接続を試みる。
+    /// 時刻前であれば最大 RECONNECT_POLL_INTERVAL だけ待って戻る。
+    fn try_reconnect(&mut self) {
+        let now = Instant::now();
+        if now < self.next_attempt {
+            thread::sleep((self.next_attempt - now).min(RECONNECT_POLL_INTERVAL));
+            return;
+        }
+
+        match Connection::open(&self.rtsp_url, self.transport, self.rtp_port_range.clone()) {
+            Ok(conn) => {
+                println!("Reconnected to {}", self.rtsp_url);
+                self.set_connection(conn);
+                self.backoff = RECONNECT_BACKOFF_INITIAL;
+                self.reconnected = true;
+            }
+            Err(e) => {
+                eprintln!("Reconnect failed: {} (retry in {:?})", e, self.backoff);
+                self.next_attempt = Instant::now() + self.backoff;
+                self.backoff = (self.backoff * 2).min(RECONNECT_BACKOFF_MAX);
+            }
+        }
+    }
+
+    fn set_connection(&mut self, conn: Connection) {
+        self.conn = Some(conn);
+        self.received = false;
+        self.timeouts = 0;
+        self.last_packet = Instant::now();
+        self.last_keep_alive = Instant::now();
+        // 接続し直すと sequence_number は連続しない
+        self.jitter.reset();
+    }
+
+    /// TEARDOWN して RTSP 接続を閉じる。
+    pub fn shutdown(&mut self) {
+        if let Some(mut conn) = self.conn.take() {
+            conn.close();
+        }
+    }
+}
+
+/// 接続後の最初のパケットに CSRC があれば表示する（ミキサーを経由したストリームの確認用）。
+fn log_contributing_sources(header: &RTPHeader) {
+    if header.csrcs.is_empty() {
+        return;
+    }
+    let csrcs: Vec<String> = header.csrcs.iter().map(|c| format!("{:08x}", c)).collect();
+    println!("RTP contributing sources of ssrc={:08x}: [{}]", header.ssrc, csrcs.join(", "));
+}
+
+/// 接続後の最初のパケットのヘッダ拡張を表示する（カメラの対応状況の確認用）。
+fn log_header_extension(header: &RTPHeader) {
+    let ext = match header.extension_header {
+        Some(ref ext) => ext,
+        None => return,
+    };
+    if ext.onvif_replay().is_some() {
+        println!("RTP header extension: ONVIF replay");
+        return;
+    }
+    match ext.elements() {
+        Some(elements) => {
+            let ids: Vec<String> = elements.iter()
+                .map(|e| format!("{}({} bytes)", e.id, e.data.len()))
+                .collect();
+            println!("RTP header extension: RFC 8285 elements [{}]", ids.join(", "));
+        }
+        None => println!("RTP header extension: profile 0x{:04x}, {} bytes", ext.profile, ext.data.len()),
+    }
+}